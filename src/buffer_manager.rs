@@ -1,6 +1,12 @@
-use crate::disk_manager::{DiskManager, Page, PAGE_SIZE};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use crate::disk_manager::{
+    page_lsn, set_page_lsn, DiskError, DiskManager, Page, SyncPolicy, PAGE_SIZE, USABLE_PAGE_SIZE,
+};
+use crate::slotted_page::{SlotId, SlottedPage};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
 
 // A Frame holds one page and its metadata.
 pub struct Frame {
@@ -10,6 +16,7 @@ pub struct Frame {
     pin_count: u32,
 }
 impl Frame {
+    #[allow(dead_code)]
     pub fn copy(&self) -> Self {
         Self {
             page_id: self.page_id,
@@ -20,17 +27,144 @@ impl Frame {
     }
 }
 
-// The BufferPoolManager manages the buffer pool.
+// Whether a fetch found the page resident or had to read it from disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FetchOutcome {
+    Hit,
+    Miss,
+}
+
+// Everything that can go wrong when the buffer pool serves a page.
+#[derive(Debug)]
+pub enum BufferError {
+    PoolExhausted,     // every frame is pinned
+    PinBudgetExceeded, // pinning would exceed the budget set with set_pin_budget
+    Disk(DiskError),
+}
+
+impl From<DiskError> for BufferError {
+    fn from(err: DiskError) -> Self {
+        BufferError::Disk(err)
+    }
+}
+
+impl fmt::Display for BufferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BufferError::PoolExhausted => write!(f, "every buffer pool frame is pinned"),
+            BufferError::PinBudgetExceeded => write!(f, "buffer pool pin budget exceeded"),
+            BufferError::Disk(err) => write!(f, "disk error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BufferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BufferError::Disk(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// Counters of how fetches were served.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// The settings a pool was built with, see BufferPoolManager::config.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferPoolConfig {
+    pub pool_size: usize,
+    pub replacer: &'static str,
+    pub sync_policy: SyncPolicy,
+    pub page_size: usize,
+}
+
+// The BufferPoolManager manages the buffer pool. It is a cheap handle: clones
+// share the same pool, so every thread can hold its own and fetch pages
+// concurrently. Each frame has its own lock; the page table and the rest of
+// the bookkeeping sit behind a separate mutex that is only held for the
+// duration of one call.
+#[derive(Clone)]
 pub struct BufferPoolManager {
-    buffer_pool: Vec<Arc<Mutex<Frame>>>,
+    buffer_pool: Arc<Vec<Arc<Mutex<Frame>>>>,
+    latches: Arc<Vec<Arc<RwLock<()>>>>, // One page latch per frame, see fetch_latched
+    slot_locks: Arc<Vec<Arc<SlotLocks>>>, // Per-slot locks per frame, see PageLatch::lock_slot
+    state: Arc<Mutex<PoolState>>,
+    released: Arc<Condvar>, // Signalled when a pin is released, see fetch_page_wait
+    policy: ReplacerPolicy,
+    handles: Arc<()>, // One reference per live handle, see the Drop impl
+    pub disk_manager: Arc<Mutex<DiskManager>>,
+}
+
+// Bookkeeping shared by all handles of one pool. Lock order is the state,
+// then a frame, then the disk manager.
+struct PoolState {
     page_table: HashMap<u64, usize>, // page_id -> frame_id
     replacer: ClockReplacer,
-    pub disk_manager: Arc<Mutex<DiskManager>>,
-    free_list: Vec<usize>, // List of frame_ids that are free
+    free_list: VecDeque<usize>, // frame_ids that are free, handed out in order
+    dirty_pages: HashSet<u64>,  // Resident pages that may be dirty, see flush_all_pages
+    pins: usize,                // Pins currently held, summed over all frames
+    pin_budget: Option<usize>,  // Most pins allowed at once, see set_pin_budget
+    stats: BufferPoolStats,
+    waiters: VecDeque<u64>, // Tickets of blocked fetch_page_wait calls, oldest first
+    next_ticket: u64,
+    eviction_batch: Option<usize>, // Dirty victims held back before a batched write
+    pending_writes: BTreeMap<u64, Box<Page>>, // Evicted dirty pages not yet on disk
+    clean_probes: usize,           // Candidates to check for a clean victim, see set_prefer_clean
+}
+
+impl PoolState {
+    fn over_pin_budget(&self) -> bool {
+        self.pin_budget.is_some_and(|budget| self.pins >= budget)
+    }
+}
+
+// Dropping the last handle of a pool flushes its dirty pages, so data written
+// through the pool survives a normal shutdown. Drop cannot report a failed
+// write; call checkpoint or flush_all_pages first to see errors.
+impl Drop for BufferPoolManager {
+    fn drop(&mut self) {
+        // into_inner succeeds for exactly one handle, even if several are
+        // dropped at once.
+        let handle = std::mem::replace(&mut self.handles, Arc::new(()));
+        if Arc::into_inner(handle).is_some() {
+            let _ = self.flush_dirty_pages();
+        }
+    }
+}
+
+// Which page replacement algorithm a BufferPoolManager evicts with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplacerPolicy {
+    #[default]
+    Clock,
+}
+
+impl ReplacerPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReplacerPolicy::Clock => "clock",
+        }
+    }
 }
 
 impl BufferPoolManager {
     pub fn new(pool_size: usize, disk_manager: DiskManager) -> Self {
+        Self::with_replacer(pool_size, disk_manager, ReplacerPolicy::default())
+    }
+
+    pub fn with_replacer(
+        pool_size: usize,
+        disk_manager: DiskManager,
+        policy: ReplacerPolicy,
+    ) -> Self {
+        let replacer = match policy {
+            ReplacerPolicy::Clock => ClockReplacer::new(pool_size),
+        };
         let mut buffer_pool = Vec::with_capacity(pool_size);
         for _ in 0..pool_size {
             buffer_pool.push(Arc::new(Mutex::new(Frame {
@@ -41,39 +175,190 @@ impl BufferPoolManager {
             })));
         }
         BufferPoolManager {
-            buffer_pool,
-            page_table: HashMap::new(),
-            replacer: ClockReplacer::new(pool_size),
+            buffer_pool: Arc::new(buffer_pool),
+            latches: Arc::new((0..pool_size).map(|_| Arc::new(RwLock::new(()))).collect()),
+            slot_locks: Arc::new((0..pool_size).map(|_| Arc::default()).collect()),
+            state: Arc::new(Mutex::new(PoolState {
+                page_table: HashMap::new(),
+                replacer,
+                free_list: (0..pool_size).collect(),
+                dirty_pages: HashSet::new(),
+                pins: 0,
+                pin_budget: None,
+                stats: BufferPoolStats::default(),
+                waiters: VecDeque::new(),
+                next_ticket: 0,
+                eviction_batch: None,
+                pending_writes: BTreeMap::new(),
+                clean_probes: 0,
+            })),
+            released: Arc::new(Condvar::new()),
+            policy,
+            handles: Arc::new(()),
             disk_manager: Arc::new(Mutex::new(disk_manager)),
-            free_list: (0..pool_size).collect(),
         }
     }
 
-    // Create and allocate a new page in the buffer pool.
-    pub fn new_page(&mut self) -> Option<Arc<Mutex<Frame>>> {
-        let frame_id = if let Some(free_frame_id) = self.free_list.pop() {
-            free_frame_id
-        } else if let Some(victim_frame_id) = self.replacer.victim() {
-            // Evict the victim frame
-            let victim_frame: Arc<Mutex<Frame>> = self.buffer_pool[victim_frame_id].clone();
-            let victim_lock: std::sync::MutexGuard<'_, Frame> = victim_frame.lock().unwrap();
-            if victim_lock.is_dirty {
-                // Write back to disk if dirty
-                self.disk_manager
+    // Whether both handles refer to the same pool.
+    pub fn same_pool(&self, other: &BufferPoolManager) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+
+    // Find a frame to hold a new page: take a free frame if there is one,
+    // otherwise evict a victim. The victim is pinned in the replacer before it
+    // is handed back so it cannot be chosen again while it is being reused.
+    // Also returns the id of the evicted page, if any. Fails with
+    // PoolExhausted if every frame is pinned. If writing back a dirty victim
+    // fails, the victim stays resident and dirty and the error is returned.
+    fn acquire_frame(&self, state: &mut PoolState) -> Result<(usize, Option<u64>), BufferError> {
+        if let Some(free_frame_id) = state.free_list.pop_front() {
+            return Ok((free_frame_id, None));
+        }
+        let victim_frame_id = match state.clean_probes {
+            0 => state.replacer.victim(),
+            probes => state.replacer.victim_preferring(
+                |frame_id| !self.buffer_pool[frame_id].lock().unwrap().is_dirty,
+                probes,
+            ),
+        }
+        .ok_or(BufferError::PoolExhausted)?;
+        state.replacer.pin(victim_frame_id);
+        let victim_lock = self.buffer_pool[victim_frame_id].lock().unwrap();
+        let victim_page_id = victim_lock.page_id;
+        if victim_lock.is_dirty {
+            // Write back to disk if dirty, or hold it for the next batch
+            let written = match state.eviction_batch {
+                Some(batch) => {
+                    state
+                        .pending_writes
+                        .insert(victim_page_id, Box::new(victim_lock.data));
+                    if state.pending_writes.len() > batch {
+                        self.write_pending(state).map(|_| ())
+                    } else {
+                        Ok(())
+                    }
+                }
+                None => self
+                    .disk_manager
                     .lock()
                     .unwrap()
-                    .write_page(victim_lock.page_id, &victim_lock.data)
-                    .unwrap();
-            }
-            self.page_table.remove(&victim_lock.page_id);
-            victim_frame_id
-        } else {
-            // No free frame and no victim available
-            return None;
-        };
+                    .write_page(victim_page_id, &victim_lock.data),
+            };
+            if let Err(err) = written {
+                // The frame still has the only up to date copy.
+                state.pending_writes.remove(&victim_page_id);
+                state.replacer.unpin(victim_frame_id);
+                return Err(err.into());
+            }
+        }
+        state.dirty_pages.remove(&victim_page_id);
+        state.page_table.remove(&victim_page_id);
+        Ok((victim_frame_id, Some(victim_page_id)))
+    }
+
+    // Hold up to `max_pending` dirty victims in memory instead of writing
+    // each one as it is evicted. Once one more is evicted, the whole batch is
+    // written in page id order, so an eviction storm turns into a few
+    // sequential writes. None writes every victim straight away.
+    pub fn set_eviction_batch(&self, max_pending: Option<usize>) -> Result<(), DiskError> {
+        let mut state = self.state.lock().unwrap();
+        state.eviction_batch = max_pending;
+        if max_pending.is_none() {
+            self.write_pending(&mut state)?;
+        }
+        Ok(())
+    }
+
+    // Before evicting a dirty page, look at up to `probes` eviction candidates
+    // for a clean one, which can be dropped without a write. 0 turns this off
+    // and evicts in plain clock order.
+    pub fn set_prefer_clean(&self, probes: usize) {
+        self.state.lock().unwrap().clean_probes = probes;
+    }
+
+    // Write out the held back victims. Returns how many pages were written.
+    fn write_pending(&self, state: &mut PoolState) -> Result<usize, DiskError> {
+        if state.pending_writes.is_empty() {
+            return Ok(0);
+        }
+        let pages: Vec<(u64, &Page)> = state
+            .pending_writes
+            .iter()
+            .map(|(&page_id, data)| (page_id, &**data))
+            .collect();
+        self.disk_manager.lock().unwrap().write_pages(&pages)?;
+        let written = pages.len();
+        state.pending_writes.clear();
+        Ok(written)
+    }
+
+    // Create and allocate a new page in the buffer pool. Returns None if no
+    // frame is available or the disk manager cannot allocate a page, e.g.
+    // because its page quota is used up.
+    pub fn new_page(&self) -> Option<Arc<Mutex<Frame>>> {
+        self.try_new_page().ok()
+    }
+
+    // Like new_page, but says why no page could be created.
+    pub fn try_new_page(&self) -> Result<Arc<Mutex<Frame>>, BufferError> {
+        let mut state = self.state.lock().unwrap();
+        if state.over_pin_budget() {
+            return Err(BufferError::PinBudgetExceeded);
+        }
+        let (frame_id, _) = self.acquire_frame(&mut state)?;
         // Allocate a new page id from disk manager
-        let new_page_id = self.disk_manager.lock().unwrap().allocate_page().unwrap();
-        // Initialize the frame
+        let allocated = self.disk_manager.lock().unwrap().allocate_page();
+        let new_page_id = match allocated {
+            Ok(page_id) => page_id,
+            Err(err) => {
+                state.free_list.push_front(frame_id);
+                return Err(err.into());
+            }
+        };
+        Ok(self.install_new_page(&mut state, frame_id, new_page_id))
+    }
+
+    // Allocate `n` pages with consecutive ids, e.g. scratch pages for a sort
+    // run, and return them all pinned. Fails without allocating anything if
+    // the pool cannot take `n` more pins.
+    pub fn new_pages(&self, n: usize) -> Result<Vec<Arc<Mutex<Frame>>>, BufferError> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .pin_budget
+            .is_some_and(|budget| state.pins + n > budget)
+        {
+            return Err(BufferError::PinBudgetExceeded);
+        }
+        if state.free_list.len() + state.replacer.size() < n {
+            return Err(BufferError::PoolExhausted);
+        }
+        let run = self.disk_manager.lock().unwrap().allocate_run(n as u64)?;
+        let mut frames = Vec::with_capacity(n);
+        for page_id in run {
+            match self.acquire_frame(&mut state) {
+                Ok((frame_id, _)) => {
+                    frames.push(self.install_new_page(&mut state, frame_id, page_id))
+                }
+                Err(err) => {
+                    // A victim could not be written back; release what we took.
+                    for frame in frames {
+                        let page_id = frame.lock().unwrap().page_id;
+                        self.unpin_locked(&mut state, page_id, false);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(frames)
+    }
+
+    // Put a freshly allocated, empty page into a frame and pin it.
+    fn install_new_page(
+        &self,
+        state: &mut PoolState,
+        frame_id: usize,
+        new_page_id: u64,
+    ) -> Arc<Mutex<Frame>> {
         let frame: Arc<Mutex<Frame>> = self.buffer_pool[frame_id].clone();
         {
             let mut frame_lock: std::sync::MutexGuard<'_, Frame> = frame.lock().unwrap();
@@ -82,83 +367,443 @@ impl BufferPoolManager {
             frame_lock.pin_count = 1;
             frame_lock.data = [0; PAGE_SIZE]; // New page is empty
         }
-        self.page_table.insert(new_page_id, frame_id);
-        self.replacer.pin(frame_id);
-        Some(frame)
+        state.page_table.insert(new_page_id, frame_id);
+        state.replacer.pin(frame_id);
+        state.pins += 1;
+        frame
+    }
+
+    // Cap the number of pins held at once across the whole pool. Fetches that
+    // would go beyond it fail with PinBudgetExceeded until pins are released.
+    // The cap is advisory and shared by every user of the pool.
+    pub fn set_pin_budget(&self, max_pins: usize) {
+        self.state.lock().unwrap().pin_budget = Some(max_pins);
+    }
+
+    // Like fetch_page, but says why a page could not be fetched.
+    pub fn try_fetch_page(&self, page_id: u64) -> Result<Arc<Mutex<Frame>>, BufferError> {
+        self.try_fetch_page_reporting(page_id)
+            .map(|(frame, _)| frame)
     }
 
     // Fetch a page from the buffer pool, loading it from disk if necessary.
-    // Returns None if no frame is available.
-    pub fn fetch_page(&mut self, page_id: u64) -> Option<Arc<Mutex<Frame>>> {
+    // Returns None if no frame is available or the pin budget is used up.
+    pub fn fetch_page(&self, page_id: u64) -> Option<Arc<Mutex<Frame>>> {
+        self.fetch_page_reporting(page_id).map(|(frame, _)| frame)
+    }
+
+    // Fetch and pin a page for code that latches pages across several steps,
+    // e.g. index traversals. Latch it with PageLatch::latch_shared or
+    // latch_exclusive, and unpin it with unpin_page once done.
+    pub fn fetch_latched(&self, page_id: u64) -> Option<PageLatch> {
+        let frame = self.fetch_page(page_id)?;
+        let frame_id = self.state.lock().unwrap().page_table[&page_id];
+        Some(PageLatch {
+            page_id,
+            frame,
+            latch: self.latches[frame_id].clone(),
+            slot_locks: self.slot_locks[frame_id].clone(),
+        })
+    }
+
+    // Same as fetch_page, but also reports whether the page had to be read
+    // from disk, e.g. for a prefetcher tuning its distance.
+    pub fn fetch_page_reporting(&self, page_id: u64) -> Option<(Arc<Mutex<Frame>>, FetchOutcome)> {
+        self.try_fetch_page_reporting(page_id).ok()
+    }
+
+    // Like fetch_page, but if no frame is free, wait up to `timeout` for one.
+    // Waiters are served in arrival order, and while any are queued, plain
+    // fetches may not take a frame that is not already pinned, so a thread
+    // that keeps re-pinning the same pages cannot starve them. Fails with
+    // PoolExhausted or PinBudgetExceeded once the timeout passes.
+    pub fn fetch_page_wait(
+        &self,
+        page_id: u64,
+        timeout: Duration,
+    ) -> Result<Arc<Mutex<Frame>>, BufferError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiters.push_back(ticket);
+        loop {
+            if state.waiters.front() == Some(&ticket) {
+                let result = self.fetch_locked(&mut state, page_id, true);
+                let retry = matches!(
+                    result,
+                    Err(BufferError::PoolExhausted | BufferError::PinBudgetExceeded)
+                );
+                let now = Instant::now();
+                if !retry || now >= deadline {
+                    state.waiters.pop_front();
+                    self.released.notify_all();
+                    return result.map(|(frame, _, _)| frame);
+                }
+            } else if Instant::now() >= deadline {
+                state.waiters.retain(|&t| t != ticket);
+                return Err(BufferError::PoolExhausted);
+            }
+            let wait = deadline.saturating_duration_since(Instant::now());
+            state = self.released.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+
+    // Like try_fetch_page, but also returns the id of the page that was
+    // evicted to make room for this one, e.g. to promote it to a second-level
+    // cache. None if the page was resident or a free frame was used.
+    pub fn fetch_page_with_eviction(
+        &self,
+        page_id: u64,
+    ) -> Result<(Arc<Mutex<Frame>>, Option<u64>), BufferError> {
+        let mut state = self.state.lock().unwrap();
+        self.fetch_locked(&mut state, page_id, false)
+            .map(|(frame, _, evicted)| (frame, evicted))
+    }
+
+    fn try_fetch_page_reporting(
+        &self,
+        page_id: u64,
+    ) -> Result<(Arc<Mutex<Frame>>, FetchOutcome), BufferError> {
+        let mut state = self.state.lock().unwrap();
+        self.fetch_locked(&mut state, page_id, false)
+            .map(|(frame, outcome, _)| (frame, outcome))
+    }
+
+    // Returns the frame, whether it was a hit, and the page evicted for it.
+    #[allow(clippy::type_complexity)]
+    fn fetch_locked(
+        &self,
+        state: &mut PoolState,
+        page_id: u64,
+        waiting: bool,
+    ) -> Result<(Arc<Mutex<Frame>>, FetchOutcome, Option<u64>), BufferError> {
+        if state.over_pin_budget() {
+            return Err(BufferError::PinBudgetExceeded);
+        }
+        // Queued waiters get the next unpinned frame before anyone else.
+        let defer = !waiting && !state.waiters.is_empty();
         // Check if the page is already in the buffer pool
-        match self.page_table.get(&page_id) {
+        match state.page_table.get(&page_id) {
             Some(&frame_id) => {
                 // Found the page
                 let frame = self.buffer_pool[frame_id].clone();
                 {
                     let mut frame_lock = frame.lock().unwrap();
+                    if defer && frame_lock.pin_count == 0 {
+                        return Err(BufferError::PoolExhausted);
+                    }
                     frame_lock.pin_count += 1;
                 }
-                self.replacer.pin(frame_id);
-                Some(frame)
+                state.replacer.touch(frame_id);
+                state.replacer.pin(frame_id);
+                state.pins += 1;
+                state.stats.hits += 1;
+                Ok((frame, FetchOutcome::Hit, None))
             }
             None => {
                 // Not found
-                let frame_id = if let Some(free_frame_id) = self.free_list.pop() {
-                    free_frame_id
-                } else if let Some(victim_frame_id) = self.replacer.victim() {
-                    // Evict the victim frame
-                    let victim_frame: Arc<Mutex<Frame>> = self.buffer_pool[victim_frame_id].clone();
-                    let victim_lock: std::sync::MutexGuard<'_, Frame> =
-                        victim_frame.lock().unwrap();
-                    if victim_lock.is_dirty {
-                        // Write back to disk if dirty
-                        self.disk_manager
-                            .lock()
-                            .unwrap()
-                            .write_page(victim_lock.page_id, &victim_lock.data)
-                            .unwrap();
+                if defer {
+                    return Err(BufferError::PoolExhausted);
+                }
+                let (frame_id, evicted) = self.acquire_frame(state)?;
+                if state.pending_writes.contains_key(&page_id) {
+                    if let Err(err) = self.write_pending(state) {
+                        state.free_list.push_front(frame_id);
+                        return Err(err.into());
                     }
-                    self.page_table.remove(&victim_lock.page_id);
-                    victim_frame_id
-                } else {
-                    // No free frame and no victim available
-                    return None;
-                };
+                }
                 // Load the new page from disk
                 let frame: Arc<Mutex<Frame>> = self.buffer_pool[frame_id].clone();
                 {
                     let mut frame_lock: std::sync::MutexGuard<'_, Frame> = frame.lock().unwrap();
+                    let read = self
+                        .disk_manager
+                        .lock()
+                        .unwrap()
+                        .read_page(page_id, &mut frame_lock.data);
+                    if let Err(err) = read {
+                        // The frame no longer holds its old page either.
+                        frame_lock.pin_count = 0;
+                        frame_lock.is_dirty = false;
+                        state.free_list.push_front(frame_id);
+                        return Err(err.into());
+                    }
                     frame_lock.page_id = page_id;
                     frame_lock.is_dirty = false;
                     frame_lock.pin_count = 1;
+                }
+                state.page_table.insert(page_id, frame_id);
+                state.replacer.pin(frame_id);
+                state.pins += 1;
+                state.stats.misses += 1;
+                Ok((frame, FetchOutcome::Miss, evicted))
+            }
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        self.state.lock().unwrap().stats
+    }
+
+    // The effective settings, e.g. to print them or to build a matching pool.
+    pub fn config(&self) -> BufferPoolConfig {
+        BufferPoolConfig {
+            pool_size: self.buffer_pool.len(),
+            replacer: self.policy.name(),
+            sync_policy: self.disk_manager.lock().unwrap().sync_policy(),
+            page_size: PAGE_SIZE,
+        }
+    }
+
+    // Load pages into free frames without pinning them, e.g. to replay a hot
+    // set saved at shutdown. Stops once no free frame is left, so nothing
+    // already cached is evicted. Returns how many pages were loaded.
+    pub fn warmup(&self, page_ids: &[u64]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let mut loaded = 0;
+        for &page_id in page_ids {
+            if state.page_table.contains_key(&page_id) {
+                continue;
+            }
+            if state.pending_writes.contains_key(&page_id) {
+                self.write_pending(&mut state)?;
+            }
+            let Some(frame_id) = state.free_list.pop_front() else {
+                break;
+            };
+            {
+                let mut frame_lock = self.buffer_pool[frame_id].lock().unwrap();
+                let read = self
+                    .disk_manager
+                    .lock()
+                    .unwrap()
+                    .read_page(page_id, &mut frame_lock.data);
+                if let Err(err) = read {
+                    state.free_list.push_front(frame_id);
+                    return Err(err.into());
+                }
+                frame_lock.page_id = page_id;
+                frame_lock.is_dirty = false;
+                frame_lock.pin_count = 0;
+            }
+            state.page_table.insert(page_id, frame_id);
+            state.replacer.unpin(frame_id);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    // Fetch a page and keep it pinned for as long as the returned view lives.
+    // Dropping the view unpins the page. The frame is only locked during each
+    // access through the view, so other threads can keep using the pool, and
+    // this page, meanwhile.
+    pub fn page_view(&self, page_id: u64) -> Option<PageView<'_>> {
+        Some(PageView {
+            frame: self.fetch_page(page_id)?,
+            pool: self,
+            page_id,
+            dirtied: false,
+        })
+    }
+
+    // Frames a fetch of a non-resident page could use: free or evictable.
+    pub fn available_frames(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.free_list.len() + state.replacer.size()
+    }
+
+    // Write a page back to disk if it is resident and dirty, or if it is an
+    // evicted page still waiting in the eviction batch. Returns whether it
+    // was written.
+    pub fn flush_page(&self, page_id: u64) -> Result<bool, DiskError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(&frame_id) = state.page_table.get(&page_id) else {
+            if state.pending_writes.contains_key(&page_id) {
+                self.write_pending(&mut state)?;
+                return Ok(true);
+            }
+            return Ok(false);
+        };
+        let mut frame_lock = self.buffer_pool[frame_id].lock().unwrap();
+        if !frame_lock.is_dirty {
+            return Ok(false);
+        }
+        self.disk_manager
+            .lock()
+            .unwrap()
+            .write_page(page_id, &frame_lock.data)?;
+        frame_lock.is_dirty = false;
+        state.dirty_pages.remove(&page_id);
+        Ok(true)
+    }
+
+    // Throw away the in-memory changes to a dirty page by reading it back from
+    // disk, e.g. when an operation aborts before anything was logged. Only
+    // done if the page is resident, dirty and pinned at most once (by the
+    // caller). Whether the copy on disk is the one to go back to is up to the
+    // caller; a page that never reached the disk fails with the read error.
+    pub fn discard_changes(&self, page_id: u64) -> io::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let Some(&frame_id) = state.page_table.get(&page_id) else {
+            return Ok(false);
+        };
+        let mut frame_lock = self.buffer_pool[frame_id].lock().unwrap();
+        if !frame_lock.is_dirty || frame_lock.pin_count > 1 {
+            return Ok(false);
+        }
+        let mut page: Page = [0; PAGE_SIZE];
+        self.disk_manager
+            .lock()
+            .unwrap()
+            .read_page(page_id, &mut page)?;
+        frame_lock.data = page;
+        frame_lock.is_dirty = false;
+        state.dirty_pages.remove(&page_id);
+        Ok(true)
+    }
+
+    // Forget a page without writing it back, e.g. before its id is handed
+    // back to the disk manager for reuse. Its frame becomes free. Returns
+    // false if the page is still pinned.
+    pub fn delete_page(&self, page_id: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.pending_writes.remove(&page_id);
+        let Some(&frame_id) = state.page_table.get(&page_id) else {
+            return true;
+        };
+        let mut frame_lock = self.buffer_pool[frame_id].lock().unwrap();
+        if frame_lock.pin_count > 0 {
+            return false;
+        }
+        frame_lock.is_dirty = false;
+        state.replacer.pin(frame_id);
+        state.page_table.remove(&page_id);
+        state.dirty_pages.remove(&page_id);
+        state.free_list.push_back(frame_id);
+        true
+    }
+
+    // Unpin a batch of pages under one acquisition of the pool lock. Returns
+    // how many of the unpins succeeded.
+    pub fn unpin_pages(&self, ids_and_dirty: &[(u64, bool)]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        ids_and_dirty
+            .iter()
+            .filter(|&&(page_id, is_dirty)| self.unpin_locked(&mut state, page_id, is_dirty))
+            .count()
+    }
+
+    // Write every dirty page back to disk. Pinned pages are written too.
+    // Only pages in the dirty set are visited, so every path that dirties a
+    // frame must report it through unpin_page or mark_dirty.
+    pub fn flush_all_pages(&self) -> Result<(), DiskError> {
+        self.flush_dirty_pages().map(|_| ())
+    }
+
+    // Flush all dirty pages, rewrite the metadata header and sync the file.
+    // Returns how many pages were written, not counting the header.
+    pub fn checkpoint(&self) -> Result<usize, DiskError> {
+        let written = self.flush_dirty_pages()?;
+        let mut dm = self.disk_manager.lock().unwrap();
+        dm.write_metadata()?;
+        dm.sync()?;
+        Ok(written)
+    }
+
+    fn flush_dirty_pages(&self) -> Result<usize, DiskError> {
+        let mut state = self.state.lock().unwrap();
+        let mut written = self.write_pending(&mut state)?;
+        let dirty: Vec<u64> = state.dirty_pages.iter().copied().collect();
+        for page_id in dirty {
+            if let Some(&frame_id) = state.page_table.get(&page_id) {
+                let mut frame_lock = self.buffer_pool[frame_id].lock().unwrap();
+                if frame_lock.is_dirty {
                     self.disk_manager
                         .lock()
                         .unwrap()
-                        .read_page(page_id, &mut frame_lock.data)
-                        .unwrap();
+                        .write_page(page_id, &frame_lock.data)?;
+                    frame_lock.is_dirty = false;
+                    written += 1;
                 }
-                self.page_table.insert(page_id, frame_id);
-                self.replacer.pin(frame_id);
-                Some(frame)
             }
+            state.dirty_pages.remove(&page_id);
+        }
+        Ok(written)
+    }
+
+    // Apply a logged change of `bytes` at `offset` to a page, unless the page
+    // has already seen a change with an LSN of `record_lsn` or later. Applying
+    // the same record twice is therefore a no-op, also after the page was
+    // written out and read back, since the page LSN is stored in the page.
+    // The change must stay within the first USABLE_PAGE_SIZE bytes.
+    pub fn apply_redo(
+        &self,
+        page_id: u64,
+        record_lsn: u64,
+        offset: usize,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        if offset
+            .checked_add(bytes.len())
+            .is_none_or(|end| end > USABLE_PAGE_SIZE)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "redo record extends past the end of the page",
+            ));
         }
+        let frame = self.try_fetch_page(page_id).map_err(|err| match err {
+            BufferError::Disk(err) => io::Error::from(err),
+            err => io::Error::other(err),
+        })?;
+        let applied = {
+            let mut frame_lock = frame.lock().unwrap();
+            let applied = page_lsn(&frame_lock.data) < record_lsn;
+            if applied {
+                frame_lock.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+                set_page_lsn(&mut frame_lock.data, record_lsn);
+            }
+            applied
+        };
+        self.unpin_page(page_id, applied);
+        Ok(())
+    }
+
+    // Mark a resident page dirty without unpinning it.
+    pub fn mark_dirty(&self, page_id: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(&frame_id) = state.page_table.get(&page_id) else {
+            return false;
+        };
+        self.buffer_pool[frame_id].lock().unwrap().is_dirty = true;
+        state.dirty_pages.insert(page_id);
+        true
     }
 
     // Unpin a page in the buffer pool.
     // Unpin means that the page is no longer needed by the caller.
-    pub fn unpin_page(&mut self, page_id: u64, is_dirty: bool) -> bool {
-        match self.page_table.get(&page_id) {
+    pub fn unpin_page(&self, page_id: u64, is_dirty: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.unpin_locked(&mut state, page_id, is_dirty)
+    }
+
+    fn unpin_locked(&self, state: &mut PoolState, page_id: u64, is_dirty: bool) -> bool {
+        match state.page_table.get(&page_id) {
             Some(&frame_id) => {
-                let frame = self.buffer_pool[frame_id].clone();
-                let mut frame_lock = frame.lock().unwrap();
+                let mut frame_lock = self.buffer_pool[frame_id].lock().unwrap();
                 if frame_lock.pin_count > 0 {
                     frame_lock.pin_count -= 1;
+                    state.pins -= 1;
                     if is_dirty {
                         frame_lock.is_dirty = true;
+                        state.dirty_pages.insert(page_id);
                     }
                     if frame_lock.pin_count == 0 {
-                        self.replacer.unpin(frame_id);
+                        state.replacer.unpin(frame_id);
+                    }
+                    if !state.waiters.is_empty() {
+                        self.released.notify_all();
                     }
                     true
                 } else {
@@ -170,8 +815,164 @@ impl BufferPoolManager {
     }
 }
 
+// A page pinned in the buffer pool. Neither the pool state nor the frame is
+// locked between accesses, so holding a view never stalls the pool.
+pub struct PageView<'a> {
+    frame: Arc<Mutex<Frame>>,
+    pool: &'a BufferPoolManager,
+    page_id: u64,
+    dirtied: bool,
+}
+
+impl PageView<'_> {
+    pub fn page_id(&self) -> u64 {
+        self.page_id
+    }
+
+    // Run `f` on the page as a slotted page, with the frame locked. The page
+    // is marked dirty since `f` may modify it; it joins the dirty set when
+    // the view is dropped.
+    pub fn with_slotted<R>(&mut self, f: impl FnOnce(&mut SlottedPage<'_>) -> R) -> R {
+        self.dirtied = true;
+        let mut frame = self.frame.lock().unwrap();
+        frame.is_dirty = true;
+        f(&mut SlottedPage::from_buffer_unchecked(&mut frame.data))
+    }
+}
+
+impl Drop for PageView<'_> {
+    fn drop(&mut self) {
+        self.pool.unpin_page(self.page_id, self.dirtied);
+    }
+}
+
+// A pinned page with a reader-writer latch that can be held for as long as an
+// operation needs, unlike the frame mutex which only guards a single access.
+// Latches are only waited on with the buffer pool unlocked, so crabbing (latch
+// the child, then release the parent) cannot deadlock as long as every caller
+// latches pages top-down in the same order.
+pub struct PageLatch {
+    page_id: u64,
+    frame: Arc<Mutex<Frame>>,
+    latch: Arc<RwLock<()>>,
+    slot_locks: Arc<SlotLocks>,
+}
+
+impl PageLatch {
+    pub fn page_id(&self) -> u64 {
+        self.page_id
+    }
+
+    pub fn latch_shared(&self) -> SharedLatch<'_> {
+        SharedLatch {
+            _guard: self.latch.read().unwrap(),
+            frame: &self.frame,
+        }
+    }
+
+    pub fn latch_exclusive(&self) -> ExclusiveLatch<'_> {
+        ExclusiveLatch {
+            _guard: self.latch.write().unwrap(),
+            frame: &self.frame,
+        }
+    }
+
+    // Lock a single slot of a slotted page. Threads holding locks on different
+    // slots proceed concurrently; the frame mutex is only taken for the byte
+    // copies in SlotGuard::read and write.
+    pub fn lock_slot(&self, slot: SlotId) -> SlotGuard<'_> {
+        let mut locked = self.slot_locks.locked.lock().unwrap();
+        while locked.contains(&slot.0) {
+            locked = self.slot_locks.released.wait(locked).unwrap();
+        }
+        locked.insert(slot.0);
+        SlotGuard {
+            locks: &self.slot_locks,
+            frame: &self.frame,
+            slot,
+        }
+    }
+}
+
+// The slots of one frame that are currently locked.
+#[derive(Default)]
+struct SlotLocks {
+    locked: Mutex<HashSet<u16>>,
+    released: Condvar,
+}
+
+// Exclusive access to one slot of a page, see PageLatch::lock_slot.
+pub struct SlotGuard<'a> {
+    locks: &'a SlotLocks,
+    frame: &'a Mutex<Frame>,
+    slot: SlotId,
+}
+
+impl SlotGuard<'_> {
+    pub fn read(&self) -> Option<Vec<u8>> {
+        let mut frame = self.frame.lock().unwrap();
+        let sp = SlottedPage::from_buffer_unchecked(&mut frame.data);
+        sp.read(self.slot).map(|tuple| tuple.to_vec())
+    }
+
+    // Replace the slot's bytes; see SlottedPage::update. As with
+    // ExclusiveLatch::write, unpin the page with is_dirty set afterwards.
+    pub fn write(&mut self, tuple: &[u8]) -> bool {
+        let mut frame = self.frame.lock().unwrap();
+        let updated = SlottedPage::from_buffer_unchecked(&mut frame.data).update(self.slot, tuple);
+        if updated {
+            frame.is_dirty = true;
+        }
+        updated
+    }
+}
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.locked.lock().unwrap().remove(&self.slot.0);
+        self.locks.released.notify_all();
+    }
+}
+
+// Read access to a page while its latch is held in shared mode.
+pub struct SharedLatch<'a> {
+    _guard: RwLockReadGuard<'a, ()>,
+    frame: &'a Mutex<Frame>,
+}
+
+impl SharedLatch<'_> {
+    pub fn read<R>(&self, f: impl FnOnce(&Page) -> R) -> R {
+        f(&self.frame.lock().unwrap().data)
+    }
+}
+
+// Write access to a page while its latch is held in exclusive mode.
+pub struct ExclusiveLatch<'a> {
+    _guard: RwLockWriteGuard<'a, ()>,
+    frame: &'a Mutex<Frame>,
+}
+
+impl ExclusiveLatch<'_> {
+    pub fn read<R>(&self, f: impl FnOnce(&Page) -> R) -> R {
+        f(&self.frame.lock().unwrap().data)
+    }
+
+    // Modify the page. The frame is marked dirty; unpin it with is_dirty set
+    // so the buffer pool knows to flush it.
+    pub fn write<R>(&mut self, f: impl FnOnce(&mut Page) -> R) -> R {
+        let mut frame = self.frame.lock().unwrap();
+        frame.is_dirty = true;
+        f(&mut frame.data)
+    }
+}
+
+// Second-chance clock: every evictable frame carries a reference bit that is
+// set when the frame is used. The hand clears set bits as it sweeps and
+// evicts the first frame whose bit is already clear, so a frame used since
+// the hand last passed survives one more rotation.
 pub struct ClockReplacer {
     frames: Vec<Option<usize>>, // Holds the frame_ids of frames in the buffer pool
+    ref_bits: Vec<bool>,        // Reference bit per frame
     clock_hand: usize,
 }
 
@@ -179,26 +980,63 @@ impl ClockReplacer {
     pub fn new(pool_size: usize) -> Self {
         Self {
             frames: vec![None; pool_size],
+            ref_bits: vec![false; pool_size],
             clock_hand: 0,
         }
     }
 
     // Finds a frame to evict.
     pub fn victim(&mut self) -> Option<usize> {
+        // The first rotation clears every set bit, so two always suffice.
         for _ in 0..(2 * self.frames.len()) {
-            // Loop at most twice to find a victim
-            let frame_id = self.clock_hand;
+            let idx = self.clock_hand;
             self.clock_hand = (self.clock_hand + 1) % self.frames.len();
 
-            if let Some(id) = self.frames[frame_id] {
-                // In a real implementation, you would check a ref bit or pin count.
-                // For now, we'll just return the first frame we find.
-                return Some(id);
+            if let Some(id) = self.frames[idx] {
+                if !self.ref_bits[idx] {
+                    return Some(id);
+                }
+                self.ref_bits[idx] = false;
             }
         }
         None // No frames to evict
     }
 
+    // Like victim, but sweeps past up to `max_probes` candidates whose
+    // reference bit is clear and takes the first one `prefer` accepts. If
+    // none does, the first of them is evicted, as victim would have.
+    pub fn victim_preferring(
+        &mut self,
+        prefer: impl Fn(usize) -> bool,
+        max_probes: usize,
+    ) -> Option<usize> {
+        let len = self.frames.len();
+        let mut fallback = None;
+        let mut probes = 0;
+        for _ in 0..(2 * len) {
+            let idx = self.clock_hand;
+            self.clock_hand = (self.clock_hand + 1) % len;
+            let Some(frame_id) = self.frames[idx] else {
+                continue;
+            };
+            if self.ref_bits[idx] {
+                self.ref_bits[idx] = false;
+                continue;
+            }
+            if prefer(frame_id) {
+                return Some(frame_id);
+            }
+            fallback.get_or_insert(idx);
+            probes += 1;
+            if probes >= max_probes {
+                break;
+            }
+        }
+        let idx = fallback?;
+        self.clock_hand = (idx + 1) % len;
+        self.frames[idx]
+    }
+
     // Add a frame to the replacer's tracking.
     pub fn pin(&mut self, frame_id: usize) {
         self.frames[frame_id] = None;
@@ -207,6 +1045,17 @@ impl ClockReplacer {
     // Remove a frame from the replacer's tracking.
     pub fn unpin(&mut self, frame_id: usize) {
         self.frames[frame_id] = Some(frame_id);
+        self.ref_bits[frame_id] = true;
+    }
+
+    // Record a use of a frame, e.g. a cache hit, giving it a second chance.
+    pub fn touch(&mut self, frame_id: usize) {
+        self.ref_bits[frame_id] = true;
+    }
+
+    // Number of frames that could be evicted.
+    pub fn size(&self) -> usize {
+        self.frames.iter().filter(|frame| frame.is_some()).count()
     }
 }
 
@@ -216,11 +1065,828 @@ fn clock_replacer_test() {
     clock_replacer.unpin(0);
     clock_replacer.unpin(1);
     clock_replacer.unpin(2);
+    // Every bit is set, so the hand clears them all and comes back to 0.
     assert_eq!(clock_replacer.victim(), Some(0));
     clock_replacer.pin(0);
+    // 0 is used again and gets its bit back; 1 and 2 were already cleared.
+    clock_replacer.unpin(0);
     assert_eq!(clock_replacer.victim(), Some(1));
     clock_replacer.pin(1);
     assert_eq!(clock_replacer.victim(), Some(2));
     clock_replacer.pin(2);
+    assert_eq!(clock_replacer.victim(), Some(0));
+    clock_replacer.pin(0);
     assert_eq!(clock_replacer.victim(), None);
 }
+
+#[test]
+fn second_chance_test() {
+    let mut clock_replacer = ClockReplacer::new(4);
+    for frame_id in 0..4 {
+        clock_replacer.unpin(frame_id);
+    }
+    assert_eq!(clock_replacer.victim(), Some(0));
+    clock_replacer.pin(0);
+
+    // Frame 2 is used while the hand sits at 1: it survives the next pass.
+    clock_replacer.touch(2);
+    assert_eq!(clock_replacer.victim(), Some(1));
+    clock_replacer.pin(1);
+    assert_eq!(clock_replacer.victim(), Some(3));
+    clock_replacer.pin(3);
+    assert_eq!(clock_replacer.victim(), Some(2));
+
+    // Through the pool: a cache hit saves a page the hand is about to reach.
+    let path = crate::disk_manager::temp_db_path("second_chance");
+    let bpm = BufferPoolManager::new(3, DiskManager::new(&path).unwrap());
+    let new_page = || {
+        let page_id = bpm.new_page().unwrap().lock().unwrap().page_id;
+        bpm.unpin_page(page_id, false);
+        page_id
+    };
+    let [_, hot, cold] = [new_page(), new_page(), new_page()];
+    new_page(); // Clears every bit and evicts the first page; the hand moves to `hot`
+    bpm.fetch_page(hot).unwrap();
+    bpm.unpin_page(hot, false);
+    new_page();
+    let resident = |page_id| bpm.state.lock().unwrap().page_table.contains_key(&page_id);
+    assert!(resident(hot));
+    assert!(!resident(cold));
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn new_page_eviction_test() {
+    let dm = DiskManager::new(&crate::disk_manager::temp_db_path("new_page_eviction")).unwrap();
+    let bpm = BufferPoolManager::new(2, dm);
+    let frame_a = bpm.new_page().unwrap();
+    let frame_b = bpm.new_page().unwrap();
+    let page_a = frame_a.lock().unwrap().page_id;
+    let page_b = frame_b.lock().unwrap().page_id;
+    assert!(bpm.unpin_page(page_a, true));
+
+    // Only page a's frame is evictable, so page c must take it over.
+    let frame_c = bpm.new_page().unwrap();
+    assert!(Arc::ptr_eq(&frame_a, &frame_c));
+    assert!(!bpm.state.lock().unwrap().page_table.contains_key(&page_a));
+
+    // Pages b and c are both pinned, so the reused frame must not be evicted again.
+    assert!(bpm.new_page().is_none());
+    assert_eq!(bpm.state.lock().unwrap().page_table.len(), 2);
+    assert!(bpm.state.lock().unwrap().page_table.contains_key(&page_b));
+}
+
+#[test]
+fn new_page_quota_test() {
+    let path = crate::disk_manager::temp_db_path("new_page_quota");
+    let mut dm = DiskManager::new(&path).unwrap();
+    dm.set_max_pages(2); // the metadata page and one data page
+    let bpm = BufferPoolManager::new(1, dm);
+    let page_id = bpm.new_page().unwrap().lock().unwrap().page_id;
+    assert!(bpm.unpin_page(page_id, true));
+
+    // The only frame is evicted for the new page, then the quota refuses it.
+    assert!(bpm.new_page().is_none());
+    assert_eq!(bpm.available_frames(), 1);
+    let frame = bpm.fetch_page(page_id).unwrap();
+    assert_eq!(frame.lock().unwrap().page_id, page_id);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn eviction_write_error_test() {
+    use crate::disk_manager::WriteDecision;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let path = crate::disk_manager::temp_db_path("eviction_write_error");
+    let failing = Arc::new(AtomicBool::new(false));
+    let mut dm = DiskManager::new(&path)
+        .unwrap()
+        .with_write_interceptor(Box::new({
+            let failing = failing.clone();
+            // The metadata page stays writable so allocations still work.
+            move |page_id, _| {
+                if failing.load(Ordering::SeqCst) && page_id != 0 {
+                    WriteDecision::Fail
+                } else {
+                    WriteDecision::Allow
+                }
+            }
+        }));
+    let other = dm.allocate_page().unwrap();
+    dm.write_page(other, &[0; PAGE_SIZE]).unwrap();
+    let bpm = BufferPoolManager::new(1, dm);
+    let frame = bpm.new_page().unwrap();
+    let page_id = {
+        let mut frame_lock = frame.lock().unwrap();
+        frame_lock.data[0] = 7;
+        frame_lock.page_id
+    };
+    assert!(bpm.unpin_page(page_id, true));
+
+    // The dirty victim cannot be written, so it stays put and dirty.
+    failing.store(true, Ordering::SeqCst);
+    assert!(matches!(
+        bpm.try_fetch_page(other),
+        Err(BufferError::Disk(DiskError::Io(_)))
+    ));
+    assert!(matches!(bpm.try_new_page(), Err(BufferError::Disk(_))));
+    {
+        let frame_lock = frame.lock().unwrap();
+        assert_eq!(frame_lock.page_id, page_id);
+        assert!(frame_lock.is_dirty);
+    }
+    assert_eq!(bpm.available_frames(), 1);
+
+    // Once the disk recovers, the eviction goes through.
+    failing.store(false, Ordering::SeqCst);
+    assert!(bpm.fetch_page(other).is_some());
+    let mut page: Page = [0; PAGE_SIZE];
+    bpm.disk_manager
+        .lock()
+        .unwrap()
+        .read_page(page_id, &mut page)
+        .unwrap();
+    assert_eq!(page[0], 7);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn free_list_fifo_test() {
+    let dm = DiskManager::new(&crate::disk_manager::temp_db_path("free_list_fifo")).unwrap();
+    let bpm = BufferPoolManager::new(3, dm);
+    let mut page_ids = Vec::new();
+    for _ in 0..3 {
+        let frame = bpm.new_page().unwrap();
+        page_ids.push(frame.lock().unwrap().page_id);
+    }
+    // Frames are handed out in allocation order, the same order the clock sweeps.
+    for (frame_id, page_id) in page_ids.iter().enumerate() {
+        assert_eq!(bpm.state.lock().unwrap().page_table[page_id], frame_id);
+    }
+    for &page_id in page_ids.iter() {
+        bpm.unpin_page(page_id, false);
+    }
+    // So the oldest page is the first one evicted.
+    bpm.new_page().unwrap();
+    assert!(!bpm
+        .state
+        .lock()
+        .unwrap()
+        .page_table
+        .contains_key(&page_ids[0]));
+    assert!(bpm
+        .state
+        .lock()
+        .unwrap()
+        .page_table
+        .contains_key(&page_ids[1]));
+    assert!(bpm
+        .state
+        .lock()
+        .unwrap()
+        .page_table
+        .contains_key(&page_ids[2]));
+}
+
+#[test]
+fn fetch_outcome_test() {
+    let mut dm = DiskManager::new(&crate::disk_manager::temp_db_path("fetch_outcome")).unwrap();
+    let page_id = dm.allocate_page().unwrap();
+    let bpm = BufferPoolManager::new(2, dm);
+    let (_, outcome) = bpm.fetch_page_reporting(page_id).unwrap();
+    assert_eq!(outcome, FetchOutcome::Miss);
+    bpm.unpin_page(page_id, false);
+    let (_, outcome) = bpm.fetch_page_reporting(page_id).unwrap();
+    assert_eq!(outcome, FetchOutcome::Hit);
+    assert_eq!(bpm.stats(), BufferPoolStats { hits: 1, misses: 1 });
+}
+
+#[test]
+fn page_view_test() {
+    let mut dm = DiskManager::new(&crate::disk_manager::temp_db_path("page_view")).unwrap();
+    let page_id = dm.allocate_page().unwrap();
+    let mut page: Page = [0; PAGE_SIZE];
+    SlottedPage::init(&mut page);
+    dm.write_page(page_id, &page).unwrap();
+    let bpm = BufferPoolManager::new(2, dm);
+
+    let slot_id = {
+        let mut view = bpm.page_view(page_id).unwrap();
+        assert_eq!(view.page_id(), page_id);
+        // The view keeps neither the pool nor its own page locked.
+        assert_eq!(bpm.available_frames(), 1);
+        let other = bpm.clone();
+        std::thread::spawn(move || {
+            assert!(other.fetch_page(page_id).is_some());
+            assert!(other.unpin_page(page_id, false));
+        })
+        .join()
+        .unwrap();
+        view.with_slotted(|sp| sp.insert(b"viewed").unwrap())
+    };
+    assert!(bpm.state.lock().unwrap().dirty_pages.contains(&page_id));
+
+    let frame = bpm.buffer_pool[bpm.state.lock().unwrap().page_table[&page_id]].clone();
+    {
+        let mut frame_lock = frame.lock().unwrap();
+        assert!(frame_lock.is_dirty);
+        assert_eq!(frame_lock.pin_count, 0);
+        let sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+        assert_eq!(sp.read(slot_id).unwrap(), b"viewed");
+    }
+    // Unpinned, so the frame can be evicted.
+    let mut state = bpm.state.lock().unwrap();
+    let frame_id = state.page_table[&page_id];
+    assert_eq!(state.replacer.victim(), Some(frame_id));
+}
+
+#[test]
+fn latch_coupling_test() {
+    use crate::disk_manager::temp_db_path;
+
+    let path = temp_db_path("latch_coupling");
+    let bpm = BufferPoolManager::new(4, DiskManager::new(&path).unwrap());
+    // A three level "tree": root -> inner -> leaf.
+    let levels: Vec<u64> = (0..3)
+        .map(|_| {
+            let page_id = bpm.new_page().unwrap().lock().unwrap().page_id;
+            bpm.unpin_page(page_id, true);
+            page_id
+        })
+        .collect();
+    let [root, inner, leaf] = levels[..] else {
+        unreachable!()
+    };
+
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            let bpm = bpm.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    let pages: Vec<PageLatch> = [root, inner, leaf]
+                        .iter()
+                        .map(|&id| bpm.fetch_latched(id).unwrap())
+                        .collect();
+                    if t % 2 == 0 {
+                        // Writer: crab down exclusively, then do a
+                        // read-modify-write of a counter and a busy flag.
+                        let parent = pages[0].latch_exclusive();
+                        let child = pages[1].latch_exclusive();
+                        drop(parent);
+                        let mut leaf_latch = pages[2].latch_exclusive();
+                        drop(child);
+                        leaf_latch.write(|page| page[8] = 1);
+                        let count = leaf_latch.read(|page| page[0]);
+                        std::thread::yield_now();
+                        leaf_latch.write(|page| {
+                            page[0] = count + 1;
+                            page[8] = 0;
+                        });
+                    } else {
+                        // Reader: crab down shared and never see a write half done.
+                        let parent = pages[0].latch_shared();
+                        let child = pages[1].latch_shared();
+                        drop(parent);
+                        let leaf_latch = pages[2].latch_shared();
+                        drop(child);
+                        assert_eq!(leaf_latch.read(|page| page[8]), 0);
+                    }
+                    for page in pages.iter() {
+                        bpm.unpin_page(page.page_id(), t % 2 == 0);
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let leaf_latch = bpm.fetch_latched(leaf).unwrap();
+    assert_eq!(leaf_latch.latch_shared().read(|page| page[0]), 4 * 50);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn unpin_pages_test() {
+    use crate::disk_manager::temp_db_path;
+
+    let path = temp_db_path("unpin_pages");
+    let bpm = BufferPoolManager::new(3, DiskManager::new(&path).unwrap());
+    let frames: Vec<Arc<Mutex<Frame>>> = (0..3).map(|_| bpm.new_page().unwrap()).collect();
+    let ids: Vec<u64> = frames.iter().map(|f| f.lock().unwrap().page_id).collect();
+    assert!(bpm.new_page().is_none());
+
+    let batch = [
+        (ids[0], true),
+        (ids[1], false),
+        (ids[2], false),
+        (999, false),
+    ];
+    assert_eq!(bpm.unpin_pages(&batch), 3);
+    for frame in frames.iter() {
+        assert_eq!(frame.lock().unwrap().pin_count, 0);
+    }
+    assert!(frames[0].lock().unwrap().is_dirty);
+    // All frames are evictable again.
+    assert!(bpm.new_page().is_some());
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn warmup_test() {
+    use crate::disk_manager::temp_db_path;
+
+    let path = temp_db_path("warmup");
+    let mut dm = DiskManager::new(&path).unwrap();
+    let ids: Vec<u64> = (0..3).map(|_| dm.allocate_page().unwrap()).collect();
+    let bpm = BufferPoolManager::new(2, dm);
+
+    assert_eq!(bpm.warmup(&ids).unwrap(), 2);
+    for &page_id in &ids[..2] {
+        let (_, outcome) = bpm.fetch_page_reporting(page_id).unwrap();
+        assert_eq!(outcome, FetchOutcome::Hit);
+        bpm.unpin_page(page_id, false);
+    }
+    assert_eq!(bpm.stats(), BufferPoolStats { hits: 2, misses: 0 });
+    // Warmed pages are unpinned, so the third page can still be fetched.
+    assert!(bpm.fetch_page(ids[2]).is_some());
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn slot_lock_test() {
+    use crate::disk_manager::temp_db_path;
+
+    let path = temp_db_path("slot_lock");
+    let bpm = BufferPoolManager::new(2, DiskManager::new(&path).unwrap());
+    let page_id = {
+        let frame = bpm.new_page().unwrap();
+        let mut frame_lock = frame.lock().unwrap();
+        let mut sp = SlottedPage::init(&mut frame_lock.data);
+        for _ in 0..4 {
+            sp.insert(&0u64.to_le_bytes()).unwrap();
+        }
+        frame_lock.page_id
+    };
+
+    // Two threads per slot increment that slot's counter.
+    let threads: Vec<_> = (0..8u16)
+        .map(|t| {
+            let bpm = bpm.clone();
+            std::thread::spawn(move || {
+                let page = bpm.fetch_latched(page_id).unwrap();
+                for _ in 0..200 {
+                    let mut slot = page.lock_slot(SlotId(t % 4));
+                    let count = u64::from_le_bytes(slot.read().unwrap().try_into().unwrap());
+                    std::thread::yield_now();
+                    assert!(slot.write(&(count + 1).to_le_bytes()));
+                }
+                bpm.unpin_page(page_id, true);
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let frame = bpm.fetch_page(page_id).unwrap();
+    let mut frame_lock = frame.lock().unwrap();
+    let sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+    for slot in 0..4 {
+        assert_eq!(sp.read(SlotId(slot)), Some(&400u64.to_le_bytes()[..]));
+    }
+    drop(frame_lock);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn checkpoint_dirty_set_test() {
+    use crate::disk_manager::temp_db_path;
+
+    let path = temp_db_path("checkpoint_dirty");
+    let bpm = BufferPoolManager::new(8, DiskManager::new(&path).unwrap());
+    let ids: Vec<u64> = (0..8)
+        .map(|_| {
+            let page_id = bpm.new_page().unwrap().lock().unwrap().page_id;
+            bpm.unpin_page(page_id, false);
+            page_id
+        })
+        .collect();
+    bpm.checkpoint().unwrap();
+
+    let fetched = bpm.fetch_page(ids[2]).unwrap();
+    fetched.lock().unwrap().data[0] = 1;
+    bpm.unpin_page(ids[2], true);
+    {
+        let mut view = bpm.page_view(ids[5]).unwrap();
+        SlottedPage::init(&mut view.frame.lock().unwrap().data);
+        view.with_slotted(|sp| sp.insert(b"x").unwrap());
+    }
+
+    let writes = bpm.disk_manager.lock().unwrap().stats().writes;
+    assert_eq!(bpm.checkpoint().unwrap(), 2);
+    // The two dirty pages plus the metadata header.
+    assert_eq!(bpm.disk_manager.lock().unwrap().stats().writes, writes + 3);
+    assert_eq!(bpm.checkpoint().unwrap(), 0);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn apply_redo_test() {
+    use crate::disk_manager::temp_db_path;
+
+    let path = temp_db_path("apply_redo");
+    let bpm = BufferPoolManager::new(2, DiskManager::new(&path).unwrap());
+    let page_id = bpm.new_page().unwrap().lock().unwrap().page_id;
+    bpm.unpin_page(page_id, false);
+
+    bpm.apply_redo(page_id, 5, 100, b"redo").unwrap();
+    let frame = bpm.fetch_page(page_id).unwrap();
+    assert_eq!(&frame.lock().unwrap().data[100..104], b"redo");
+    frame.lock().unwrap().data[100] = b'R';
+    bpm.unpin_page(page_id, true);
+    bpm.checkpoint().unwrap();
+
+    // Replaying the same record, or an older one, leaves the page alone.
+    bpm.apply_redo(page_id, 5, 100, b"redo").unwrap();
+    bpm.apply_redo(page_id, 3, 100, b"old!").unwrap();
+    assert_eq!(&frame.lock().unwrap().data[100..104], b"Redo");
+    assert_eq!(bpm.checkpoint().unwrap(), 0);
+
+    // The page LSN survives a flush and an eviction.
+    bpm.flush_page(page_id).unwrap();
+    for _ in 0..2 {
+        let other = bpm.new_page().unwrap().lock().unwrap().page_id;
+        bpm.unpin_page(other, false);
+    }
+    assert!(!bpm.state.lock().unwrap().page_table.contains_key(&page_id));
+    bpm.apply_redo(page_id, 5, 100, b"redo").unwrap();
+    let frame = bpm.fetch_page(page_id).unwrap();
+    assert_eq!(&frame.lock().unwrap().data[100..104], b"Redo");
+    bpm.unpin_page(page_id, false);
+    assert_eq!(bpm.checkpoint().unwrap(), 0);
+
+    // Records may not touch the page LSN or checksum.
+    assert!(bpm.apply_redo(page_id, 9, PAGE_SIZE - 2, b"long").is_err());
+    assert!(bpm
+        .apply_redo(page_id, 9, USABLE_PAGE_SIZE - 2, b"long")
+        .is_err());
+
+    // A page that cannot be read reports why.
+    let err = bpm.apply_redo(page_id + 100, 9, 100, b"redo").unwrap_err();
+    let source = err
+        .get_ref()
+        .and_then(|err| err.downcast_ref::<DiskError>());
+    assert!(matches!(source, Some(DiskError::PageOutOfRange)));
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn pin_budget_test() {
+    use crate::disk_manager::temp_db_path;
+
+    let path = temp_db_path("pin_budget");
+    let bpm = BufferPoolManager::new(8, DiskManager::new(&path).unwrap());
+    let ids: Vec<u64> = (0..3)
+        .map(|_| {
+            let page_id = bpm.new_page().unwrap().lock().unwrap().page_id;
+            bpm.unpin_page(page_id, false);
+            page_id
+        })
+        .collect();
+    bpm.set_pin_budget(2);
+
+    let _a = bpm.try_fetch_page(ids[0]).unwrap();
+    let _b = bpm.try_fetch_page(ids[1]).unwrap();
+    assert!(matches!(
+        bpm.try_fetch_page(ids[2]),
+        Err(BufferError::PinBudgetExceeded)
+    ));
+    // Hits count against the budget too.
+    assert!(bpm.fetch_page(ids[0]).is_none());
+    assert!(bpm.new_page().is_none());
+
+    bpm.unpin_page(ids[0], false);
+    assert!(bpm.try_fetch_page(ids[2]).is_ok());
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn cloned_handles_test() {
+    use crate::disk_manager::temp_db_path;
+
+    let path = temp_db_path("cloned_handles");
+    let mut dm = DiskManager::new(&path).unwrap();
+    let page_ids: Vec<u64> = (0..8)
+        .map(|i| {
+            let page_id = dm.allocate_page().unwrap();
+            dm.write_page(page_id, &[i as u8; PAGE_SIZE]).unwrap();
+            page_id
+        })
+        .collect();
+    let bpm = BufferPoolManager::new(4, dm);
+
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let bpm = bpm.clone();
+            let page_ids = page_ids.clone();
+            std::thread::spawn(move || {
+                for round in 0..50 {
+                    let i = (t + round) % page_ids.len();
+                    let Some(frame) = bpm.fetch_page(page_ids[i]) else {
+                        continue; // every frame pinned by the other threads
+                    };
+                    assert_eq!(frame.lock().unwrap().data[0], i as u8);
+                    assert!(bpm.unpin_page(page_ids[i], false));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let stats = bpm.stats();
+    assert!(stats.hits + stats.misses > 0);
+    assert_eq!(bpm.available_frames(), 4);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn fetch_page_wait_test() {
+    use crate::disk_manager::temp_db_path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let path = temp_db_path("fetch_page_wait");
+    let mut dm = DiskManager::new(&path).unwrap();
+    let page_ids: Vec<u64> = (0..3).map(|_| dm.allocate_page().unwrap()).collect();
+    let bpm = BufferPoolManager::new(2, dm);
+
+    // Two threads keep both frames pinned on their own pages nearly all the time.
+    let stop = Arc::new(AtomicBool::new(false));
+    let hogs: Vec<_> = page_ids[..2]
+        .iter()
+        .map(|&page_id| {
+            let (bpm, stop) = (bpm.clone(), stop.clone());
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    if bpm.fetch_page(page_id).is_some() {
+                        std::thread::sleep(Duration::from_millis(1));
+                        bpm.unpin_page(page_id, false);
+                    }
+                }
+            })
+        })
+        .collect();
+    std::thread::sleep(Duration::from_millis(20));
+
+    let frame = bpm
+        .fetch_page_wait(page_ids[2], Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(frame.lock().unwrap().page_id, page_ids[2]);
+    bpm.unpin_page(page_ids[2], false);
+    stop.store(true, Ordering::Relaxed);
+    for hog in hogs {
+        hog.join().unwrap();
+    }
+
+    // With every frame pinned and nobody unpinning, the wait times out.
+    let pinned: Vec<_> = page_ids[..2]
+        .iter()
+        .map(|&page_id| bpm.fetch_page(page_id).unwrap())
+        .collect();
+    assert!(matches!(
+        bpm.fetch_page_wait(page_ids[2], Duration::from_millis(20)),
+        Err(BufferError::PoolExhausted)
+    ));
+    drop(pinned);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn eviction_batch_test() {
+    use crate::disk_manager::temp_db_path;
+    let path = temp_db_path("eviction_batch");
+    let mut dm = DiskManager::new(&path).unwrap();
+    for page_id in 1..=8 {
+        dm.write_page(page_id, &[0; PAGE_SIZE]).unwrap();
+    }
+    let bpm = BufferPoolManager::new(4, dm);
+    bpm.set_eviction_batch(Some(3)).unwrap();
+
+    // Dirty pages land in frames out of page id order.
+    for page_id in [4, 2, 3, 1] {
+        let frame = bpm.fetch_page(page_id).unwrap();
+        frame.lock().unwrap().data[0] = page_id as u8;
+        bpm.unpin_page(page_id, true);
+    }
+    let before = bpm.disk_manager.lock().unwrap().stats();
+    for page_id in 5..=7 {
+        bpm.fetch_page(page_id).unwrap();
+        bpm.unpin_page(page_id, false);
+    }
+    // Three victims are held back, nothing is written yet.
+    assert_eq!(
+        bpm.disk_manager.lock().unwrap().stats().writes,
+        before.writes
+    );
+
+    bpm.fetch_page(8).unwrap();
+    bpm.unpin_page(8, false);
+    let after = bpm.disk_manager.lock().unwrap().stats();
+    assert_eq!(after.writes - before.writes, 4);
+    assert_eq!(after.write_runs - before.write_runs, 1);
+
+    for page_id in 1..=4 {
+        let frame = bpm.fetch_page(page_id).unwrap();
+        assert_eq!(frame.lock().unwrap().data[0], page_id as u8);
+        bpm.unpin_page(page_id, false);
+    }
+
+    // A held back page is written before it is read again.
+    let frame = bpm.fetch_page(5).unwrap();
+    frame.lock().unwrap().data[0] = 5;
+    bpm.unpin_page(5, true);
+    for page_id in 1..=4 {
+        bpm.fetch_page(page_id).unwrap();
+        bpm.unpin_page(page_id, false);
+    }
+    let frame = bpm.fetch_page(5).unwrap();
+    assert_eq!(frame.lock().unwrap().data[0], 5);
+    bpm.unpin_page(5, false);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn discard_changes_test() {
+    use crate::disk_manager::temp_db_path;
+    let path = temp_db_path("discard_changes");
+    let mut dm = DiskManager::new(&path).unwrap();
+    dm.write_page(1, &[7; PAGE_SIZE]).unwrap();
+    let bpm = BufferPoolManager::new(2, dm);
+
+    let frame = bpm.fetch_page(1).unwrap();
+    assert!(!bpm.discard_changes(1).unwrap());
+    frame.lock().unwrap().data[..4].copy_from_slice(b"oops");
+    bpm.mark_dirty(1);
+    let other = bpm.fetch_page(1).unwrap();
+    assert!(!bpm.discard_changes(1).unwrap());
+    bpm.unpin_page(1, false);
+    drop(other);
+
+    assert!(bpm.discard_changes(1).unwrap());
+    {
+        let frame_lock = frame.lock().unwrap();
+        assert!(!frame_lock.is_dirty);
+        assert!(frame_lock.data.iter().all(|&b| b == 7));
+    }
+    bpm.unpin_page(1, false);
+    assert_eq!(bpm.checkpoint().unwrap(), 0);
+    assert!(!bpm.discard_changes(9).unwrap());
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn fetch_page_with_eviction_test() {
+    use crate::disk_manager::temp_db_path;
+    let path = temp_db_path("fetch_page_with_eviction");
+    let mut dm = DiskManager::new(&path).unwrap();
+    for page_id in 1..=3 {
+        dm.write_page(page_id, &[page_id as u8; PAGE_SIZE]).unwrap();
+    }
+    let bpm = BufferPoolManager::new(2, dm);
+
+    for page_id in [1, 2] {
+        let (_, evicted) = bpm.fetch_page_with_eviction(page_id).unwrap();
+        assert_eq!(evicted, None);
+    }
+    bpm.unpin_page(1, false);
+    let (_, evicted) = bpm.fetch_page_with_eviction(2).unwrap();
+    assert_eq!(evicted, None);
+
+    let (frame, evicted) = bpm.fetch_page_with_eviction(3).unwrap();
+    assert_eq!(evicted, Some(1));
+    assert_eq!(frame.lock().unwrap().data[0], 3);
+    assert!(matches!(
+        bpm.fetch_page_with_eviction(1),
+        Err(BufferError::PoolExhausted)
+    ));
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn new_pages_test() {
+    use crate::disk_manager::temp_db_path;
+    let path = temp_db_path("new_pages");
+    let bpm = BufferPoolManager::new(8, DiskManager::new(&path).unwrap());
+
+    let run = bpm.new_pages(4).unwrap();
+    let ids: Vec<u64> = run.iter().map(|f| f.lock().unwrap().page_id).collect();
+    assert!(ids.windows(2).all(|w| w[1] == w[0] + 1));
+    assert!(run.iter().all(|f| f.lock().unwrap().pin_count == 1));
+    assert_eq!(bpm.available_frames(), 4);
+
+    assert!(matches!(bpm.new_pages(5), Err(BufferError::PoolExhausted)));
+    bpm.set_pin_budget(6);
+    assert!(matches!(
+        bpm.new_pages(3),
+        Err(BufferError::PinBudgetExceeded)
+    ));
+    assert_eq!(bpm.new_pages(2).unwrap().len(), 2);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn prefer_clean_victim_test() {
+    use crate::disk_manager::temp_db_path;
+    let path = temp_db_path("prefer_clean_victim");
+    let mut dm = DiskManager::new(&path).unwrap();
+    for page_id in 1..=5 {
+        dm.write_page(page_id, &[0; PAGE_SIZE]).unwrap();
+    }
+    let bpm = BufferPoolManager::new(3, dm);
+    for (page_id, dirty) in [(1, true), (2, true), (3, false)] {
+        bpm.fetch_page(page_id).unwrap();
+        bpm.unpin_page(page_id, dirty);
+    }
+    let writes = || bpm.disk_manager.lock().unwrap().stats().writes;
+    let before = writes();
+
+    bpm.set_prefer_clean(3);
+    let (_, evicted) = bpm.fetch_page_with_eviction(4).unwrap();
+    assert_eq!(evicted, Some(3));
+    assert_eq!(writes(), before);
+    bpm.unpin_page(4, true);
+
+    // Only dirty candidates left: the clock order decides.
+    let (_, evicted) = bpm.fetch_page_with_eviction(5).unwrap();
+    assert_eq!(evicted, Some(1));
+    assert_eq!(writes(), before + 1);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn config_test() {
+    use crate::disk_manager::temp_db_path;
+
+    let path = temp_db_path("pool_config");
+    let mut dm = DiskManager::new(&path).unwrap();
+    dm.set_sync_policy(SyncPolicy::Never);
+    let bpm = BufferPoolManager::with_replacer(7, dm, ReplacerPolicy::Clock);
+    assert_eq!(
+        bpm.config(),
+        BufferPoolConfig {
+            pool_size: 7,
+            replacer: "clock",
+            sync_policy: SyncPolicy::Never,
+            page_size: PAGE_SIZE,
+        }
+    );
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn flush_on_drop_test() {
+    use crate::disk_manager::temp_db_path;
+    use crate::heap_file::HeapFile;
+
+    let path = temp_db_path("flush_on_drop");
+    let bpm = BufferPoolManager::new(8, DiskManager::new(&path).unwrap());
+    let mut heap = HeapFile::new(bpm.clone());
+    let tids: Vec<_> = (0..50u8)
+        .map(|i| heap.insert_tuple(&[i; 100]).unwrap())
+        .collect();
+
+    // A pinned dirty page is written out but stays pinned and resident.
+    let page_id = tids[0].page_id;
+    let frame = bpm.fetch_page(page_id).unwrap();
+    bpm.mark_dirty(page_id);
+    bpm.flush_all_pages().unwrap();
+    {
+        let frame_lock = frame.lock().unwrap();
+        assert!(!frame_lock.is_dirty);
+        assert_eq!(frame_lock.pin_count, 1);
+    }
+    bpm.unpin_page(page_id, false);
+
+    // Dirtied after the flush and never flushed again: only dropping the
+    // last handle writes it.
+    assert!(heap.delete_tuple(tids[3]));
+    let pages = heap.pages().to_vec();
+    let clone = bpm.clone();
+    drop(heap);
+    drop(bpm);
+    assert!(!clone.state.lock().unwrap().dirty_pages.is_empty());
+    drop(clone);
+
+    let bpm = BufferPoolManager::new(8, DiskManager::new(&path).unwrap());
+    let mut reopened = HeapFile::open(bpm, pages);
+    assert_eq!(reopened.read_tuple(tids[3]), None);
+    for (i, &tid) in tids.iter().enumerate().filter(|&(i, _)| i != 3) {
+        assert_eq!(reopened.read_tuple(tid).unwrap(), [i as u8; 100]);
+    }
+    let _ = std::fs::remove_file(path);
+}
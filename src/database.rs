@@ -0,0 +1,157 @@
+use crate::buffer_manager::{BufferPoolManager, ReplacerPolicy};
+use crate::disk_manager::{DiskError, DiskManager, SyncPolicy, PAGE_SIZE};
+use crate::heap_file::{HeapFile, PageId};
+
+const DEFAULT_POOL_SIZE: usize = 64;
+
+// Settings for opening a Database. Everything except the path has a default.
+pub struct DbBuilder {
+    path: String,
+    page_size: usize,
+    pool_size: usize,
+    replacer: ReplacerPolicy,
+    sync_policy: SyncPolicy,
+    in_memory: bool,
+    page_checksums: bool,
+}
+
+impl DbBuilder {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            page_size: PAGE_SIZE,
+            pool_size: DEFAULT_POOL_SIZE,
+            replacer: ReplacerPolicy::default(),
+            sync_policy: SyncPolicy::default(),
+            in_memory: false,
+            page_checksums: false,
+        }
+    }
+
+    // Only PAGE_SIZE is supported; build fails for anything else.
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    pub fn pool_size(mut self, pool_size: usize) -> Self {
+        self.pool_size = pool_size;
+        self
+    }
+
+    pub fn replacer(mut self, replacer: ReplacerPolicy) -> Self {
+        self.replacer = replacer;
+        self
+    }
+
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    // Keep the whole database in memory and never create the file. Nothing
+    // survives the Database being dropped.
+    pub fn in_memory(mut self) -> Self {
+        self.in_memory = true;
+        self
+    }
+
+    // Checksum every page so torn or corrupted pages fail to read, see
+    // DiskManager::set_page_checksums. Only for new files.
+    pub fn page_checksums(mut self) -> Self {
+        self.page_checksums = true;
+        self
+    }
+
+    pub fn build(self) -> Result<Database, DiskError> {
+        if self.page_size != PAGE_SIZE {
+            return Err(DiskError::UnsupportedPageSize(self.page_size));
+        }
+        let mut dm = if self.in_memory {
+            DiskManager::new_in_memory()
+        } else {
+            DiskManager::new(&self.path)?
+        };
+        dm.set_sync_policy(self.sync_policy);
+        dm.set_page_checksums(self.page_checksums);
+        let bpm = BufferPoolManager::with_replacer(self.pool_size, dm, self.replacer);
+        Ok(Database {
+            buffer_pool_manager: bpm,
+        })
+    }
+}
+
+// An open database: one file and the buffer pool shared by all its heaps.
+pub struct Database {
+    buffer_pool_manager: BufferPoolManager,
+}
+
+impl Database {
+    pub fn create_heap(&self) -> HeapFile {
+        HeapFile::new(self.buffer_pool_manager.clone())
+    }
+
+    // Reopen a heap from the pages it reported through HeapFile::pages.
+    pub fn open_heap(&self, pages: Vec<PageId>) -> HeapFile {
+        HeapFile::open(self.buffer_pool_manager.clone(), pages)
+    }
+
+    pub fn buffer_pool_manager(&self) -> BufferPoolManager {
+        self.buffer_pool_manager.clone()
+    }
+}
+
+#[test]
+fn db_builder_test() {
+    use crate::disk_manager::temp_db_path;
+
+    let path = temp_db_path("db_builder");
+    assert!(matches!(
+        DbBuilder::new(&path).page_size(8192).build(),
+        Err(DiskError::UnsupportedPageSize(8192))
+    ));
+
+    let db = DbBuilder::new(&path)
+        .pool_size(4)
+        .replacer(ReplacerPolicy::Clock)
+        .sync_policy(SyncPolicy::Never)
+        .page_checksums()
+        .build()
+        .unwrap();
+    let mut heap = db.create_heap();
+    let tid = heap.insert_tuple(b"through the handle").unwrap();
+    assert_eq!(heap.read_tuple(tid).unwrap(), b"through the handle");
+
+    let pages = heap.pages().to_vec();
+    drop(heap);
+    let mut reopened = db.open_heap(pages);
+    assert_eq!(reopened.read_tuple(tid).unwrap(), b"through the handle");
+    assert_eq!(reopened.insert_tuple(b"more").unwrap().page_id, tid.page_id);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn in_memory_db_test() {
+    use crate::disk_manager::temp_db_path;
+
+    let path = temp_db_path("in_memory_db");
+    let db = DbBuilder::new(&path)
+        .pool_size(2)
+        .in_memory()
+        .build()
+        .unwrap();
+    let mut heap = db.create_heap();
+    let tids: Vec<_> = (0..300u32)
+        .map(|i| heap.insert_tuple(&[i as u8; 40]).unwrap())
+        .collect();
+    assert!(heap.pages().len() > 2); // more pages than frames, so some were evicted
+    assert!(heap.delete_tuple(tids[7]));
+    let rows: Vec<_> = heap.scan().collect();
+    assert_eq!(rows.len(), 299);
+    assert!(rows.iter().all(|(tid, _)| *tid != tids[7]));
+    assert_eq!(heap.read_tuple(tids[299]).unwrap(), [299u32 as u8; 40]);
+    db.buffer_pool_manager().flush_all_pages().unwrap();
+
+    assert!(!std::path::Path::new(&path).exists());
+}
@@ -1,56 +1,1198 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use crate::crc32::crc32;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::{Arc, Condvar, Mutex};
 pub const PAGE_SIZE: usize = 4096;
 
 // A Page is just an array of bytes.
 pub type Page = [u8; PAGE_SIZE];
+
+// The last PAGE_CHECKSUM_SIZE bytes of every page are reserved for a CRC32 of
+// the rest, see set_page_checksums. Right before them, at PAGE_LSN_OFFSET,
+// every page carries the LSN of the last logged change applied to it, see
+// BufferPoolManager::apply_redo. Page formats only use the bytes before
+// USABLE_PAGE_SIZE.
+pub const PAGE_CHECKSUM_SIZE: usize = 4;
+pub const PAGE_LSN_SIZE: usize = 8;
+pub const PAGE_LSN_OFFSET: usize = PAGE_SIZE - PAGE_CHECKSUM_SIZE - PAGE_LSN_SIZE;
+pub const USABLE_PAGE_SIZE: usize = PAGE_LSN_OFFSET;
+const PAGE_CHECKSUM_OFFSET: usize = PAGE_SIZE - PAGE_CHECKSUM_SIZE;
+
+// Page 0 holds the database-wide metadata header and is never handed out as
+// a data page. Its layout: magic (8 bytes), format version (u32), page size
+// (u32), num_pages (u64), free-list root (u64), catalog root (u64),
+// compaction log root (u64), then a CRC32 of everything before it. Roots of
+// 0 mean unset.
+pub const METADATA_PAGE_ID: u64 = 0;
+pub const FORMAT_VERSION: u32 = 2;
+const META_MAGIC: &[u8; 8] = b"DUCKLING";
+const META_CRC_OFFSET: usize = 48;
+
+// Compacting a page rewrites it in place, so a crash halfway through would
+// leave it torn. The compacted image is first written to the log's image
+// page, then its intent page records which page the image belongs to; only
+// then is the real page overwritten. The log is two consecutive pages whose
+// first id is the compaction log root, allocated on first use and kept for
+// the life of the database. Opening a file whose intent is still set copies
+// the image over again.
+pub const COMPACTION_MAGIC: &[u8; 4] = b"CMPT";
+const FIRST_DATA_PAGE_ID: u64 = METADATA_PAGE_ID + 1;
+
+// Everything that can go wrong when reading or writing the database file.
+#[derive(Debug)]
+pub enum DiskError {
+    Io(std::io::Error),
+    ChecksumMismatch(u64), // page contents do not match their stored checksum
+    PageOutOfRange,        // page starts past the end of the file
+    TruncatedPage,         // file ends in the middle of the page
+    QuotaExceeded,         // allocation would grow the file beyond max_pages
+    ReadOnly,              // write attempted on a read-only DiskManager
+    UnsupportedPageSize(usize), // pages are always PAGE_SIZE bytes
+    AlreadyOpen,           // another DiskManager holds the file's lock
+    BadMetadata,           // page 0 is not a valid metadata header for this build
+}
+
+impl From<std::io::Error> for DiskError {
+    fn from(err: std::io::Error) -> Self {
+        DiskError::Io(err)
+    }
+}
+
+impl From<DiskError> for std::io::Error {
+    fn from(err: DiskError) -> Self {
+        match err {
+            DiskError::Io(err) => err,
+            other => std::io::Error::other(other),
+        }
+    }
+}
+
+impl fmt::Display for DiskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiskError::Io(err) => write!(f, "I/O error: {}", err),
+            DiskError::ChecksumMismatch(page_id) => write!(
+                f,
+                "page {} does not match its checksum, e.g. after a torn write",
+                page_id
+            ),
+            DiskError::PageOutOfRange => write!(f, "page is beyond the end of the file"),
+            DiskError::TruncatedPage => write!(f, "page is truncated"),
+            DiskError::QuotaExceeded => write!(f, "page quota exceeded"),
+            DiskError::ReadOnly => write!(f, "database file is read-only"),
+            DiskError::UnsupportedPageSize(size) => {
+                write!(f, "unsupported page size {} (only {})", size, PAGE_SIZE)
+            }
+            DiskError::AlreadyOpen => write!(f, "database file is already open"),
+            DiskError::BadMetadata => write!(f, "bad or corrupt metadata header"),
+        }
+    }
+}
+
+impl std::error::Error for DiskError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiskError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// Counters of the page I/O a DiskManager has performed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiskStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub syncs: u64,
+    pub write_runs: u64, // Contiguous runs issued by write_pages
+}
+
+// The metadata header on page 0, see METADATA_PAGE_ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DbMetadata {
+    pub format_version: u32,
+    pub page_size: u32,
+    pub num_pages: u64,
+    pub free_list_root: Option<u64>,
+    pub catalog_root: Option<u64>,
+    pub compaction_log: Option<u64>,
+}
+
+impl DbMetadata {
+    fn encode(&self) -> Page {
+        let mut page: Page = [0; PAGE_SIZE];
+        page[0..8].copy_from_slice(META_MAGIC);
+        page[8..12].copy_from_slice(&self.format_version.to_le_bytes());
+        page[12..16].copy_from_slice(&self.page_size.to_le_bytes());
+        page[16..24].copy_from_slice(&self.num_pages.to_le_bytes());
+        page[24..32].copy_from_slice(&self.free_list_root.unwrap_or(0).to_le_bytes());
+        page[32..40].copy_from_slice(&self.catalog_root.unwrap_or(0).to_le_bytes());
+        page[40..48].copy_from_slice(&self.compaction_log.unwrap_or(0).to_le_bytes());
+        let crc = crc32(&page[..META_CRC_OFFSET]);
+        page[META_CRC_OFFSET..META_CRC_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    // None for an all-zero page, i.e. a file that was never checkpointed.
+    fn decode(page: &Page) -> Result<Option<Self>, DiskError> {
+        if page.iter().all(|&b| b == 0) {
+            return Ok(None);
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(page[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(page[at..at + 8].try_into().unwrap());
+        let root = |at: usize| Some(u64_at(at)).filter(|&page_id| page_id != 0);
+        if &page[0..8] != META_MAGIC || u32_at(META_CRC_OFFSET) != crc32(&page[..META_CRC_OFFSET]) {
+            return Err(DiskError::BadMetadata);
+        }
+        let meta = DbMetadata {
+            format_version: u32_at(8),
+            page_size: u32_at(12),
+            num_pages: u64_at(16),
+            free_list_root: root(24),
+            catalog_root: root(32),
+            compaction_log: root(40),
+        };
+        if meta.format_version != FORMAT_VERSION || meta.page_size as usize != PAGE_SIZE {
+            return Err(DiskError::BadMetadata);
+        }
+        Ok(Some(meta))
+    }
+}
+
+// Group commit: grouped writers take a ticket after writing their page and
+// then wait until the file is durable up to that ticket. The first waiter
+// that finds no fsync in progress becomes the flusher and covers everything
+// written so far with one fsync, so concurrent writers share fsyncs.
+struct GroupCommit {
+    sync_file: Option<File>, // Second handle to the database file, used for the fsyncs
+    state: Mutex<GroupState>,
+    synced: Condvar,
+}
+
+struct GroupState {
+    written: u64, // Tickets handed out
+    durable: u64, // Tickets covered by a finished fsync
+    flushing: bool,
+    syncs: u64,
+}
+
+impl GroupCommit {
+    fn new(sync_file: Option<File>) -> Self {
+        Self {
+            sync_file,
+            state: Mutex::new(GroupState {
+                written: 0,
+                durable: 0,
+                flushing: false,
+                syncs: 0,
+            }),
+            synced: Condvar::new(),
+        }
+    }
+
+    fn ticket(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        state.written
+    }
+
+    fn wait_durable(&self, ticket: u64) -> Result<(), DiskError> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.durable >= ticket {
+                return Ok(());
+            }
+            if state.flushing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            state.flushing = true;
+            let target = state.written;
+            drop(state);
+            let result = match &self.sync_file {
+                Some(file) => file.sync_data(),
+                None => Ok(()),
+            };
+            state = self.state.lock().unwrap();
+            state.flushing = false;
+            state.syncs += 1;
+            if result.is_ok() {
+                state.durable = target;
+            }
+            self.synced.notify_all();
+            result?;
+        }
+    }
+}
+
+// When the DiskManager asks the OS to make writes durable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    #[default]
+    OnSync, // fsync on sync() and for grouped writes
+    Never, // skip every fsync; a crash may lose anything not yet synced
+}
+
+// Identifies who owns an allocated page, e.g. one HeapFile among the tables
+// sharing a file.
+pub type OwnerTag = u32;
+
+// Pages freed by any owner of the file, reused before the file grows, plus the
+// owner tag of every tagged page that is still in use.
+#[derive(Debug, Default)]
+pub struct FreeSpaceDirectory {
+    free: Vec<u64>,
+    owners: HashMap<u64, OwnerTag>,
+    next_owner: OwnerTag,
+}
+
+impl FreeSpaceDirectory {
+    fn push_free(&mut self, page_id: u64) {
+        self.owners.remove(&page_id);
+        if !self.free.contains(&page_id) {
+            self.free.push(page_id);
+        }
+    }
+
+    pub fn free_pages(&self) -> &[u64] {
+        &self.free
+    }
+
+    pub fn owner(&self, page_id: u64) -> Option<OwnerTag> {
+        self.owners.get(&page_id).copied()
+    }
+}
+
+// Where the pages live: the database file, or a byte buffer that behaves like
+// one for throwaway databases.
+enum Storage {
+    File(File),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Storage {
+    fn sync_data(&self) -> std::io::Result<()> {
+        match self {
+            Storage::File(file) => file.sync_data(),
+            Storage::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for Storage {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Storage::File(file) => file.read(buf),
+            Storage::Memory(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl Write for Storage {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Storage::File(file) => file.write(buf),
+            Storage::Memory(cursor) => cursor.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Storage::File(file) => file.flush(),
+            Storage::Memory(cursor) => cursor.flush(),
+        }
+    }
+}
+
+impl Seek for Storage {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Storage::File(file) => file.seek(pos),
+            Storage::Memory(cursor) => cursor.seek(pos),
+        }
+    }
+}
+
+// What a write interceptor lets happen to a page write, see
+// DiskManager::with_write_interceptor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteDecision {
+    Allow,
+    Drop,        // report success but write nothing, like a lost write
+    Tear(usize), // write only the first n bytes, like a torn write
+    Fail,        // fail the write with an I/O error
+}
+
+type WriteInterceptor = Box<dyn FnMut(u64, &Page) -> WriteDecision + Send>;
+
 pub struct DiskManager {
-    db_file: File,
+    db_file: Storage,
+    group: Arc<GroupCommit>,
+    read_only: bool,
+    sync_policy: SyncPolicy,
+    stats: DiskStats,
     num_pages: u64,
+    directory: FreeSpaceDirectory,
+    free_list_root: Option<u64>, // Persisted in the metadata header
+    catalog_root: Option<u64>,   // Persisted in the metadata header
+    compaction_log: Option<u64>, // Persisted in the metadata header
+    max_pages: Option<u64>,      // Cap on the file size in pages
+    wipe_byte: u8,               // Fill byte for secure_deallocate_page
+    interceptor: Option<WriteInterceptor>, // Test seam for lost and torn writes
+    checksums: bool,             // Stamp and verify page CRCs, see set_page_checksums
 }
 
 impl DiskManager {
-    // Create a new DiskManager with the given file path.
-    pub fn new(file_path: &str) -> Self {
+    // Create a new DiskManager with the given file path. The file is locked
+    // for as long as the manager lives, so a second DiskManager on the same
+    // path, in this process or another, fails with AlreadyOpen. An existing
+    // file's metadata header is validated, see BadMetadata.
+    pub fn new(file_path: &str) -> Result<Self, DiskError> {
         let db_file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(file_path)
-            .expect("Failed to open database file");
+            .truncate(false)
+            .open(file_path)?;
+        db_file.try_lock().map_err(|err| match err {
+            TryLockError::WouldBlock => DiskError::AlreadyOpen,
+            TryLockError::Error(err) => DiskError::Io(err),
+        })?;
+        Self::from_file(db_file, false)
+    }
+
+    // Open an existing database file for reading only; writes fail with ReadOnly.
+    pub fn open_read_only(file_path: &str) -> Result<Self, DiskError> {
+        let db_file = OpenOptions::new().read(true).open(file_path)?;
+        Self::from_file(db_file, true)
+    }
+
+    // A DiskManager that keeps its pages in memory and never touches disk.
+    // Everything is lost when it is dropped, so it cannot be reopened.
+    pub fn new_in_memory() -> Self {
+        Self::with_storage(Storage::Memory(Cursor::new(Vec::new())), None, false)
+    }
+
+    fn from_file(db_file: File, read_only: bool) -> Result<Self, DiskError> {
+        let sync_file = db_file.try_clone()?;
+        let file_pages = db_file.metadata()?.len() / PAGE_SIZE as u64;
+        let mut dm = Self::with_storage(Storage::File(db_file), Some(sync_file), read_only);
+        dm.num_pages = file_pages;
+        if file_pages > METADATA_PAGE_ID {
+            dm.load_metadata()?;
+        }
+        if !read_only {
+            dm.recover_compaction()?;
+        }
+        Ok(dm)
+    }
+
+    fn load_metadata(&mut self) -> Result<(), DiskError> {
+        let mut page: Page = [0; PAGE_SIZE];
+        self.read_page(METADATA_PAGE_ID, &mut page)?;
+        if let Some(meta) = DbMetadata::decode(&page)? {
+            self.num_pages = self.num_pages.max(meta.num_pages);
+            self.free_list_root = meta.free_list_root;
+            self.catalog_root = meta.catalog_root;
+            self.compaction_log = meta.compaction_log;
+        }
+        Ok(())
+    }
+
+    pub fn metadata(&self) -> DbMetadata {
+        DbMetadata {
+            format_version: FORMAT_VERSION,
+            page_size: PAGE_SIZE as u32,
+            num_pages: self.num_pages.max(FIRST_DATA_PAGE_ID),
+            free_list_root: self.free_list_root,
+            catalog_root: self.catalog_root,
+            compaction_log: self.compaction_log,
+        }
+    }
+
+    // Write the metadata header to page 0. Allocations that grow the file and
+    // BufferPoolManager::checkpoint call this; the header only becomes durable
+    // with the next sync.
+    pub fn write_metadata(&mut self) -> Result<(), DiskError> {
+        let page = self.metadata().encode();
+        self.write_page(METADATA_PAGE_ID, &page)
+    }
+
+    #[allow(dead_code)]
+    pub fn set_free_list_root(&mut self, root: Option<u64>) {
+        self.free_list_root = root;
+    }
+
+    pub fn set_catalog_root(&mut self, root: Option<u64>) {
+        self.catalog_root = root;
+    }
+
+    fn with_storage(db_file: Storage, sync_file: Option<File>, read_only: bool) -> Self {
         DiskManager {
+            group: Arc::new(GroupCommit::new(sync_file)),
             db_file,
+            read_only,
+            sync_policy: SyncPolicy::default(),
+            stats: DiskStats::default(),
             num_pages: 0,
+            directory: FreeSpaceDirectory::default(),
+            free_list_root: None,
+            catalog_root: None,
+            compaction_log: None,
+            max_pages: None,
+            wipe_byte: 0,
+            interceptor: None,
+            checksums: false,
         }
     }
 
+    // Route every page write through `interceptor` first, which decides
+    // whether it goes through, is silently lost, is torn or fails. Meant for
+    // recovery tests that need to simulate a crash at a precise write.
+    pub fn with_write_interceptor(mut self, interceptor: WriteInterceptor) -> Self {
+        self.interceptor = Some(interceptor);
+        self
+    }
+
+    // With checksums on, every page written gets a CRC32 of its first
+    // USABLE_PAGE_SIZE bytes in its last PAGE_CHECKSUM_SIZE bytes, and every
+    // page read is checked against it. A mismatch, e.g. from a torn write,
+    // fails the read with ChecksumMismatch instead of returning the bytes.
+    // An all-zero page was never written and passes. Turn this on for a new
+    // file: pages written without it fail the check.
+    pub fn set_page_checksums(&mut self, enabled: bool) {
+        self.checksums = enabled;
+    }
+
+    #[allow(dead_code)]
+    pub fn is_in_memory(&self) -> bool {
+        matches!(self.db_file, Storage::Memory(_))
+    }
+
     // Read a page from the database file.
-    pub fn read_page(&mut self, page_id: u64, page: &mut Page) -> std::io::Result<()> {
-        let offset = page_id * PAGE_SIZE as u64;
-        self.db_file
-            .seek(SeekFrom::Start(offset))
-            .expect("Failed to seek to page");
-        self.db_file.read_exact(page).expect("Failed to read page");
+    pub fn read_page(&mut self, page_id: u64, page: &mut Page) -> Result<(), DiskError> {
+        let offset = page_offset(page_id)?;
+        self.db_file.seek(SeekFrom::Start(offset))?;
+        let mut filled = 0;
+        while filled < PAGE_SIZE {
+            match self.db_file.read(&mut page[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        match filled {
+            0 => return Err(DiskError::PageOutOfRange),
+            PAGE_SIZE => {}
+            _ => return Err(DiskError::TruncatedPage),
+        }
+        self.stats.reads += 1;
+        if self.checksums && !checksum_matches(page) {
+            return Err(DiskError::ChecksumMismatch(page_id));
+        }
         Ok(())
     }
 
     // Write a page to the database file.
-    pub fn write_page(&mut self, page_id: u64, page: &Page) -> std::io::Result<()> {
-        let offset = page_id * PAGE_SIZE as u64;
-        self.db_file
-            .seek(SeekFrom::Start(offset))
-            .expect("Failed to seek to page");
-        self.db_file.write_all(page).expect("Failed to write page");
-        self.db_file.flush()?;
+    pub fn write_page(&mut self, page_id: u64, page: &Page) -> Result<(), DiskError> {
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        let offset = page_offset(page_id)?;
+        let mut stamped: Page;
+        let page = if self.checksums {
+            stamped = *page;
+            stamp_checksum(&mut stamped);
+            &stamped
+        } else {
+            page
+        };
+        let decision = match self.interceptor.as_mut() {
+            Some(interceptor) => interceptor(page_id, page),
+            None => WriteDecision::Allow,
+        };
+        let len = match decision {
+            WriteDecision::Allow => PAGE_SIZE,
+            WriteDecision::Drop => 0,
+            WriteDecision::Tear(len) => len.min(PAGE_SIZE),
+            WriteDecision::Fail => {
+                return Err(std::io::Error::other("write failed by interceptor").into());
+            }
+        };
+        if len > 0 {
+            self.db_file.seek(SeekFrom::Start(offset))?;
+            self.db_file.write_all(&page[..len])?;
+            self.db_file.flush()?;
+        }
+        self.stats.writes += 1;
         self.num_pages = self.num_pages.max(page_id + 1);
         Ok(())
     }
 
-    pub fn allocate_page(&mut self) -> std::io::Result<u64> {
-        let new_page_id = self.num_pages as u64 + 1 as u64;
-        self.num_pages += 1;
+    // Write a batch of pages in page id order. Pages with consecutive ids are
+    // combined into one seek and one write.
+    pub fn write_pages(&mut self, pages: &[(u64, &Page)]) -> Result<(), DiskError> {
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        let mut sorted: Vec<(u64, &Page)> = pages.to_vec();
+        sorted.sort_by_key(|&(page_id, _)| page_id);
+        if self.interceptor.is_some() {
+            // The interceptor judges every page on its own.
+            for (page_id, page) in sorted {
+                self.write_page(page_id, page)?;
+            }
+            return Ok(());
+        }
+        let mut run: Vec<u8> = Vec::new();
+        let mut i = 0;
+        while i < sorted.len() {
+            let first = sorted[i].0;
+            run.clear();
+            while i < sorted.len() && sorted[i].0 == first + (run.len() / PAGE_SIZE) as u64 {
+                run.extend_from_slice(sorted[i].1);
+                if self.checksums {
+                    let start = run.len() - PAGE_SIZE;
+                    stamp_checksum(&mut run[start..]);
+                }
+                i += 1;
+            }
+            self.db_file.seek(SeekFrom::Start(page_offset(first)?))?;
+            self.db_file.write_all(&run)?;
+            let count = (run.len() / PAGE_SIZE) as u64;
+            self.stats.writes += count;
+            self.stats.write_runs += 1;
+            self.num_pages = self.num_pages.max(first + count);
+        }
+        self.db_file.flush()?;
+        Ok(())
+    }
+
+    // Read `buf.len()` bytes starting at an arbitrary byte offset in the file.
+    // The range may start and end in the middle of a page and span several pages.
+    pub fn read_range(&mut self, byte_offset: u64, buf: &mut [u8]) -> Result<(), DiskError> {
+        let mut page: Page = [0; PAGE_SIZE];
+        let mut copied = 0;
+        while copied < buf.len() {
+            let pos = byte_offset
+                .checked_add(copied as u64)
+                .ok_or(DiskError::PageOutOfRange)?;
+            let page_id = pos / PAGE_SIZE as u64;
+            let in_page = (pos % PAGE_SIZE as u64) as usize;
+            self.read_page(page_id, &mut page)?;
+            let n = (PAGE_SIZE - in_page).min(buf.len() - copied);
+            buf[copied..copied + n].copy_from_slice(&page[in_page..in_page + n]);
+            copied += n;
+        }
+        Ok(())
+    }
+
+    // Write a page and return once it is durable. Meant for many threads
+    // sharing one DiskManager: the wait happens outside the lock, so writers
+    // that arrive while an fsync is running are covered by a single next fsync.
+    pub fn write_page_grouped(
+        disk_manager: &Mutex<DiskManager>,
+        page_id: u64,
+        page: &Page,
+    ) -> Result<(), DiskError> {
+        let (group, ticket) = {
+            let mut dm = disk_manager.lock().unwrap();
+            dm.write_page(page_id, page)?;
+            if dm.sync_policy == SyncPolicy::Never {
+                return Ok(());
+            }
+            (dm.group.clone(), dm.group.ticket())
+        };
+        group.wait_durable(ticket)
+    }
+
+    pub fn stats(&self) -> DiskStats {
+        let mut stats = self.stats;
+        stats.syncs += self.group.state.lock().unwrap().syncs;
+        stats
+    }
+
+    // Force everything written so far to stable storage, unless the sync
+    // policy is Never.
+    pub fn sync(&mut self) -> Result<(), DiskError> {
+        if self.sync_policy == SyncPolicy::Never {
+            return Ok(());
+        }
+        self.force_sync()
+    }
+
+    // fsync regardless of the sync policy.
+    pub fn force_sync(&mut self) -> Result<(), DiskError> {
+        self.db_file.sync_data()?;
+        self.stats.syncs += 1;
+        Ok(())
+    }
+
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.sync_policy = policy;
+    }
+
+    // Refuse to grow the file beyond `limit` pages. Freed pages can still be reused.
+    pub fn set_max_pages(&mut self, limit: u64) {
+        self.max_pages = Some(limit);
+    }
+
+    // Give a page back so a later allocate_page can reuse it. The metadata
+    // page is never freed.
+    pub fn deallocate_page(&mut self, page_id: u64) {
+        if page_id == METADATA_PAGE_ID {
+            return;
+        }
+        self.directory.push_free(page_id);
+    }
+
+    // Like deallocate_page, but first overwrite the page with the wipe byte and
+    // make that durable, so the old contents cannot be recovered from the file.
+    // The fsync happens even under SyncPolicy::Never.
+    pub fn secure_deallocate_page(&mut self, page_id: u64) -> Result<(), DiskError> {
+        self.write_page(page_id, &[self.wipe_byte; PAGE_SIZE])?;
+        self.force_sync()?;
+        self.deallocate_page(page_id);
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn set_wipe_byte(&mut self, byte: u8) {
+        self.wipe_byte = byte;
+    }
+
+    // Every data page id in the file that is not on the free list, in order.
+    // This is the set of pages a whole-file sweep (verify, fsck, digest)
+    // should visit; the metadata page is not one of them.
+    pub fn allocated_page_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        (FIRST_DATA_PAGE_ID..self.num_pages)
+            .filter(|page_id| !self.directory.free.contains(page_id))
+    }
+
+    pub fn directory(&self) -> &FreeSpaceDirectory {
+        &self.directory
+    }
+
+    // Hand out a fresh tag for a new owner of pages in this file.
+    pub fn register_owner(&mut self) -> OwnerTag {
+        self.directory.next_owner += 1;
+        self.directory.next_owner
+    }
+
+    // Allocate a page tagged as belonging to `owner`.
+    pub fn allocate_page_for(&mut self, owner: OwnerTag) -> Result<u64, DiskError> {
+        let page_id = self.allocate_page()?;
+        self.directory.owners.insert(page_id, owner);
+        Ok(page_id)
+    }
+
+    // Move a tagged page to another owner. Fails if `from` does not own it.
+    pub fn transfer_page(&mut self, page_id: u64, from: OwnerTag, to: OwnerTag) -> bool {
+        if self.directory.owner(page_id) != Some(from) {
+            return false;
+        }
+        self.directory.owners.insert(page_id, to);
+        true
+    }
+
+    // Tag a page as belonging to `owner`, whoever had it before. Tags only
+    // live in memory, so a reopened table claims its pages again this way.
+    pub fn claim_page(&mut self, page_id: u64, owner: OwnerTag) {
+        self.directory.owners.insert(page_id, owner);
+    }
+
+    // Free a tagged page on behalf of its owner. Pages owned by someone else,
+    // or not tagged at all, are left alone.
+    pub fn release_page(&mut self, page_id: u64, owner: OwnerTag) -> bool {
+        if self.directory.owner(page_id) != Some(owner) {
+            return false;
+        }
+        self.directory.push_free(page_id);
+        true
+    }
+
+    // How many more pages allocate_page can hand out before hitting the
+    // quota, counting freed pages. None if there is no quota.
+    pub fn pages_until_quota(&self) -> Option<u64> {
+        let limit = self.max_pages?;
+        let growth = limit.saturating_sub(self.num_pages.max(FIRST_DATA_PAGE_ID));
+        Some(self.directory.free.len() as u64 + growth)
+    }
+
+    pub fn allocate_page(&mut self) -> Result<u64, DiskError> {
+        if let Some(page_id) = self.directory.free.pop() {
+            self.write_page(page_id, &[0; PAGE_SIZE])?;
+            return Ok(page_id);
+        }
+        // Page ids are dense, starting right after the metadata page.
+        let new_page_id = self.num_pages.max(FIRST_DATA_PAGE_ID);
+        if self.max_pages.is_some_and(|limit| new_page_id >= limit) {
+            return Err(DiskError::QuotaExceeded);
+        }
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
         let new_page: Page = [0; PAGE_SIZE];
-        self.write_page(new_page_id, &new_page).unwrap();
+        self.write_page(new_page_id, &new_page)?;
+        self.write_metadata()?;
         Ok(new_page_id)
     }
+
+    // Durably write the compacted `image` of `page_id` and the intent record,
+    // see COMPACTION_MAGIC. Follow up with apply_compaction.
+    pub fn log_compaction(&mut self, page_id: u64, image: &Page) -> Result<(), DiskError> {
+        let log = match self.compaction_log {
+            Some(log) => log,
+            None => {
+                let log = self.allocate_run(2)?.start;
+                self.compaction_log = Some(log);
+                self.write_metadata()?;
+                log
+            }
+        };
+        let mut intent: Page = [0; PAGE_SIZE];
+        intent[0..4].copy_from_slice(COMPACTION_MAGIC);
+        intent[4..12].copy_from_slice(&page_id.to_le_bytes());
+        self.write_page(log + 1, image)?;
+        self.sync()?;
+        self.write_page(log, &intent)?;
+        self.sync()
+    }
+
+    // Overwrite the real page with the logged image, then clear the intent.
+    pub fn apply_compaction(&mut self, page_id: u64, image: &Page) -> Result<(), DiskError> {
+        self.write_page(page_id, image)?;
+        self.sync()?;
+        if let Some(log) = self.compaction_log {
+            self.write_page(log, &[0; PAGE_SIZE])?;
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // Finish a compaction that was interrupted by a crash. Returns the page
+    // that had to be rewritten, if any. Run when the file is opened.
+    pub fn recover_compaction(&mut self) -> Result<Option<u64>, DiskError> {
+        let Some(log) = self.compaction_log else {
+            return Ok(None);
+        };
+        let mut intent: Page = [0; PAGE_SIZE];
+        self.read_page(log, &mut intent)?;
+        if &intent[0..4] != COMPACTION_MAGIC {
+            return Ok(None);
+        }
+        let page_id = u64::from_le_bytes(intent[4..12].try_into().unwrap());
+        let mut image: Page = [0; PAGE_SIZE];
+        self.read_page(log + 1, &mut image)?;
+        self.apply_compaction(page_id, &image)?;
+        Ok(Some(page_id))
+    }
+
+    // Allocate `n` pages with consecutive ids at the end of the file, e.g. for
+    // a sort run. Freed pages are not reused, since they are rarely adjacent.
+    pub fn allocate_run(&mut self, n: u64) -> Result<Range<u64>, DiskError> {
+        let first = self.num_pages.max(FIRST_DATA_PAGE_ID);
+        let end = first.checked_add(n).ok_or(DiskError::QuotaExceeded)?;
+        if self.max_pages.is_some_and(|limit| n > 0 && end > limit) {
+            return Err(DiskError::QuotaExceeded);
+        }
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        let zeroed: Page = [0; PAGE_SIZE];
+        let pages: Vec<(u64, &Page)> = (first..end).map(|page_id| (page_id, &zeroed)).collect();
+        self.write_pages(&pages)?;
+        self.write_metadata()?;
+        Ok(first..end)
+    }
+}
+
+fn stamp_checksum(page: &mut [u8]) {
+    let crc = crc32(&page[..PAGE_CHECKSUM_OFFSET]);
+    page[PAGE_CHECKSUM_OFFSET..PAGE_SIZE].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn checksum_matches(page: &Page) -> bool {
+    let stored = u32::from_le_bytes(page[PAGE_CHECKSUM_OFFSET..].try_into().unwrap());
+    stored == crc32(&page[..PAGE_CHECKSUM_OFFSET]) || page.iter().all(|&b| b == 0)
+}
+
+// The LSN stored in a page, 0 if no logged change was ever applied to it.
+pub fn page_lsn(page: &Page) -> u64 {
+    u64::from_le_bytes(
+        page[PAGE_LSN_OFFSET..PAGE_CHECKSUM_OFFSET]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+pub fn set_page_lsn(page: &mut Page, lsn: u64) {
+    page[PAGE_LSN_OFFSET..PAGE_CHECKSUM_OFFSET].copy_from_slice(&lsn.to_le_bytes());
+}
+
+// Byte offset of a page in the file; page ids too large to address are out of range.
+fn page_offset(page_id: u64) -> Result<u64, DiskError> {
+    page_id
+        .checked_mul(PAGE_SIZE as u64)
+        .ok_or(DiskError::PageOutOfRange)
+}
+
+#[test]
+fn page_quota_test() {
+    let mut dm = DiskManager::new(&temp_db_path("page_quota")).unwrap();
+    dm.set_max_pages(4);
+    let mut allocated = Vec::new();
+    while let Ok(page_id) = dm.allocate_page() {
+        allocated.push(page_id);
+    }
+    assert!(!allocated.is_empty());
+    assert!(dm.num_pages <= 4);
+    assert!(matches!(dm.allocate_page(), Err(DiskError::QuotaExceeded)));
+
+    // A freed page is reused without growing the file.
+    dm.deallocate_page(allocated[0]);
+    assert_eq!(dm.allocate_page().unwrap(), allocated[0]);
+    assert!(dm.allocate_page().is_err());
+}
+
+#[test]
+fn disk_error_test() {
+    let path = temp_db_path("disk_error");
+    assert!(matches!(
+        DiskManager::open_read_only(&path),
+        Err(DiskError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound
+    ));
+
+    let mut dm = DiskManager::new(&path).unwrap();
+    let page_id = dm.allocate_page().unwrap();
+    let mut page: Page = [0; PAGE_SIZE];
+    assert!(matches!(
+        dm.read_page(page_id + 1, &mut page),
+        Err(DiskError::PageOutOfRange)
+    ));
+
+    // Chop the last page in half.
+    let file_len = (page_id + 1) * PAGE_SIZE as u64;
+    let Storage::File(file) = &dm.db_file else {
+        unreachable!()
+    };
+    file.set_len(file_len - PAGE_SIZE as u64 / 2).unwrap();
+    assert!(matches!(
+        dm.read_page(page_id, &mut page),
+        Err(DiskError::TruncatedPage)
+    ));
+
+    let mut read_only = DiskManager::open_read_only(&path).unwrap();
+    assert!(read_only.read_page(0, &mut page).is_ok());
+    assert!(matches!(
+        read_only.write_page(0, &page),
+        Err(DiskError::ReadOnly)
+    ));
+    assert!(matches!(
+        read_only.allocate_page(),
+        Err(DiskError::ReadOnly)
+    ));
+}
+
+#[test]
+fn read_range_test() {
+    let mut dm = DiskManager::new(&temp_db_path("read_range")).unwrap();
+    let mut expected = Vec::new();
+    for page_id in 0..3u64 {
+        let mut page: Page = [0; PAGE_SIZE];
+        for (i, byte) in page.iter_mut().enumerate() {
+            *byte = ((page_id as usize * PAGE_SIZE + i) % 251) as u8;
+        }
+        dm.write_page(page_id, &page).unwrap();
+        expected.extend_from_slice(&page);
+    }
+
+    // Starts near the end of page 0 and ends early in page 2.
+    let start = PAGE_SIZE - 100;
+    let mut buf = vec![0u8; PAGE_SIZE + 300];
+    dm.read_range(start as u64, &mut buf).unwrap();
+    assert_eq!(buf, &expected[start..start + buf.len()]);
+
+    let mut tail = [0u8; 10];
+    dm.read_range(5, &mut tail).unwrap();
+    assert_eq!(tail, expected[5..15]);
+    assert!(matches!(
+        dm.read_range(3 * PAGE_SIZE as u64 - 5, &mut tail),
+        Err(DiskError::PageOutOfRange)
+    ));
+}
+
+#[test]
+fn group_commit_test() {
+    let dm = Arc::new(Mutex::new(
+        DiskManager::new(&temp_db_path("group_commit")).unwrap(),
+    ));
+    let threads = 8u64;
+    let writes_per_thread = 25u64;
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let dm = dm.clone();
+            std::thread::spawn(move || {
+                for i in 0..writes_per_thread {
+                    let page_id = t * writes_per_thread + i;
+                    let page: Page = [page_id as u8; PAGE_SIZE];
+                    DiskManager::write_page_grouped(&dm, page_id, &page).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut dm = dm.lock().unwrap();
+    let total = threads * writes_per_thread;
+    assert_eq!(dm.stats().writes, total);
+    assert!(dm.stats().syncs < total);
+    let mut page: Page = [0; PAGE_SIZE];
+    for page_id in 0..total {
+        dm.read_page(page_id, &mut page).unwrap();
+        assert_eq!(page, [page_id as u8; PAGE_SIZE]);
+    }
+}
+
+#[test]
+fn secure_deallocate_test() {
+    let path = temp_db_path("secure_deallocate");
+    let mut dm = DiskManager::new(&path).unwrap();
+    let page_id = dm.allocate_page().unwrap();
+    let secret = [0x5a; PAGE_SIZE];
+    dm.write_page(page_id, &secret).unwrap();
+    let syncs = dm.stats().syncs;
+
+    dm.set_sync_policy(SyncPolicy::Never);
+    dm.secure_deallocate_page(page_id).unwrap();
+    assert_eq!(dm.stats().syncs, syncs + 1);
+    assert_eq!(dm.directory().free_pages(), [page_id]);
+
+    let file = std::fs::read(&path).unwrap();
+    let start = page_id as usize * PAGE_SIZE;
+    assert!(file[start..start + PAGE_SIZE].iter().all(|&b| b == 0));
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn page_offset_overflow_test() {
+    let path = temp_db_path("page_offset_overflow");
+    let mut dm = DiskManager::new(&path).unwrap();
+    let page_id = u64::MAX / PAGE_SIZE as u64 + 1;
+    let mut page: Page = [0; PAGE_SIZE];
+    assert!(matches!(
+        dm.read_page(page_id, &mut page),
+        Err(DiskError::PageOutOfRange)
+    ));
+    assert!(matches!(
+        dm.write_page(page_id, &page),
+        Err(DiskError::PageOutOfRange)
+    ));
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn allocated_page_ids_test() {
+    let path = temp_db_path("allocated_page_ids");
+    let mut dm = DiskManager::new(&path).unwrap();
+    let pages: Vec<u64> = (0..5).map(|_| dm.allocate_page().unwrap()).collect();
+    dm.deallocate_page(pages[2]);
+
+    assert_eq!(pages, [1, 2, 3, 4, 5]);
+    let live: Vec<u64> = dm.allocated_page_ids().collect();
+    assert_eq!(live, [1, 2, 4, 5]);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn already_open_test() {
+    let path = temp_db_path("already_open");
+    let dm = DiskManager::new(&path).unwrap();
+    assert!(matches!(
+        DiskManager::new(&path),
+        Err(DiskError::AlreadyOpen)
+    ));
+    drop(dm);
+    assert!(DiskManager::new(&path).is_ok());
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn allocate_run_test() {
+    let path = temp_db_path("allocate_run");
+    let mut dm = DiskManager::new(&path).unwrap();
+    let single = dm.allocate_page().unwrap();
+    let run = dm.allocate_run(4).unwrap();
+    assert_eq!(run.end - run.start, 4);
+    assert!(run.start > single);
+    assert_eq!(dm.stats().write_runs, 1);
+    assert!(dm.allocate_page().unwrap() >= run.end);
+
+    dm.set_max_pages(run.end + 4);
+    assert!(matches!(dm.allocate_run(8), Err(DiskError::QuotaExceeded)));
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn write_interceptor_test() {
+    let path = temp_db_path("write_interceptor");
+    let mut dm = DiskManager::new(&path)
+        .unwrap()
+        .with_write_interceptor(Box::new(|page_id, _| match page_id {
+            2 => WriteDecision::Tear(PAGE_SIZE / 2),
+            3 => WriteDecision::Fail,
+            _ => WriteDecision::Allow,
+        }));
+    dm.write_page(4, &[0; PAGE_SIZE]).unwrap();
+    dm.write_page(2, &[9; PAGE_SIZE]).unwrap();
+    assert!(matches!(
+        dm.write_page(3, &[9; PAGE_SIZE]),
+        Err(DiskError::Io(_))
+    ));
+
+    let mut page: Page = [0; PAGE_SIZE];
+    dm.read_page(2, &mut page).unwrap();
+    assert!(page[..PAGE_SIZE / 2].iter().all(|&b| b == 9));
+    assert!(page[PAGE_SIZE / 2..].iter().all(|&b| b == 0));
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn metadata_test() {
+    use crate::buffer_manager::BufferPoolManager;
+
+    let path = temp_db_path("metadata");
+    let mut dm = DiskManager::new(&path).unwrap();
+    let pages: Vec<u64> = (0..3).map(|_| dm.allocate_page().unwrap()).collect();
+    assert!(!pages.contains(&METADATA_PAGE_ID));
+    dm.set_catalog_root(Some(pages[0]));
+    let expected = DbMetadata {
+        format_version: FORMAT_VERSION,
+        page_size: PAGE_SIZE as u32,
+        num_pages: dm.num_pages,
+        free_list_root: None,
+        catalog_root: Some(pages[0]),
+        compaction_log: None,
+    };
+    let bpm = BufferPoolManager::new(2, dm);
+    bpm.checkpoint().unwrap();
+    drop(bpm);
+
+    let reopened = DiskManager::new(&path).unwrap();
+    assert_eq!(reopened.metadata(), expected);
+    drop(reopened);
+    assert_eq!(
+        DiskManager::open_read_only(&path).unwrap().metadata(),
+        expected
+    );
+
+    // Flip one bit of num_pages: the CRC no longer matches.
+    let mut file = std::fs::read(&path).unwrap();
+    file[16] ^= 1;
+    std::fs::write(&path, &file).unwrap();
+    assert!(matches!(
+        DiskManager::new(&path),
+        Err(DiskError::BadMetadata)
+    ));
+    assert!(matches!(
+        DiskManager::open_read_only(&path),
+        Err(DiskError::BadMetadata)
+    ));
+
+    // A data page where the header should be is rejected as well.
+    file[..PAGE_SIZE].fill(7);
+    std::fs::write(&path, &file).unwrap();
+    assert!(matches!(
+        DiskManager::new(&path),
+        Err(DiskError::BadMetadata)
+    ));
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn reopen_num_pages_test() {
+    let path = temp_db_path("reopen_num_pages");
+    let mut dm = DiskManager::new(&path).unwrap();
+    let pages: Vec<u64> = (0..3).map(|_| dm.allocate_page().unwrap()).collect();
+    assert_eq!(pages, [1, 2, 3]);
+    for &page_id in &pages {
+        dm.write_page(page_id, &[page_id as u8; PAGE_SIZE]).unwrap();
+    }
+    drop(dm);
+
+    let mut dm = DiskManager::new(&path).unwrap();
+    assert_eq!(dm.metadata().num_pages, 4);
+    assert_eq!(dm.allocate_page().unwrap(), 4);
+    let mut page: Page = [0; PAGE_SIZE];
+    for &page_id in &pages {
+        dm.read_page(page_id, &mut page).unwrap();
+        assert_eq!(page, [page_id as u8; PAGE_SIZE]);
+    }
+    drop(dm);
+
+    // Pages written without an allocation are covered by the file length.
+    let mut dm = DiskManager::new(&path).unwrap();
+    dm.write_page(6, &[6; PAGE_SIZE]).unwrap();
+    drop(dm);
+    let mut dm = DiskManager::new(&path).unwrap();
+    assert_eq!(dm.allocate_page().unwrap(), 7);
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn page_checksum_test() {
+    use crate::slotted_page::SlottedPage;
+
+    let path = temp_db_path("page_checksum");
+    let mut dm = DiskManager::new(&path).unwrap();
+    dm.set_page_checksums(true);
+    let fresh = dm.allocate_page().unwrap();
+    let page_id = dm.allocate_page().unwrap();
+    let mut page: Page = [0; PAGE_SIZE];
+    SlottedPage::init(&mut page);
+    dm.write_page(fresh, &page).unwrap();
+    SlottedPage::init(&mut page)
+        .insert(b"flaky storage")
+        .unwrap();
+    dm.write_page(page_id, &page).unwrap();
+
+    let mut read: Page = [0; PAGE_SIZE];
+    dm.read_page(fresh, &mut read).unwrap();
+    assert!(SlottedPage::from_buffer(&mut read).is_ok());
+    dm.read_page(page_id, &mut read).unwrap();
+    assert_eq!(read[..USABLE_PAGE_SIZE], page[..USABLE_PAGE_SIZE]);
+
+    // Flip one byte of the tuple on disk.
+    let mut file = std::fs::read(&path).unwrap();
+    let pos = page_id as usize * PAGE_SIZE + 10;
+    file[pos] ^= 0x01;
+    std::fs::write(&path, &file).unwrap();
+    let err = dm.read_page(page_id, &mut read).unwrap_err();
+    assert!(matches!(err, DiskError::ChecksumMismatch(id) if id == page_id));
+    let err: std::io::Error = err.into();
+    assert!(err.to_string().contains("checksum"));
+
+    // Batched writes are stamped too.
+    dm.write_pages(&[(page_id, &page)]).unwrap();
+    dm.read_page(page_id, &mut read).unwrap();
+    let _ = std::fs::remove_file(path);
+}
+
+// Path for a scratch database file used by tests. Any leftover file from a
+// previous run is removed so every test starts from an empty database.
+#[cfg(test)]
+pub fn temp_db_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("duckling_{}_{}.db", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path.to_str().unwrap().to_string()
 }
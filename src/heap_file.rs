@@ -1,76 +1,344 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::buffer_manager::BufferPoolManager;
-use crate::slotted_page::{SlotId, SlottedPage};
+use crate::buffer_manager::{BufferError, BufferPoolManager, Frame};
+use crate::disk_manager::{DiskError, DiskManager, OwnerTag, Page, SyncPolicy, PAGE_SIZE};
+use crate::slotted_page::{
+    PageError, SlotId, SlottedPage, FREE_SPACE_BUCKET_BYTES, MAX_TUPLE_SIZE, SLOT_ENTRY_SIZE,
+};
+use crate::tuple_cache::TupleCache;
 
 pub type PageId = u64;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TupleId {
     pub page_id: PageId,
     pub slot_id: SlotId,
 }
 
+// How tuples are laid out on the page. Expiring and versioned heaps store a
+// small header in front of every tuple; callers only ever see the tuple bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RecordFormat {
+    Plain,
+    Expiring, // expiry time in seconds since the epoch (u64, little endian)
+    // TupleId of the previous version: page id (u64) + slot id (u16), then the
+    // version's lifetime [begin_ts, end_ts) in seconds since the epoch (2 x u64)
+    Versioned,
+}
+
+impl RecordFormat {
+    fn prefix_size(self) -> usize {
+        match self {
+            RecordFormat::Plain => 0,
+            RecordFormat::Expiring => EXPIRY_PREFIX_SIZE,
+            RecordFormat::Versioned => VERSION_PREFIX_SIZE,
+        }
+    }
+}
+
+const EXPIRY_PREFIX_SIZE: usize = 8;
+const NEVER_EXPIRES: u64 = u64::MAX;
+const VERSION_PREFIX_SIZE: usize = 26;
+const NO_PREV_VERSION: PageId = PageId::MAX;
+const END_TS_OFFSET: usize = 18;
+const STILL_CURRENT: u64 = u64::MAX;
+
+fn now_epoch_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// The free-space map keeps one byte per page: its contiguous free space in
+// units of FSM_BUCKET_BYTES, rounded down. Inserts use it to skip pages that
+// cannot fit the tuple without fetching them.
+// Pages cache their own bucket in the header, see SlottedPage::free_space_bucket.
+const FSM_BUCKET_BYTES: usize = FREE_SPACE_BUCKET_BYTES;
+
+fn fsm_bucket(free_bytes: usize) -> u8 {
+    SlottedPage::bucket_for(free_bytes)
+}
+
+// Result of HeapFile::rewrite_to.
+#[derive(Debug, Default)]
+pub struct RewriteReport {
+    pub remap: HashMap<TupleId, TupleId>, // old TupleId -> TupleId in the copy
+    pub pages: Vec<PageId>,               // pages of the copy on the destination
+    pub bytes_saved: u64,                 // disk_size_bytes of the source minus the copy's
+}
+
+// Why insert_tuple_unique did not insert a tuple.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UniqueInsertError {
+    Duplicate { existing: TupleId }, // the key is taken by the live tuple `existing`
+    NoSpace,                         // the tuple fits on no page, as when insert_tuple returns None
+}
+
+// Why update_batch failed. Either way the update at index `failed_at` could
+// not be applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchError {
+    // Every earlier update of the batch was undone.
+    Failed { failed_at: usize },
+    // Undoing the earlier updates failed too: the before-image of `tid`
+    // could not be put back, so the batch is partly applied.
+    RollbackFailed { failed_at: usize, tid: TupleId },
+}
+
 pub struct HeapFile {
-    buffer_pool_manager: Arc<Mutex<BufferPoolManager>>,
+    buffer_pool_manager: BufferPoolManager,
     pages: Vec<PageId>,
+    fsm: Vec<u8>, // Free-space map, parallel to pages
+    format: RecordFormat,
+    cache: Option<TupleCache>, // Recently read records, see enable_tuple_cache
+    owner: OwnerTag,           // Tag of this heap's pages in the disk manager's directory
+    bulk_import: Option<SyncPolicy>, // Policy to restore when the bulk import finishes
+    working_page: Option<PageId>, // Page kept pinned by insert_tuple_blocking
+    unique_keys: Option<HashSet<u64>>, // Key hashes for insert_tuple_unique, built on first use
+    free_empty_pages: bool,    // Whether delete_tuple frees pages it empties
+    freed_idxs: Vec<usize>,    // Directory indexes removed by free_page, oldest first
 }
 
 impl HeapFile {
-    pub fn new(buffer_pool_manager: Arc<Mutex<BufferPoolManager>>) -> Self {
+    pub fn new(buffer_pool_manager: BufferPoolManager) -> Self {
+        Self::with_format(buffer_pool_manager, RecordFormat::Plain)
+    }
+
+    // Create a heap file whose tuples can expire, see insert_with_ttl.
+    pub fn new_expiring(buffer_pool_manager: BufferPoolManager) -> Self {
+        Self::with_format(buffer_pool_manager, RecordFormat::Expiring)
+    }
+
+    // Create a heap file that keeps old tuple versions on update, see
+    // read_version_chain.
+    pub fn new_versioned(buffer_pool_manager: BufferPoolManager) -> Self {
+        Self::with_format(buffer_pool_manager, RecordFormat::Versioned)
+    }
+
+    // Reopen a plain heap file over pages it used before, see pages.
+    pub fn open(buffer_pool_manager: BufferPoolManager, pages: Vec<PageId>) -> Self {
+        let mut heap = Self::new(buffer_pool_manager);
+        {
+            let mut dm = heap.buffer_pool_manager.disk_manager.lock().unwrap();
+            for &page_id in &pages {
+                dm.claim_page(page_id, heap.owner);
+            }
+        }
+        heap.fsm = vec![0; pages.len()];
+        heap.pages = pages;
+        heap.rebuild_fsm();
+        heap
+    }
+
+    pub fn pages(&self) -> &[PageId] {
+        &self.pages
+    }
+
+    fn with_format(buffer_pool_manager: BufferPoolManager, format: RecordFormat) -> Self {
+        let owner = {
+            let mut dm = buffer_pool_manager.disk_manager.lock().unwrap();
+            dm.register_owner()
+        };
         Self {
             buffer_pool_manager,
             pages: Vec::new(),
+            fsm: Vec::new(),
+            format,
+            cache: None,
+            owner,
+            bulk_import: None,
+            working_page: None,
+            unique_keys: None,
+            free_empty_pages: true,
+            freed_idxs: Vec::new(),
+        }
+    }
+
+    // Stop syncing to disk until finish_bulk_import, for fast initial loads.
+    // A crash before the finish may lose everything written in between.
+    pub fn begin_bulk_import(&mut self) {
+        let mut dm = self.buffer_pool_manager.disk_manager.lock().unwrap();
+        if self.bulk_import.is_none() {
+            self.bulk_import = Some(dm.sync_policy());
+        }
+        dm.set_sync_policy(SyncPolicy::Never);
+    }
+
+    // Write out all dirty pages and make them durable with a single fsync,
+    // then restore the sync policy from before begin_bulk_import.
+    pub fn finish_bulk_import(&mut self) -> io::Result<()> {
+        let bpm = &self.buffer_pool_manager;
+        bpm.flush_all_pages()?;
+        let mut dm = bpm.disk_manager.lock().unwrap();
+        dm.force_sync()?;
+        if let Some(policy) = self.bulk_import.take() {
+            dm.set_sync_policy(policy);
+        }
+        Ok(())
+    }
+
+    // Keep up to `capacity` recently read tuples in memory so repeated point
+    // reads of hot tuples skip the buffer pool entirely.
+    pub fn enable_tuple_cache(&mut self, capacity: usize) {
+        self.cache = Some(TupleCache::new(capacity));
+    }
+
+    fn invalidate_cached(&mut self, tid: TupleId) {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.invalidate(tid);
         }
     }
 
     pub fn insert_tuple(&mut self, data: &[u8]) -> Option<TupleId> {
-        // For each page in the heap file, try to insert the tuple
-        // let mut bpm: std::sync::MutexGuard<'_, BufferPoolManager> = self.buffer_pool_manager.lock().unwrap();
+        match self.format {
+            RecordFormat::Plain => self.insert_record(data),
+            RecordFormat::Expiring => self.insert_with_ttl(data, NEVER_EXPIRES),
+            RecordFormat::Versioned => self.insert_tuple_at(data, now_epoch_secs()),
+        }
+    }
 
-        for &page_id in self.pages.iter() {
-            let frame = {
-                let mut bpm = self.buffer_pool_manager.lock().unwrap();
-                bpm.fetch_page(page_id)?
-            };
-            let slot_id_opt = {
-                let mut frame_lock: std::sync::MutexGuard<'_, crate::buffer_manager::Frame> =
-                    frame.lock().unwrap();
-                let mut sp: SlottedPage = SlottedPage::from_buffer(&mut frame_lock.data);
-                let slot_id = sp.insert(data);
-                if slot_id.is_some() {
-                    frame_lock.is_dirty = true;
+    // Insert for long loading loops. The page the last tuple went to stays
+    // pinned between calls as the working page, so it cannot be evicted. When
+    // no frame is left for the next page, the working page is flushed and
+    // unpinned before retrying, so a loader never blocks on its own pin.
+    // Fails with PoolExhausted only if other pins hold every frame. Ok(None)
+    // means the same as None from insert_tuple.
+    pub fn insert_tuple_blocking(&mut self, data: &[u8]) -> Result<Option<TupleId>, BufferError> {
+        let mut tid = self.insert_tuple(data);
+        if tid.is_none() && self.working_page.is_some() && self.pool_exhausted() {
+            self.release_working_page()?;
+            tid = self.insert_tuple(data);
+        }
+        match tid {
+            Some(tid) => {
+                if self.working_page != Some(tid.page_id) {
+                    self.release_working_page()?;
+                    if self.buffer_pool_manager.fetch_page(tid.page_id).is_some() {
+                        self.working_page = Some(tid.page_id);
+                    }
                 }
-                slot_id
-            };
-            {
-                let mut bpm = self.buffer_pool_manager.lock().unwrap();
-                let _ = bpm.unpin_page(page_id, slot_id_opt.is_some());
+                Ok(Some(tid))
+            }
+            None if self.pool_exhausted() => Err(BufferError::PoolExhausted),
+            None => Ok(None),
+        }
+    }
+
+    // Insert a tuple unless a live tuple with the same key already exists.
+    // The key hashes are kept in memory and rebuilt from a scan on first use,
+    // so `key_fn` must be the same on every call. Any other write to the heap
+    // drops them, to be rebuilt on the next call. Hashes of deleted tuples
+    // linger, which is why a matching hash is confirmed by comparing the
+    // actual keys.
+    pub fn insert_tuple_unique<K: Hash + Eq>(
+        &mut self,
+        data: &[u8],
+        key_fn: impl Fn(&[u8]) -> K,
+    ) -> Result<TupleId, UniqueInsertError> {
+        let key_hash = |key: &K| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish()
+        };
+        if self.unique_keys.is_none() {
+            let hashes = self
+                .scan()
+                .map(|(_, tuple)| key_hash(&key_fn(&tuple)))
+                .collect();
+            self.unique_keys = Some(hashes);
+        }
+        let key = key_fn(data);
+        let hash = key_hash(&key);
+        if self.unique_keys.as_ref().unwrap().contains(&hash) {
+            if let Some((existing, _)) = self.scan().find(|(_, tuple)| key_fn(tuple) == key) {
+                return Err(UniqueInsertError::Duplicate { existing });
+            }
+        }
+        // insert_tuple drops the hashes like any other write; this one keeps them.
+        let mut hashes = self.unique_keys.take().unwrap();
+        let tid = self.insert_tuple(data).ok_or(UniqueInsertError::NoSpace)?;
+        hashes.insert(hash);
+        self.unique_keys = Some(hashes);
+        Ok(tid)
+    }
+
+    // Flush and unpin the page insert_tuple_blocking keeps pinned, if any.
+    pub fn release_working_page(&mut self) -> Result<(), BufferError> {
+        if let Some(page_id) = self.working_page.take() {
+            let bpm = &self.buffer_pool_manager;
+            bpm.flush_page(page_id)?;
+            bpm.unpin_page(page_id, false);
+        }
+        Ok(())
+    }
+
+    fn pool_exhausted(&self) -> bool {
+        self.buffer_pool_manager.available_frames() == 0
+    }
+
+    // Insert a tuple that reads as absent once `expire_at_epoch_secs` is reached.
+    // Only available on heap files created with new_expiring.
+    pub fn insert_with_ttl(&mut self, data: &[u8], expire_at_epoch_secs: u64) -> Option<TupleId> {
+        if self.format != RecordFormat::Expiring {
+            return None;
+        }
+        let mut record = Vec::with_capacity(EXPIRY_PREFIX_SIZE + data.len());
+        record.extend_from_slice(&expire_at_epoch_secs.to_le_bytes());
+        record.extend_from_slice(data);
+        self.insert_record(&record)
+    }
+
+    // Insert the raw record bytes into the first page that has room.
+    fn insert_record(&mut self, data: &[u8]) -> Option<TupleId> {
+        self.unique_keys = None;
+        let need_space = data.len() + SLOT_ENTRY_SIZE;
+        // For each page the free-space map says can fit the tuple, try to insert it
+        for idx in 0..self.pages.len() {
+            if (self.fsm[idx] as usize) * FSM_BUCKET_BYTES < need_space {
+                continue;
             }
-            if let Some(slot_id) = slot_id_opt {
-                return Some(TupleId { page_id, slot_id });
+            if let Some(tid) = self.insert_record_at(idx, data) {
+                return Some(tid);
             }
         }
-        // If we're here, no existing page could accommodate the tuple
+        // If we're here, no existing page could accommodate the tuple. Don't
+        // allocate a page that could not hold it either.
+        if data.len() > MAX_TUPLE_SIZE {
+            return None;
+        }
         let (new_page_id, frame) = {
-            let mut bpm = self.buffer_pool_manager.lock().unwrap();
+            let bpm = &self.buffer_pool_manager;
             // Ideally have bpm.new_page(); using allocate + fetch for now:
-            let pid = bpm.disk_manager.lock().unwrap().allocate_page().ok()?;
-            let f = bpm.fetch_page(pid)?;
+            let pid = bpm
+                .disk_manager
+                .lock()
+                .unwrap()
+                .allocate_page_for(self.owner)
+                .ok()?;
+            let Some(f) = bpm.fetch_page(pid) else {
+                // No frame for it; give the page back instead of leaking it.
+                bpm.disk_manager.lock().unwrap().deallocate_page(pid);
+                return None;
+            };
             (pid, f)
         };
-        let slot_id = {
+        let (slot_id, free) = {
             let mut frame_lock = frame.lock().unwrap();
             let mut sp = SlottedPage::init(&mut frame_lock.data); // <-- init for fresh page
-            let sid = sp.insert(data)?; // must succeed on empty page
+            let sid = sp.insert(data).ok()?; // must succeed on empty page
+            let free = sp.largest_contiguous_free();
             frame_lock.is_dirty = true;
-            sid
+            (sid, free)
         };
-        {
-            let mut bpm = self.buffer_pool_manager.lock().unwrap();
-            let _ = bpm.unpin_page(new_page_id, true);
-        }
+        let _ = self.buffer_pool_manager.unpin_page(new_page_id, true);
         self.pages.push(new_page_id);
+        self.fsm.push(fsm_bucket(free));
 
         Some(TupleId {
             page_id: new_page_id,
@@ -78,22 +346,2616 @@ impl HeapFile {
         })
     }
 
-    // Read a tuple given its TupleId
-    pub fn read_tuple(&mut self, tid: TupleId) -> Option<Vec<u8>> {
-        let frame = {
-            let mut bpm = self.buffer_pool_manager.lock().unwrap();
-            bpm.fetch_page(tid.page_id)?
-        };
-        let data_opt: Option<Vec<u8>> = {
+    // Insert the raw record bytes into the page at `idx` in the directory, if
+    // it has room.
+    fn insert_record_at(&mut self, idx: usize, data: &[u8]) -> Option<TupleId> {
+        let page_id = self.pages[idx];
+        let frame = self.buffer_pool_manager.fetch_page(page_id)?;
+        let slot_id_opt = {
             let mut frame_lock: std::sync::MutexGuard<'_, crate::buffer_manager::Frame> =
                 frame.lock().unwrap();
-            let sp = SlottedPage::from_buffer(&mut frame_lock.data);
-            sp.read(tid.slot_id).map(|data| data.to_vec())
+            let mut sp: SlottedPage = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+            let slot_id = sp.insert(data).ok();
+            self.fsm[idx] = fsm_bucket(sp.largest_contiguous_free());
+            if slot_id.is_some() {
+                frame_lock.is_dirty = true;
+            }
+            slot_id
+        };
+        let _ = self
+            .buffer_pool_manager
+            .unpin_page(page_id, slot_id_opt.is_some());
+        Some(TupleId {
+            page_id,
+            slot_id: slot_id_opt?,
+        })
+    }
+
+    // Recompute the free-space map from the pages themselves, e.g. after a
+    // crash left it out of date. Returns how many entries were wrong.
+    pub fn rebuild_fsm(&mut self) -> usize {
+        let mut corrections = 0;
+        for idx in 0..self.pages.len() {
+            let page_id = self.pages[idx];
+            let frame = {
+                match self.buffer_pool_manager.fetch_page(page_id) {
+                    Some(frame) => frame,
+                    None => continue,
+                }
+            };
+            let bucket = {
+                let mut frame_lock = frame.lock().unwrap();
+                SlottedPage::from_buffer_unchecked(&mut frame_lock.data).free_space_bucket()
+            };
+            let _ = self.buffer_pool_manager.unpin_page(page_id, false);
+            if self.fsm[idx] != bucket {
+                self.fsm[idx] = bucket;
+                corrections += 1;
+            }
+        }
+        corrections
+    }
+
+    // Check every page of the heap: slotted page invariants, record prefixes,
+    // and that version links stay inside the heap. Returns all problems found.
+    pub fn verify(&mut self) -> Vec<(PageId, PageError)> {
+        let mut problems = Vec::new();
+        for &page_id in self.pages.iter() {
+            let frame = self.buffer_pool_manager.fetch_page(page_id);
+            let Some(frame) = frame else {
+                problems.push((page_id, PageError::Unreadable));
+                continue;
+            };
+            {
+                let mut frame_lock = frame.lock().unwrap();
+                let sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+                if let Err(err) = sp.check_invariants() {
+                    problems.push((page_id, err));
+                } else {
+                    for (slot_id, record) in sp.iter() {
+                        if record.len() < self.format.prefix_size() {
+                            problems.push((page_id, PageError::ShortRecord(slot_id)));
+                        } else if self.format == RecordFormat::Versioned {
+                            if let Some(prev) = prev_version(record) {
+                                if !self.pages.contains(&prev.page_id) {
+                                    problems.push((page_id, PageError::DanglingLink(slot_id)));
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            let _ = self.buffer_pool_manager.unpin_page(page_id, false);
+        }
+        problems
+    }
+
+    // Replace a tuple's bytes. Versioned heaps keep the old version and insert
+    // the new one with a link back to it, returning the new TupleId. Other
+    // heaps update the tuple on its page and return the same TupleId. If the
+    // page has no room for the new bytes even after compaction, the tuple
+    // moves to another page and its new TupleId is returned; the old one no
+    // longer reads. None if the tuple does not exist or fits nowhere.
+    pub fn update_tuple(&mut self, tid: TupleId, data: &[u8]) -> Option<TupleId> {
+        let old = self.read_record(tid)?;
+        let record = match self.format {
+            RecordFormat::Plain => data.to_vec(),
+            RecordFormat::Expiring => [&old[..EXPIRY_PREFIX_SIZE], data].concat(),
+            RecordFormat::Versioned => return self.update_tuple_at(tid, data, now_epoch_secs()),
+        };
+        if self.update_record(tid, &record) {
+            return Some(tid);
+        }
+        // Insert before deleting, so a tuple that fits nowhere is not lost.
+        let new_tid = self.insert_record(&record)?;
+        self.delete_tuple(tid);
+        Some(new_tid)
+    }
+
+    // Apply several updates as one: if any of them fails, the ones already
+    // applied are undone from before-images of their records, newest first,
+    // and the heap is left as it was. Returns the TupleId each update ended
+    // up at, as update_tuple would.
+    pub fn update_batch(
+        &mut self,
+        updates: &[(TupleId, &[u8])],
+    ) -> Result<Vec<TupleId>, BatchError> {
+        // Undoing a move puts the tuple back on its old page, so pages that
+        // moves empty are only freed once the whole batch went through.
+        let free_empty_pages = std::mem::replace(&mut self.free_empty_pages, false);
+        let result = self.apply_batch(updates);
+        self.free_empty_pages = free_empty_pages;
+        let moved = result?;
+        if free_empty_pages {
+            for &(tid, new_tid) in &moved {
+                if new_tid.page_id != tid.page_id {
+                    self.free_page(tid.page_id);
+                }
+            }
+        }
+        Ok(moved.into_iter().map(|(_, new_tid)| new_tid).collect())
+    }
+
+    // update_batch without freeing pages. Returns the old and new TupleId of
+    // every update.
+    fn apply_batch(
+        &mut self,
+        updates: &[(TupleId, &[u8])],
+    ) -> Result<Vec<(TupleId, TupleId)>, BatchError> {
+        let mut applied: Vec<(TupleId, TupleId, Vec<u8>)> = Vec::new();
+        for (idx, &(tid, data)) in updates.iter().enumerate() {
+            let new_tid = self
+                .read_record(tid)
+                .and_then(|before| Some((self.update_tuple(tid, data)?, before)));
+            match new_tid {
+                Some((new_tid, before)) => applied.push((tid, new_tid, before)),
+                None => {
+                    let mut error = BatchError::Failed { failed_at: idx };
+                    for (tid, new_tid, before) in applied.into_iter().rev() {
+                        // A moved tuple keeps its new copy if the old one
+                        // cannot be restored.
+                        let restored = self.restore_record(tid, &before);
+                        if restored.is_ok() && new_tid != tid {
+                            self.delete_tuple(new_tid);
+                        }
+                        if let (Err(tid), BatchError::Failed { .. }) = (restored, error) {
+                            error = BatchError::RollbackFailed {
+                                failed_at: idx,
+                                tid,
+                            };
+                        }
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        Ok(applied
+            .into_iter()
+            .map(|(tid, new_tid, _)| (tid, new_tid))
+            .collect())
+    }
+
+    // Put a before-image back. Updates only ever take space away from a page,
+    // so compact it if the image does not fit in what is left. An update that
+    // moved the tuple deleted its slot, so the image goes back into that slot.
+    // Fails with `tid` if the page cannot be fetched or has no room after all.
+    fn restore_record(&mut self, tid: TupleId, record: &[u8]) -> Result<(), TupleId> {
+        if self.update_record(tid, record) {
+            return Ok(());
+        }
+        let mut restored = false;
+        if let Some(frame) = self.buffer_pool_manager.fetch_page(tid.page_id) {
+            let mut frame_lock = frame.lock().unwrap();
+            let mut sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+            sp.compact();
+            restored = sp.update(tid.slot_id, record) || sp.insert_at(tid.slot_id, record);
+            let free = sp.largest_contiguous_free();
+            frame_lock.is_dirty = true;
+            drop(frame_lock);
+            if let Some(idx) = self.pages.iter().position(|&p| p == tid.page_id) {
+                self.fsm[idx] = fsm_bucket(free);
+            }
+            let _ = self.buffer_pool_manager.unpin_page(tid.page_id, true);
+        }
+        if restored {
+            Ok(())
+        } else {
+            Err(tid)
+        }
+    }
+
+    // Insert the first version of a tuple into a versioned heap, visible from
+    // `begin_ts` on. Only available on heap files created with new_versioned.
+    pub fn insert_tuple_at(&mut self, data: &[u8], begin_ts: u64) -> Option<TupleId> {
+        if self.format != RecordFormat::Versioned {
+            return None;
+        }
+        self.insert_record(&versioned_record(None, begin_ts, data))
+    }
+
+    // Add a new version of a tuple in a versioned heap: the version at `tid`
+    // stops being visible at `ts` and the new one starts there. Returns the
+    // TupleId of the new version.
+    pub fn update_tuple_at(&mut self, tid: TupleId, data: &[u8], ts: u64) -> Option<TupleId> {
+        if self.format != RecordFormat::Versioned {
+            return None;
+        }
+        let mut old = self.read_record(tid)?;
+        let new_tid = self.insert_record(&versioned_record(Some(tid), ts, data))?;
+        old[END_TS_OFFSET..VERSION_PREFIX_SIZE].copy_from_slice(&ts.to_le_bytes());
+        self.update_record(tid, &old);
+        Some(new_tid)
+    }
+
+    // Remove a tuple. Returns false if it did not exist. If that was the
+    // last tuple on its page, the page is freed right away, see
+    // set_free_empty_pages.
+    pub fn delete_tuple(&mut self, tid: TupleId) -> bool {
+        self.invalidate_cached(tid);
+        let Some(frame) = self.buffer_pool_manager.fetch_page(tid.page_id) else {
+            return false;
+        };
+        let (deleted, emptied) = {
+            let mut frame_lock = frame.lock().unwrap();
+            let mut sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+            let deleted = sp.delete(tid.slot_id);
+            let emptied = deleted && sp.iter().next().is_none();
+            if deleted {
+                frame_lock.is_dirty = true;
+            }
+            (deleted, emptied)
         };
+        let _ = self.buffer_pool_manager.unpin_page(tid.page_id, deleted);
+        if emptied && self.free_empty_pages {
+            self.free_page(tid.page_id);
+        }
+        deleted
+    }
+
+    // Whether delete_tuple gives a page back as soon as its last tuple is
+    // deleted (the default). Turn it off when the page will be refilled
+    // soon, so it keeps its place in the directory.
+    pub fn set_free_empty_pages(&mut self, enabled: bool) {
+        self.free_empty_pages = enabled;
+    }
+
+    // Move a tuple onto `target_page`, which must belong to this heap, and
+    // tombstone the original. Returns the new TupleId, or None if the tuple
+    // does not exist or the target has no room for it. Version links that
+    // point at the old TupleId are not rewritten.
+    pub fn relocate_tuple(&mut self, tid: TupleId, target_page: PageId) -> Option<TupleId> {
+        let idx = self.pages.iter().position(|&p| p == target_page)?;
+        let record = self.read_record(tid)?;
+        let new_tid = self.insert_record_at(idx, &record)?;
+        self.delete_tuple(tid);
+        Some(new_tid)
+    }
+
+    // Follow the links of a versioned tuple from `tid` back to its first
+    // version, returning every version newest first. Deleted versions free
+    // their slot for reuse, so a link is only followed to a version that
+    // ended exactly when the newer one began; anything else is an unrelated
+    // tuple and ends the chain.
+    pub fn read_version_chain(&mut self, tid: TupleId) -> Vec<(TupleId, Vec<u8>)> {
+        let mut chain = Vec::new();
+        if self.format != RecordFormat::Versioned {
+            return chain;
+        }
+        let mut seen = HashSet::new();
+        let mut next = Some((tid, None));
+        while let Some((tid, newer_begin)) = next {
+            if !seen.insert(tid) {
+                break;
+            }
+            let Some(record) = self.read_record(tid) else {
+                break;
+            };
+            let (begin_ts, end_ts) = version_lifetime(&record);
+            if newer_begin.is_some_and(|begin| begin != end_ts) {
+                break;
+            }
+            next = prev_version(&record).map(|prev| (prev, Some(begin_ts)));
+            chain.push((tid, record[VERSION_PREFIX_SIZE..].to_vec()));
+        }
+        chain
+    }
+
+    // Take over all pages of `other`, e.g. to merge two tables. Tuples are not
+    // moved, so their TupleIds stay valid. Both heaps must live in the same
+    // buffer pool, use the same record format and own disjoint pages, and
+    // `other` must own every page it lists; otherwise `other` is handed back
+    // unchanged.
+    #[allow(clippy::result_large_err)]
+    pub fn absorb(&mut self, other: HeapFile) -> Result<(), HeapFile> {
+        if !self
+            .buffer_pool_manager
+            .same_pool(&other.buffer_pool_manager)
+            || self.format != other.format
+            || other
+                .pages
+                .iter()
+                .any(|page_id| self.pages.contains(page_id))
         {
-            let mut bpm = self.buffer_pool_manager.lock().unwrap();
-            let _ = bpm.unpin_page(tid.page_id, false);
+            return Err(other);
         }
-        data_opt
+        {
+            let mut dm = self.buffer_pool_manager.disk_manager.lock().unwrap();
+            if other
+                .pages
+                .iter()
+                .any(|&page_id| dm.directory().owner(page_id) != Some(other.owner))
+            {
+                return Err(other);
+            }
+            for &page_id in other.pages.iter() {
+                dm.transfer_page(page_id, other.owner, self.owner);
+            }
+        }
+        self.pages.extend_from_slice(&other.pages);
+        self.fsm.extend_from_slice(&other.fsm);
+        self.unique_keys = None;
+        Ok(())
+    }
+
+    // Write a defragmented copy of the heap to `dst`: every live tuple, packed
+    // densely into freshly allocated pages in scan order. The source is not
+    // changed. The report maps old TupleIds to new ones so indexes can be rebuilt.
+    pub fn rewrite_to(&mut self, dst: &mut DiskManager) -> Result<RewriteReport, DiskError> {
+        let mut report = RewriteReport::default();
+        let now = now_epoch_secs();
+        let mut image: Page = [0; PAGE_SIZE];
+        let mut image_id = None;
+        for &page_id in self.pages.iter() {
+            let Some((records, _)) = self.page_records(page_id, 0) else {
+                continue;
+            };
+            for (old_tid, record) in records {
+                if self.decode_record(record.clone(), now).is_none() {
+                    continue;
+                }
+                let mut slot_id = match image_id {
+                    Some(_) => SlottedPage::from_buffer_unchecked(&mut image)
+                        .insert(&record)
+                        .ok(),
+                    None => None,
+                };
+                if slot_id.is_none() {
+                    if let Some(page_id) = image_id {
+                        dst.write_page(page_id, &image)?;
+                    }
+                    let page_id = dst.allocate_page()?;
+                    image_id = Some(page_id);
+                    report.pages.push(page_id);
+                    slot_id = SlottedPage::init(&mut image).insert(&record).ok();
+                }
+                let new_tid = TupleId {
+                    page_id: image_id.unwrap(),
+                    slot_id: slot_id.expect("a tuple always fits an empty page"),
+                };
+                report.remap.insert(old_tid, new_tid);
+            }
+        }
+        if let Some(page_id) = image_id {
+            dst.write_page(page_id, &image)?;
+        }
+        report.bytes_saved = self
+            .disk_size_bytes()
+            .saturating_sub(report.pages.len() as u64 * PAGE_SIZE as u64);
+        Ok(report)
+    }
+
+    // Move every live tuple of page `b` onto page `a` and free `b`, if they
+    // all fit on `a` (after compacting it). Returns the old -> new TupleId of
+    // each moved tuple, or None if they do not fit. As with relocate_tuple,
+    // version links into `b` are not rewritten.
+    pub fn merge_pages(&mut self, a: PageId, b: PageId) -> Option<Vec<(TupleId, TupleId)>> {
+        let a_idx = self.pages.iter().position(|&p| p == a)?;
+        if a == b || !self.pages.contains(&b) {
+            return None;
+        }
+        let (records, _) = self.page_records(b, 0)?;
+        let need: usize = records
+            .iter()
+            .map(|(_, record)| record.len() + SLOT_ENTRY_SIZE)
+            .sum();
+        let frame = self.buffer_pool_manager.fetch_page(a)?;
+        let (new_slots, dirty) = {
+            let mut frame_lock = frame.lock().unwrap();
+            let mut sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+            let compacted = sp.largest_contiguous_free() < need;
+            if compacted {
+                sp.compact();
+            }
+            let new_slots = if sp.largest_contiguous_free() < need {
+                None
+            } else {
+                records
+                    .iter()
+                    .map(|(_, record)| sp.insert(record).ok())
+                    .collect::<Option<Vec<SlotId>>>()
+            };
+            self.fsm[a_idx] = fsm_bucket(sp.largest_contiguous_free());
+            let dirty = compacted || new_slots.is_some();
+            frame_lock.is_dirty |= dirty;
+            (new_slots, dirty)
+        };
+        let _ = self.buffer_pool_manager.unpin_page(a, dirty);
+        let remap: Vec<(TupleId, TupleId)> = records
+            .iter()
+            .zip(new_slots?)
+            .map(|(&(old, _), slot_id)| {
+                (
+                    old,
+                    TupleId {
+                        page_id: a,
+                        slot_id,
+                    },
+                )
+            })
+            .collect();
+        for &(old, _) in &remap {
+            self.delete_tuple(old);
+        }
+        // b is empty now; if the disk manager will not take it back it stays
+        // in the heap as an empty page.
+        self.free_page(b);
+        Some(remap)
+    }
+
+    // Give an empty page back to the disk manager's free-space directory so any
+    // table sharing the file can reuse it. Returns false if the page is not
+    // ours or still holds tuples.
+    pub fn free_page(&mut self, page_id: PageId) -> bool {
+        let Some(idx) = self.pages.iter().position(|&p| p == page_id) else {
+            return false;
+        };
+        if self.working_page == Some(page_id) && self.release_working_page().is_err() {
+            return false;
+        }
+        match self.page_records(page_id, 0) {
+            Some((records, _)) if records.is_empty() => {}
+            _ => return false,
+        }
+        let owner = {
+            let dm = self.buffer_pool_manager.disk_manager.lock().unwrap();
+            dm.directory().owner(page_id)
+        };
+        // Drop the frame before giving the page away, so a stale write-back
+        // cannot land on it once someone else reuses it.
+        if owner != Some(self.owner) || !self.buffer_pool_manager.delete_page(page_id) {
+            return false;
+        }
+        {
+            let mut dm = self.buffer_pool_manager.disk_manager.lock().unwrap();
+            dm.release_page(page_id, self.owner);
+        }
+        self.pages.remove(idx);
+        self.fsm.remove(idx);
+        self.freed_idxs.push(idx);
+        true
+    }
+
+    // Read a tuple given its TupleId
+    pub fn read_tuple(&mut self, tid: TupleId) -> Option<Vec<u8>> {
+        self.read_tuple_at(tid, now_epoch_secs())
+    }
+
+    // Zero-copy read: the returned TupleRef lends out the tuple's bytes in the
+    // buffer pool. The page stays pinned until the TupleRef is dropped; its
+    // frame is only locked while the bytes are being looked at.
+    pub fn read_tuple_guard(&mut self, tid: TupleId) -> Option<TupleRef<'_>> {
+        let frame = self.buffer_pool_manager.fetch_page(tid.page_id)?;
+        let visible = {
+            let mut frame_lock = frame.lock().unwrap();
+            let sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+            sp.read(tid.slot_id).is_some_and(|record| {
+                self.format != RecordFormat::Expiring || expires_at(record) > now_epoch_secs()
+            })
+        };
+        if !visible {
+            let _ = self.buffer_pool_manager.unpin_page(tid.page_id, false);
+            return None;
+        }
+        Some(TupleRef {
+            frame,
+            buffer_pool_manager: &self.buffer_pool_manager,
+            tid,
+            prefix_size: self.format.prefix_size(),
+        })
+    }
+
+    // Read a tuple as of the given time; expired tuples read as absent.
+    pub fn read_tuple_at(&mut self, tid: TupleId, now: u64) -> Option<Vec<u8>> {
+        let cached = self
+            .cache
+            .as_mut()
+            .and_then(|cache| cache.get(tid).map(|record| record.to_vec()));
+        let record = match cached {
+            Some(record) => record,
+            None => {
+                let record = self.read_record(tid)?;
+                if let Some(cache) = self.cache.as_mut() {
+                    cache.insert(tid, record.clone());
+                }
+                record
+            }
+        };
+        self.decode_record(record, now)
+    }
+
+    // Turn stored record bytes into the tuple the caller sees, hiding expired tuples.
+    fn decode_record(&self, record: Vec<u8>, now: u64) -> Option<Vec<u8>> {
+        match self.format {
+            RecordFormat::Plain => Some(record),
+            RecordFormat::Expiring if expires_at(&record) <= now => None,
+            _ => Some(record[self.format.prefix_size()..].to_vec()),
+        }
+    }
+
+    // Iterate over the versions of a versioned heap that were visible at
+    // `snapshot_ts`, i.e. whose [begin_ts, end_ts) contains it. That is at
+    // most one version of every tuple. Other heaps yield nothing.
+    pub fn scan_as_of(
+        &mut self,
+        snapshot_ts: u64,
+    ) -> impl Iterator<Item = (TupleId, Vec<u8>)> + '_ {
+        let heap: &HeapFile = self;
+        let pages = match heap.format {
+            RecordFormat::Versioned => &heap.pages[..],
+            _ => &[],
+        };
+        pages
+            .iter()
+            .flat_map(move |&page_id| heap.page_records(page_id, 0).unwrap_or_default().0)
+            .filter(move |(_, record)| {
+                let (begin_ts, end_ts) = version_lifetime(record);
+                begin_ts <= snapshot_ts && snapshot_ts < end_ts
+            })
+            .map(|(tid, record)| (tid, record[VERSION_PREFIX_SIZE..].to_vec()))
+    }
+
+    // Iterate over all live tuples backwards: pages in reverse directory
+    // order, each page from its highest slot down. For a heap that is only
+    // appended to, that is roughly newest first.
+    pub fn scan_rev(&mut self) -> impl Iterator<Item = (TupleId, Vec<u8>)> + '_ {
+        let heap: &HeapFile = self;
+        let now = now_epoch_secs();
+        heap.pages
+            .iter()
+            .rev()
+            .flat_map(move |&page_id| {
+                let records = heap.page_records(page_id, 0).unwrap_or_default().0;
+                records.into_iter().rev()
+            })
+            .filter_map(move |(tid, record)| Some((tid, heap.decode_record(record, now)?)))
+    }
+
+    // Iterate over all live tuples, page by page in directory order.
+    pub fn scan(&mut self) -> HeapScan<'_> {
+        self.scan_with_readahead(0)
+    }
+
+    // Scan without copying tuples out: see RefScan.
+    pub fn scan_refs(&mut self) -> RefScan<'_> {
+        RefScan {
+            heap: self,
+            page_idx: 0,
+            slot: 0,
+            now: now_epoch_secs(),
+        }
+    }
+
+    // The first slot at or after `from_slot` on a page that holds a tuple
+    // visible at `now`.
+    fn next_live_slot(&self, page_id: PageId, from_slot: u16, now: u64) -> Option<SlotId> {
+        let frame = self.buffer_pool_manager.fetch_page(page_id)?;
+        let slot = {
+            let mut frame_lock = frame.lock().unwrap();
+            let sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+            (from_slot..sp.slot_count()).map(SlotId).find(|&slot_id| {
+                sp.read(slot_id).is_some_and(|record| {
+                    self.format != RecordFormat::Expiring || expires_at(record) > now
+                })
+            })
+        };
+        let _ = self.buffer_pool_manager.unpin_page(page_id, false);
+        slot
+    }
+
+    // Like scan, but each time the scan moves to a page, the next `window`
+    // pages are read into the buffer pool before its tuples are yielded, so
+    // their I/O is done by the time the scan gets there. A window of 0 is
+    // a plain scan.
+    pub fn scan_with_readahead(&mut self, window: usize) -> HeapScan<'_> {
+        HeapScan {
+            heap: self,
+            page_idx: 0,
+            now: now_epoch_secs(),
+            buffered: VecDeque::new(),
+            stats: ScanStats::default(),
+            readahead: window,
+            prefetched_until: 0,
+        }
+    }
+
+    // Call `f` on every live tuple, with the page directory split into
+    // `threads` contiguous ranges that are scanned concurrently through the
+    // shared buffer pool. Tuples reach `f` in no particular order. Each worker
+    // pins one page at a time; pages that cannot be fetched are skipped, as
+    // in scan, so the pool should have at least `threads` frames to spare.
+    pub fn parallel_scan(&self, threads: usize, f: impl Fn(TupleId, &[u8]) + Sync) {
+        let now = now_epoch_secs();
+        let chunk = self.pages.len().div_ceil(threads.max(1)).max(1);
+        std::thread::scope(|scope| {
+            for pages in self.pages.chunks(chunk) {
+                let f = &f;
+                scope.spawn(move || {
+                    for &page_id in pages {
+                        let Some((records, _)) = self.page_records(page_id, 0) else {
+                            continue;
+                        };
+                        for (tid, record) in records {
+                            if let Some(tuple) = self.decode_record(record, now) {
+                                f(tid, &tuple);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    // Write every live tuple to `w` as a u32 little-endian length followed by
+    // the tuple bytes. This is a logical dump for moving data around, not a
+    // copy of the pages. Returns how many tuples were written.
+    pub fn export(&mut self, mut w: impl Write) -> io::Result<usize> {
+        let mut count = 0;
+        for (_, tuple) in self.scan() {
+            w.write_all(&(tuple.len() as u32).to_le_bytes())?;
+            w.write_all(&tuple)?;
+            count += 1;
+        }
+        w.flush()?;
+        Ok(count)
+    }
+
+    // Insert every tuple from a stream written by export. Returns the new
+    // TupleIds in stream order.
+    pub fn import(&mut self, mut r: impl Read) -> io::Result<Vec<TupleId>> {
+        let mut tids = Vec::new();
+        let mut len_buf = [0u8; 4];
+        loop {
+            // A clean end of stream falls between two tuples.
+            if r.read(&mut len_buf[..1])? == 0 {
+                return Ok(tids);
+            }
+            r.read_exact(&mut len_buf[1..])?;
+            let mut tuple = vec![0u8; u32::from_le_bytes(len_buf) as usize];
+            r.read_exact(&mut tuple)?;
+            let tid = self
+                .insert_tuple(&tuple)
+                .ok_or_else(|| io::Error::other("imported tuple could not be stored"))?;
+            tids.push(tid);
+        }
+    }
+
+    // Iterate over the TupleIds of all live tuples without copying their bytes.
+    pub fn rid_iter(&mut self) -> impl Iterator<Item = TupleId> + '_ {
+        let now = now_epoch_secs();
+        let heap: &HeapFile = self;
+        heap.pages
+            .iter()
+            .flat_map(move |&page_id| heap.page_rids(page_id, now).unwrap_or_default())
+    }
+
+    // Continue a scan from a position saved with HeapScan::position. Tuples
+    // already yielded before the position was taken are not seen again.
+    pub fn scan_from_position(&mut self, pos: ScanPosition) -> HeapScan<'_> {
+        // Replay the pages freed since, each of which shifted the directory.
+        let (mut page_idx, mut slot) = (pos.page_idx, pos.slot);
+        for &freed in &self.freed_idxs[pos.freed..] {
+            if freed < page_idx {
+                page_idx -= 1;
+            } else if freed == page_idx {
+                // Nothing was left on it; continue with the page after it.
+                slot = 0;
+            }
+        }
+        let mut scan = HeapScan {
+            heap: self,
+            page_idx,
+            now: now_epoch_secs(),
+            buffered: VecDeque::new(),
+            stats: ScanStats::default(),
+            readahead: 0,
+            prefetched_until: 0,
+        };
+        scan.load_next_page(slot);
+        scan
+    }
+
+    // Visit every live tuple with mutable access to its bytes. Returning true
+    // from `f` means the tuple was modified, and its page is marked dirty.
+    pub fn for_each_mut(&mut self, mut f: impl FnMut(TupleId, &mut [u8]) -> bool) {
+        let now = now_epoch_secs();
+        for &page_id in self.pages.iter() {
+            let frame = {
+                match self.buffer_pool_manager.fetch_page(page_id) {
+                    Some(frame) => frame,
+                    None => continue,
+                }
+            };
+            let modified = {
+                let mut frame_lock = frame.lock().unwrap();
+                let mut sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+                let slots: Vec<SlotId> = sp.iter().map(|(slot_id, _)| slot_id).collect();
+                let mut modified = false;
+                for slot_id in slots {
+                    let record = sp.read_mut(slot_id).unwrap();
+                    if self.format == RecordFormat::Expiring && expires_at(record) <= now {
+                        continue;
+                    }
+                    let tuple = &mut record[self.format.prefix_size()..];
+                    let tid = TupleId { page_id, slot_id };
+                    if f(tid, tuple) {
+                        modified = true;
+                        self.unique_keys = None;
+                        if let Some(cache) = self.cache.as_mut() {
+                            cache.invalidate(tid);
+                        }
+                    }
+                }
+                if modified {
+                    frame_lock.is_dirty = true;
+                }
+                modified
+            };
+            let _ = self.buffer_pool_manager.unpin_page(page_id, modified);
+        }
+    }
+
+    // Roughly how many more tuples of `avg_tuple_len` bytes fit: the free
+    // space the free-space map records for our pages, plus empty pages up to
+    // the disk manager's quota. Without a quota only existing pages count.
+    pub fn estimate_remaining_capacity(&mut self, avg_tuple_len: usize) -> u64 {
+        let per_tuple = (avg_tuple_len + SLOT_ENTRY_SIZE) as u64;
+        let free_bytes: u64 = self
+            .fsm
+            .iter()
+            .map(|&bucket| bucket as u64 * FSM_BUCKET_BYTES as u64)
+            .sum();
+        let new_pages = {
+            let dm = self.buffer_pool_manager.disk_manager.lock().unwrap();
+            dm.pages_until_quota().unwrap_or(0)
+        };
+        let empty_page_free = {
+            let mut page: Page = [0; PAGE_SIZE];
+            SlottedPage::init(&mut page).largest_contiguous_free() as u64
+        };
+        free_bytes / per_tuple + new_pages * (empty_page_free / per_tuple)
+    }
+
+    // Bytes the heap occupies on disk: every page it owns, whether full or not.
+    pub fn disk_size_bytes(&self) -> u64 {
+        self.pages.len() as u64 * PAGE_SIZE as u64
+    }
+
+    // Count live tuples by length. `buckets` are ascending inclusive upper
+    // bounds: a tuple lands in the first bucket whose bound is at least its
+    // length. The result has one more entry than `buckets`, counting the
+    // tuples longer than the last bound.
+    pub fn length_histogram(&mut self, buckets: &[usize]) -> Vec<u64> {
+        let mut counts = vec![0; buckets.len() + 1];
+        for (_, tuple) in self.scan() {
+            counts[buckets.partition_point(|&bound| bound < tuple.len())] += 1;
+        }
+        counts
     }
+
+    // Sum of the lengths of all live tuples. The gap to disk_size_bytes is
+    // fragmentation, page headers and slack.
+    pub fn logical_size_bytes(&mut self) -> u64 {
+        self.scan().map(|(_, tuple)| tuple.len() as u64).sum()
+    }
+
+    // Compact one page without risking a torn page if we crash halfway, see
+    // DiskManager::log_compaction. Returns false if the page could not be
+    // fetched.
+    pub fn compact_page(&mut self, page_id: PageId) -> Result<bool, DiskError> {
+        let frame = {
+            match self.buffer_pool_manager.fetch_page(page_id) {
+                Some(frame) => frame,
+                None => return Ok(false),
+            }
+        };
+        let mut image: Page = frame.lock().unwrap().data;
+        SlottedPage::from_buffer_unchecked(&mut image).compact();
+        let result = {
+            let mut dm = self.buffer_pool_manager.disk_manager.lock().unwrap();
+            dm.log_compaction(page_id, &image)
+                .and_then(|_| dm.apply_compaction(page_id, &image))
+        };
+        if result.is_ok() {
+            // The frame now matches what is on disk.
+            let mut frame_lock = frame.lock().unwrap();
+            frame_lock.data = image;
+            frame_lock.is_dirty = false;
+            if let Some(idx) = self.pages.iter().position(|&p| p == page_id) {
+                self.fsm[idx] = fsm_bucket(
+                    SlottedPage::from_buffer_unchecked(&mut image).largest_contiguous_free(),
+                );
+            }
+        }
+        let _ = self.buffer_pool_manager.unpin_page(page_id, false);
+        result.map(|_| true)
+    }
+
+    // Freeze the heap once it is fully written. Every page is compacted so no
+    // slack is left, and the page list and tuple count are recorded. The
+    // result only allows reads.
+    pub fn seal(mut self) -> ImmutableHeapFile {
+        let _ = self.release_working_page();
+        for idx in 0..self.pages.len() {
+            let page_id = self.pages[idx];
+            let Some(frame) = self.buffer_pool_manager.fetch_page(page_id) else {
+                continue;
+            };
+            let compacted = {
+                let mut frame_lock = frame.lock().unwrap();
+                let mut sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+                let compacted = sp.dead_space() > 0;
+                if compacted {
+                    sp.compact();
+                    self.fsm[idx] = fsm_bucket(sp.largest_contiguous_free());
+                    frame_lock.is_dirty = true;
+                }
+                compacted
+            };
+            let _ = self.buffer_pool_manager.unpin_page(page_id, compacted);
+        }
+        let tuple_count = self.scan().count();
+        ImmutableHeapFile {
+            heap: self,
+            tuple_count,
+        }
+    }
+
+    // Finish a compaction that was interrupted by a crash without reopening
+    // the file; opening it does the same. Returns whether a logged compaction
+    // had to be redone.
+    pub fn recover_compaction(&mut self) -> Result<bool, DiskError> {
+        let recovered = self
+            .buffer_pool_manager
+            .disk_manager
+            .lock()
+            .unwrap()
+            .recover_compaction()?;
+        let Some(page_id) = recovered else {
+            return Ok(false);
+        };
+        // Drop whatever copy of the torn page the buffer pool may hold.
+        if let Some(frame) = self.buffer_pool_manager.fetch_page(page_id) {
+            let mut frame_lock = frame.lock().unwrap();
+            let read = self
+                .buffer_pool_manager
+                .disk_manager
+                .lock()
+                .unwrap()
+                .read_page(page_id, &mut frame_lock.data);
+            frame_lock.is_dirty = false;
+            drop(frame_lock);
+            self.buffer_pool_manager.unpin_page(page_id, false);
+            read?;
+        }
+        Ok(true)
+    }
+
+    // Copy out the records stored on one page from slot `from_slot` on, and
+    // count the deleted slots among them.
+    fn page_records(&self, page_id: PageId, from_slot: u16) -> Option<(PageRecords, usize)> {
+        let frame = self.buffer_pool_manager.fetch_page(page_id)?;
+        let (records, tombstones) = {
+            let mut frame_lock = frame.lock().unwrap();
+            let sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+            let records: VecDeque<_> = sp
+                .iter()
+                .filter(|(slot_id, _)| slot_id.0 >= from_slot)
+                .map(|(slot_id, data)| (TupleId { page_id, slot_id }, data.to_vec()))
+                .collect();
+            let slots = sp.slot_count().saturating_sub(from_slot) as usize;
+            let tombstones = slots - records.len();
+            (records, tombstones)
+        };
+        let _ = self.buffer_pool_manager.unpin_page(page_id, false);
+        Some((records, tombstones))
+    }
+
+    // Collect the TupleIds of the live tuples on one page.
+    fn page_rids(&self, page_id: PageId, now: u64) -> Option<Vec<TupleId>> {
+        let frame = self.buffer_pool_manager.fetch_page(page_id)?;
+        let rids = {
+            let mut frame_lock = frame.lock().unwrap();
+            let sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+            sp.iter()
+                .filter(|(_, record)| {
+                    self.format != RecordFormat::Expiring || expires_at(record) > now
+                })
+                .map(|(slot_id, _)| TupleId { page_id, slot_id })
+                .collect()
+        };
+        let _ = self.buffer_pool_manager.unpin_page(page_id, false);
+        Some(rids)
+    }
+
+    // Tombstone every tuple that has expired by `now`.
+    // Returns the number of tuples removed.
+    pub fn vacuum_expired(&mut self, now: u64) -> usize {
+        if self.format != RecordFormat::Expiring {
+            return 0;
+        }
+        let mut removed = 0;
+        for &page_id in self.pages.iter() {
+            let frame = {
+                match self.buffer_pool_manager.fetch_page(page_id) {
+                    Some(frame) => frame,
+                    None => continue,
+                }
+            };
+            let page_removed = {
+                let mut frame_lock = frame.lock().unwrap();
+                let mut sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+                let expired: Vec<SlotId> = sp
+                    .iter()
+                    .filter(|(_, record)| expires_at(record) <= now)
+                    .map(|(slot_id, _)| slot_id)
+                    .collect();
+                for &slot_id in expired.iter() {
+                    sp.delete(slot_id);
+                    if let Some(cache) = self.cache.as_mut() {
+                        cache.invalidate(TupleId { page_id, slot_id });
+                    }
+                }
+                if !expired.is_empty() {
+                    frame_lock.is_dirty = true;
+                }
+                expired.len()
+            };
+            let _ = self
+                .buffer_pool_manager
+                .unpin_page(page_id, page_removed > 0);
+            removed += page_removed;
+        }
+        removed
+    }
+
+    // Overwrite the raw record bytes of a TupleId on its own page.
+    fn update_record(&mut self, tid: TupleId, record: &[u8]) -> bool {
+        self.invalidate_cached(tid);
+        self.unique_keys = None;
+        let Some(frame) = self.buffer_pool_manager.fetch_page(tid.page_id) else {
+            return false;
+        };
+        let updated = {
+            let mut frame_lock = frame.lock().unwrap();
+            let mut sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+            let updated = sp.update(tid.slot_id, record);
+            let free = sp.largest_contiguous_free();
+            if updated {
+                frame_lock.is_dirty = true;
+            }
+            if let Some(idx) = self.pages.iter().position(|&p| p == tid.page_id) {
+                self.fsm[idx] = fsm_bucket(free);
+            }
+            updated
+        };
+        let _ = self.buffer_pool_manager.unpin_page(tid.page_id, updated);
+        updated
+    }
+
+    // Read the raw record bytes stored for a TupleId
+    fn read_record(&mut self, tid: TupleId) -> Option<Vec<u8>> {
+        let frame = self.buffer_pool_manager.fetch_page(tid.page_id)?;
+        let data_opt: Option<Vec<u8>> = {
+            let mut frame_lock: std::sync::MutexGuard<'_, crate::buffer_manager::Frame> =
+                frame.lock().unwrap();
+            let sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+            sp.read(tid.slot_id).map(|data| data.to_vec())
+        };
+        let _ = self.buffer_pool_manager.unpin_page(tid.page_id, false);
+        data_opt
+    }
+}
+
+impl Drop for HeapFile {
+    fn drop(&mut self) {
+        if let Some(page_id) = self.working_page.take() {
+            let _ = self.buffer_pool_manager.unpin_page(page_id, false);
+        }
+    }
+}
+
+// Mutations of an ImmutableHeapFile fail with this.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeapSealed;
+
+// Pages an ImmutableHeapFile scan reads ahead. Nothing is written behind a
+// sealed heap's scans, so they can afford a wide window.
+const SEALED_READAHEAD: usize = 8;
+
+// A heap that was written once and then sealed with HeapFile::seal. Its pages
+// are compacted and never change again, so the page list and tuple count are
+// exact and scans read ahead without further checks.
+pub struct ImmutableHeapFile {
+    heap: HeapFile,
+    tuple_count: usize, // Live tuples at the time of sealing
+}
+
+impl ImmutableHeapFile {
+    pub fn pages(&self) -> &[PageId] {
+        self.heap.pages()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tuple_count
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.tuple_count == 0
+    }
+
+    pub fn read_tuple(&mut self, tid: TupleId) -> Option<Vec<u8>> {
+        self.heap.read_tuple(tid)
+    }
+
+    pub fn scan(&mut self) -> HeapScan<'_> {
+        self.heap.scan_with_readahead(SEALED_READAHEAD)
+    }
+
+    pub fn insert_tuple(&mut self, _data: &[u8]) -> Result<TupleId, HeapSealed> {
+        Err(HeapSealed)
+    }
+
+    pub fn update_tuple(&mut self, _tid: TupleId, _data: &[u8]) -> Result<TupleId, HeapSealed> {
+        Err(HeapSealed)
+    }
+
+    pub fn delete_tuple(&mut self, _tid: TupleId) -> Result<(), HeapSealed> {
+        Err(HeapSealed)
+    }
+}
+
+// A tuple borrowed straight from its page in the buffer pool, see
+// HeapFile::read_tuple_guard. The page stays pinned until it is dropped, but
+// the frame is only locked inside `with`, so other users of the pool, and of
+// this page, are not held up.
+pub struct TupleRef<'a> {
+    frame: Arc<Mutex<Frame>>,
+    buffer_pool_manager: &'a BufferPoolManager,
+    tid: TupleId,
+    prefix_size: usize, // Record prefix in front of the tuple, see RecordFormat
+}
+
+impl TupleRef<'_> {
+    // Run `f` on the tuple's bytes in place. The slot is looked up again on
+    // every call, so this is None if the tuple was deleted in the meantime,
+    // e.g. through another heap sharing the page.
+    pub fn with<R>(&self, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        let mut frame_lock = self.frame.lock().unwrap();
+        let sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+        let range = sp.tuple_range(self.tid.slot_id)?;
+        Some(f(
+            &frame_lock.data[range.start + self.prefix_size..range.end]
+        ))
+    }
+}
+
+impl Drop for TupleRef<'_> {
+    fn drop(&mut self) {
+        let _ = self.buffer_pool_manager.unpin_page(self.tid.page_id, false);
+    }
+}
+
+// A scan that lends each tuple as a TupleRef straight from its page instead
+// of copying it into a Vec, for tuples too large to copy. It is not an
+// Iterator: next_tuple borrows the scan, so a TupleRef has to be dropped
+// before the scan can move on. While one is alive its page stays pinned.
+pub struct RefScan<'a> {
+    heap: &'a mut HeapFile,
+    page_idx: usize,
+    slot: u16, // Next slot to look at on the current page
+    now: u64,
+}
+
+impl RefScan<'_> {
+    pub fn next_tuple(&mut self) -> Option<(TupleId, TupleRef<'_>)> {
+        loop {
+            let &page_id = self.heap.pages.get(self.page_idx)?;
+            match self.heap.next_live_slot(page_id, self.slot, self.now) {
+                Some(slot_id) => {
+                    self.slot = slot_id.0 + 1;
+                    let tid = TupleId { page_id, slot_id };
+                    return self.heap.read_tuple_guard(tid).map(|tuple| (tid, tuple));
+                }
+                None => {
+                    self.page_idx += 1;
+                    self.slot = 0;
+                }
+            }
+        }
+    }
+}
+
+// Records copied out of one page, in slot order.
+type PageRecords = VecDeque<(TupleId, Vec<u8>)>;
+
+// Opaque checkpoint of a HeapScan: a page index in the heap's directory, the
+// first slot on that page not yet yielded, and how many pages had been freed
+// at the time. New pages are appended to the directory and freed ones are
+// replayed on resume, so a position stays valid while the heap changes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ScanPosition {
+    page_idx: usize,
+    slot: u16,
+    freed: usize,
+}
+
+// What a HeapScan has read so far. Deleted slots count as tombstones; expired
+// tuples do not, vacuum_expired turns those into tombstones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScanStats {
+    pub pages_visited: u64,
+    pub tombstones_skipped: u64,
+}
+
+// Iterator over the live tuples of a HeapFile. Tuples are copied out one page
+// at a time so no page stays pinned between calls to next.
+pub struct HeapScan<'a> {
+    heap: &'a HeapFile,
+    page_idx: usize,
+    now: u64,
+    buffered: PageRecords,
+    stats: ScanStats,
+    readahead: usize,        // Pages to prefetch past the current one
+    prefetched_until: usize, // Directory index below which pages were prefetched
+}
+
+impl HeapScan<'_> {
+    pub fn stats(&self) -> ScanStats {
+        self.stats
+    }
+
+    // Buffer the records of the next page, starting at `from_slot`. Returns
+    // false once every page has been visited.
+    fn load_next_page(&mut self, from_slot: u16) -> bool {
+        let Some(&page_id) = self.heap.pages.get(self.page_idx) else {
+            return false;
+        };
+        self.page_idx += 1;
+        if let Some((records, tombstones)) = self.heap.page_records(page_id, from_slot) {
+            self.buffered = records;
+            self.stats.pages_visited += 1;
+            self.stats.tombstones_skipped += tombstones as u64;
+        }
+        // After copying this page out, so the prefetch cannot evict it first.
+        self.prefetch();
+        true
+    }
+
+    // Pull the pages of the read-ahead window into the buffer pool without
+    // keeping them pinned. Pages already prefetched are skipped.
+    fn prefetch(&mut self) {
+        let pages = &self.heap.pages;
+        let end = (self.page_idx + self.readahead).min(pages.len());
+        let bpm = &self.heap.buffer_pool_manager;
+        for &page_id in &pages[self.prefetched_until.max(self.page_idx)..end.max(self.page_idx)] {
+            if bpm.fetch_page(page_id).is_some() {
+                bpm.unpin_page(page_id, false);
+            }
+        }
+        self.prefetched_until = self.prefetched_until.max(end);
+    }
+
+    // Where the scan would continue from; pass to HeapFile::scan_from_position.
+    pub fn position(&self) -> ScanPosition {
+        match self.buffered.front() {
+            Some((tid, _)) => ScanPosition {
+                page_idx: self.page_idx - 1,
+                slot: tid.slot_id.0,
+                freed: self.heap.freed_idxs.len(),
+            },
+            None => ScanPosition {
+                page_idx: self.page_idx,
+                slot: 0,
+                freed: self.heap.freed_idxs.len(),
+            },
+        }
+    }
+}
+
+impl Iterator for HeapScan<'_> {
+    type Item = (TupleId, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            while let Some((tid, record)) = self.buffered.pop_front() {
+                if let Some(tuple) = self.heap.decode_record(record, self.now) {
+                    return Some((tid, tuple));
+                }
+            }
+            if !self.load_next_page(0) {
+                return None;
+            }
+        }
+    }
+}
+
+fn expires_at(record: &[u8]) -> u64 {
+    u64::from_le_bytes(record[..EXPIRY_PREFIX_SIZE].try_into().unwrap())
+}
+
+fn versioned_record(prev: Option<TupleId>, begin_ts: u64, data: &[u8]) -> Vec<u8> {
+    let (page_id, slot_id) = match prev {
+        Some(tid) => (tid.page_id, tid.slot_id.0),
+        None => (NO_PREV_VERSION, 0),
+    };
+    let mut record = Vec::with_capacity(VERSION_PREFIX_SIZE + data.len());
+    record.extend_from_slice(&page_id.to_le_bytes());
+    record.extend_from_slice(&slot_id.to_le_bytes());
+    record.extend_from_slice(&begin_ts.to_le_bytes());
+    record.extend_from_slice(&STILL_CURRENT.to_le_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+fn version_lifetime(record: &[u8]) -> (u64, u64) {
+    let begin_ts = u64::from_le_bytes(record[10..END_TS_OFFSET].try_into().unwrap());
+    let end_ts = u64::from_le_bytes(
+        record[END_TS_OFFSET..VERSION_PREFIX_SIZE]
+            .try_into()
+            .unwrap(),
+    );
+    (begin_ts, end_ts)
+}
+
+fn prev_version(record: &[u8]) -> Option<TupleId> {
+    let page_id = PageId::from_le_bytes(record[0..8].try_into().unwrap());
+    if page_id == NO_PREV_VERSION {
+        return None;
+    }
+    let slot_id = u16::from_le_bytes(record[8..10].try_into().unwrap());
+    Some(TupleId {
+        page_id,
+        slot_id: SlotId(slot_id),
+    })
+}
+
+#[test]
+fn tuple_expiry_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let dm = DiskManager::new(&temp_db_path("tuple_expiry")).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut hf = HeapFile::new_expiring(bpm);
+
+    let short_lived = hf.insert_with_ttl(b"session", 1_000).unwrap();
+    let forever = hf.insert_tuple(b"config").unwrap();
+
+    assert_eq!(hf.read_tuple_at(short_lived, 999).unwrap(), b"session");
+    assert_eq!(hf.read_tuple_at(short_lived, 1_000), None);
+    assert_eq!(hf.read_tuple_at(forever, 1_000).unwrap(), b"config");
+
+    assert_eq!(hf.vacuum_expired(1_000), 1);
+    // The slot is gone, not just hidden by the clock.
+    assert_eq!(hf.read_tuple_at(short_lived, 0), None);
+    assert_eq!(hf.read_tuple(forever).unwrap(), b"config");
+    assert_eq!(hf.vacuum_expired(1_000), 0);
+}
+
+#[test]
+fn heap_size_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let dm = DiskManager::new(&temp_db_path("heap_size")).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut hf = HeapFile::new_expiring(bpm);
+    let in_an_hour = now_epoch_secs() + 3600;
+    for i in 0..300u64 {
+        hf.insert_with_ttl(
+            &[1u8; 20],
+            if i % 2 == 0 {
+                in_an_hour
+            } else {
+                NEVER_EXPIRES
+            },
+        )
+        .unwrap();
+    }
+    let disk_before = hf.disk_size_bytes();
+    assert_eq!(disk_before, hf.pages.len() as u64 * PAGE_SIZE as u64);
+    assert_eq!(hf.logical_size_bytes(), 300 * 20);
+
+    assert_eq!(hf.vacuum_expired(in_an_hour), 150);
+    // Vacuum only tombstones, so the space is still owned but no longer live.
+    assert_eq!(hf.disk_size_bytes(), disk_before);
+    assert_eq!(hf.logical_size_bytes(), 150 * 20);
+    assert_eq!(hf.scan().count(), 150);
+}
+
+#[test]
+fn compaction_crash_recovery_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let dm = DiskManager::new(&temp_db_path("compaction_crash")).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut hf = HeapFile::new(bpm.clone());
+    let tids: Vec<TupleId> = (0..20u8)
+        .map(|i| hf.insert_tuple(&[i; 50]).unwrap())
+        .collect();
+    let page_id = tids[0].page_id;
+
+    // Punch holes into the page so compaction has something to move.
+    let mut page: Page = {
+        let frame = bpm.fetch_page(page_id).unwrap();
+        let mut frame_lock = frame.lock().unwrap();
+        let mut sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+        for tid in tids.iter().step_by(2) {
+            sp.delete(tid.slot_id);
+        }
+        frame_lock.data
+    };
+    bpm.unpin_page(page_id, true);
+    let mut image = page;
+    SlottedPage::from_buffer_unchecked(&mut image).compact();
+
+    // Crash after logging, while the real page is only half written.
+    bpm.disk_manager
+        .lock()
+        .unwrap()
+        .log_compaction(page_id, &image)
+        .unwrap();
+    page[..PAGE_SIZE / 2].copy_from_slice(&image[..PAGE_SIZE / 2]);
+    {
+        bpm.disk_manager
+            .lock()
+            .unwrap()
+            .write_page(page_id, &page)
+            .unwrap();
+    }
+
+    assert!(hf.recover_compaction().unwrap());
+    let mut on_disk: Page = [0; PAGE_SIZE];
+    {
+        let mut dm = bpm.disk_manager.lock().unwrap();
+        dm.read_page(page_id, &mut on_disk).unwrap();
+    }
+    assert_eq!(on_disk, image);
+    for (i, tid) in tids.iter().enumerate() {
+        let expected = if i % 2 == 0 {
+            None
+        } else {
+            Some(vec![i as u8; 50])
+        };
+        assert_eq!(hf.read_tuple(*tid), expected);
+    }
+    // Nothing is left to redo, and a normal compaction still works.
+    assert!(!hf.recover_compaction().unwrap());
+    assert!(hf.compact_page(page_id).unwrap());
+    assert_eq!(hf.read_tuple(tids[1]).unwrap(), vec![1; 50]);
+}
+
+#[test]
+fn compaction_restart_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("compaction_restart");
+    let (pages, tids, image) = {
+        let bpm = BufferPoolManager::new(4, DiskManager::new(&path).unwrap());
+        let mut hf = HeapFile::new(bpm.clone());
+        let tids: Vec<TupleId> = (0..20u8)
+            .map(|i| hf.insert_tuple(&[i; 50]).unwrap())
+            .collect();
+        for tid in tids.iter().step_by(2) {
+            hf.delete_tuple(*tid);
+        }
+        bpm.flush_all_pages().unwrap();
+        let page_id = tids[0].page_id;
+        let mut page: Page = [0; PAGE_SIZE];
+        let mut dm = bpm.disk_manager.lock().unwrap();
+        dm.read_page(page_id, &mut page).unwrap();
+        let mut image = page;
+        SlottedPage::from_buffer_unchecked(&mut image).compact();
+
+        // Crash after logging, while the real page is only half written.
+        dm.log_compaction(page_id, &image).unwrap();
+        page[..PAGE_SIZE / 2].copy_from_slice(&image[..PAGE_SIZE / 2]);
+        dm.write_page(page_id, &page).unwrap();
+        (hf.pages().to_vec(), tids, image)
+    };
+
+    // Reopening the file finds the log through the metadata and redoes it.
+    let dm = DiskManager::new(&path).unwrap();
+    assert!(dm.metadata().compaction_log.is_some());
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut on_disk: Page = [0; PAGE_SIZE];
+    bpm.disk_manager
+        .lock()
+        .unwrap()
+        .read_page(tids[0].page_id, &mut on_disk)
+        .unwrap();
+    assert_eq!(on_disk, image);
+    let mut hf = HeapFile::open(bpm, pages);
+    assert!(!hf.recover_compaction().unwrap());
+    for (i, tid) in tids.iter().enumerate() {
+        let expected = (i % 2 == 1).then(|| vec![i as u8; 50]);
+        assert_eq!(hf.read_tuple(*tid), expected);
+    }
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn for_each_mut_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    // A single frame makes every page change show up as a write-back on eviction.
+    let dm = DiskManager::new(&temp_db_path("for_each_mut")).unwrap();
+    let bpm = BufferPoolManager::new(1, dm);
+    let mut hf = HeapFile::new(bpm.clone());
+    while hf.pages.len() < 3 {
+        hf.insert_tuple(&[0u8; 100]).unwrap();
+    }
+    let middle = hf.pages[1];
+    let disk_writes = || bpm.disk_manager.lock().unwrap().stats().writes;
+
+    // Flush the last page out of the pool so it starts clean.
+    hf.read_tuple(TupleId {
+        page_id: hf.pages[0],
+        slot_id: SlotId(0),
+    });
+    let writes_before = disk_writes();
+    hf.for_each_mut(|tid, tuple| {
+        if tid.page_id != middle {
+            return false;
+        }
+        tuple[0] = 42;
+        true
+    });
+    hf.read_tuple(TupleId {
+        page_id: hf.pages[0],
+        slot_id: SlotId(0),
+    });
+    assert_eq!(disk_writes() - writes_before, 1);
+
+    for (tid, tuple) in hf.scan() {
+        assert_eq!(tuple[0] == 42, tid.page_id == middle);
+    }
+}
+
+#[test]
+fn rebuild_fsm_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let dm = DiskManager::new(&temp_db_path("rebuild_fsm")).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut hf = HeapFile::new(bpm);
+    while hf.pages.len() < 3 {
+        hf.insert_tuple(&[9u8; 250]).unwrap();
+    }
+    assert_eq!(hf.rebuild_fsm(), 0);
+
+    // Claim the full first page is empty and the last page, which has room, is full.
+    hf.fsm[0] = u8::MAX;
+    hf.fsm[2] = 0;
+    assert_eq!(hf.rebuild_fsm(), 2);
+    assert!(hf.fsm[0] < fsm_bucket(250 + SLOT_ENTRY_SIZE));
+
+    let tid = hf.insert_tuple(&[1u8; 250]).unwrap();
+    assert_eq!(tid.page_id, hf.pages[2]);
+    assert_eq!(hf.pages.len(), 3);
+}
+
+#[test]
+fn version_chain_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let dm = DiskManager::new(&temp_db_path("version_chain")).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut hf = HeapFile::new_versioned(bpm);
+    let v1 = hf.insert_tuple(b"v1").unwrap();
+    let v2 = hf.update_tuple(v1, b"v2").unwrap();
+    let v3 = hf.update_tuple(v2, b"v3").unwrap();
+    assert_ne!(v1, v2);
+    assert_eq!(hf.read_tuple(v3).unwrap(), b"v3");
+
+    let chain = hf.read_version_chain(v3);
+    assert_eq!(
+        chain,
+        vec![
+            (v3, b"v3".to_vec()),
+            (v2, b"v2".to_vec()),
+            (v1, b"v1".to_vec())
+        ]
+    );
+    assert_eq!(hf.read_version_chain(v1), vec![(v1, b"v1".to_vec())]);
+}
+
+#[test]
+fn version_chain_reused_slot_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("version_chain_reused_slot");
+    let bpm = BufferPoolManager::new(4, DiskManager::new(&path).unwrap());
+    let mut hf = HeapFile::new_versioned(bpm);
+    hf.set_free_empty_pages(false);
+    let a = hf.insert_tuple_at(b"a", 1).unwrap();
+    let b = hf.update_tuple_at(a, b"b", 2).unwrap();
+    hf.delete_tuple(a);
+    // c takes over a's slot, so b's link now points at c.
+    let c = hf.update_tuple_at(b, b"c", 3).unwrap();
+    assert_eq!(c, a);
+    assert_eq!(
+        hf.read_version_chain(c),
+        vec![(c, b"c".to_vec()), (b, b"b".to_vec())]
+    );
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn absorb_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let dm = DiskManager::new(&temp_db_path("absorb")).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut a = HeapFile::new(bpm.clone());
+    let mut b = HeapFile::new(bpm.clone());
+    let mut tids = Vec::new();
+    for i in 0..50u8 {
+        tids.push(a.insert_tuple(&[i; 100]).unwrap());
+        tids.push(b.insert_tuple(&[i + 100; 100]).unwrap());
+    }
+    let pages = a.pages.len() + b.pages.len();
+
+    // A heap in another buffer pool cannot be merged.
+    let other_dm = DiskManager::new(&temp_db_path("absorb_other")).unwrap();
+    let other = HeapFile::new(BufferPoolManager::new(1, other_dm));
+    assert!(a.absorb(other).is_err());
+    // Neither can one that claims a page we already own.
+    let mut overlapping = HeapFile::new(bpm.clone());
+    overlapping.pages.push(a.pages[0]);
+    overlapping.fsm.push(0);
+    assert!(a.absorb(overlapping).is_err());
+    // Or one that lists a page it does not own.
+    let mut stray = HeapFile::new(bpm.clone());
+    stray.pages.push(b.pages[0]);
+    stray.fsm.push(0);
+    let stray = a.absorb(stray).unwrap_err();
+    assert_eq!(stray.pages, [b.pages[0]]);
+    let owner = bpm
+        .disk_manager
+        .lock()
+        .unwrap()
+        .directory()
+        .owner(b.pages[0]);
+    assert_eq!(owner, Some(b.owner));
+
+    assert!(a.absorb(b).is_ok());
+    assert_eq!(a.pages.len(), pages);
+    let mut scanned: Vec<TupleId> = a.scan().map(|(tid, _)| tid).collect();
+    assert_eq!(scanned.len(), tids.len());
+    for tid in tids.iter() {
+        assert!(scanned.contains(tid));
+    }
+    scanned.sort_by_key(|tid| (tid.page_id, tid.slot_id.0));
+    scanned.dedup();
+    assert_eq!(scanned.len(), tids.len());
+}
+
+#[test]
+fn tuple_cache_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("tuple_cache");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = HeapFile::new(bpm.clone());
+    heap.enable_tuple_cache(8);
+
+    let tid = heap.insert_tuple(b"hot").unwrap();
+    assert_eq!(heap.read_tuple(tid).unwrap(), b"hot");
+    let before = bpm.stats();
+    assert_eq!(heap.read_tuple(tid).unwrap(), b"hot");
+    assert_eq!(bpm.stats(), before);
+
+    // Updates and deletes must not serve stale bytes.
+    heap.update_tuple(tid, b"new").unwrap();
+    assert_eq!(heap.read_tuple(tid).unwrap(), b"new");
+    assert!(heap.delete_tuple(tid));
+    assert_eq!(heap.read_tuple(tid), None);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn scan_position_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("scan_position");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = HeapFile::new(bpm);
+    let tuple = [7u8; 500];
+    for _ in 0..20 {
+        heap.insert_tuple(&tuple).unwrap();
+    }
+    let all: Vec<TupleId> = heap.scan().map(|(tid, _)| tid).collect();
+    assert_eq!(all.len(), 20);
+
+    let mut scan = heap.scan();
+    let first: Vec<TupleId> = scan.by_ref().take(11).map(|(tid, _)| tid).collect();
+    let pos = scan.position();
+    drop(scan);
+
+    // Growing the heap after the checkpoint must not disturb the resume.
+    let late = heap.insert_tuple(&tuple).unwrap();
+    let rest: Vec<TupleId> = heap.scan_from_position(pos).map(|(tid, _)| tid).collect();
+    assert_eq!([&first[..], &rest[..rest.len() - 1]].concat(), all);
+    assert_eq!(rest.last(), Some(&late));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn shared_free_page_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("shared_free_page");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut table_a = HeapFile::new(bpm.clone());
+    let mut table_b = HeapFile::new(bpm.clone());
+    table_a.set_free_empty_pages(false);
+
+    let tid = table_a.insert_tuple(b"short-lived").unwrap();
+    table_b.insert_tuple(&[1; 4000]).unwrap();
+    // B may not free A's page, and A may not free a page that holds tuples.
+    assert!(!table_b.free_page(tid.page_id));
+    assert!(!table_a.free_page(tid.page_id));
+
+    assert!(table_a.delete_tuple(tid));
+    assert!(table_a.free_page(tid.page_id));
+    // B's page is full, so its next insert needs a page and takes A's.
+    let reused = table_b.insert_tuple(&[2; 100]).unwrap();
+    assert_eq!(reused.page_id, tid.page_id);
+    assert_eq!(table_b.read_tuple(reused).unwrap(), [2; 100]);
+    assert_eq!(table_a.scan().count(), 0);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn bulk_import_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("bulk_import");
+    let pages = {
+        let dm = DiskManager::new(&path).unwrap();
+        let bpm = BufferPoolManager::new(4, dm);
+        let mut heap = HeapFile::new(bpm.clone());
+        heap.begin_bulk_import();
+        for i in 0..500u32 {
+            heap.insert_tuple(&i.to_le_bytes()).unwrap();
+        }
+        // Compaction would normally sync; during the import it must not.
+        heap.compact_page(heap.pages[0]).unwrap();
+        let syncs = bpm.disk_manager.lock().unwrap().stats().syncs;
+        assert_eq!(syncs, 0);
+
+        heap.finish_bulk_import().unwrap();
+        let dm = bpm.disk_manager.clone();
+        assert_eq!(dm.lock().unwrap().stats().syncs, 1);
+        assert_eq!(dm.lock().unwrap().sync_policy(), SyncPolicy::OnSync);
+        heap.pages.clone()
+    };
+
+    let mut dm = DiskManager::new(&path).unwrap();
+    let mut values = Vec::new();
+    for page_id in pages {
+        let mut page: Page = [0; PAGE_SIZE];
+        dm.read_page(page_id, &mut page).unwrap();
+        let sp = SlottedPage::from_buffer(&mut page).unwrap();
+        values.extend(
+            sp.iter()
+                .map(|(_, t)| u32::from_le_bytes(t.try_into().unwrap())),
+        );
+    }
+    values.sort();
+    assert_eq!(values, (0..500).collect::<Vec<u32>>());
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn verify_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("verify");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = HeapFile::new(bpm.clone());
+    for _ in 0..3 {
+        heap.insert_tuple(&[5; 3000]).unwrap();
+    }
+    assert_eq!(heap.pages.len(), 3);
+    assert!(heap.verify().is_empty());
+
+    // Point free_start of the middle page into its header.
+    let broken = heap.pages[1];
+    let frame = bpm.fetch_page(broken).unwrap();
+    frame.lock().unwrap().data[0..2].copy_from_slice(&1u16.to_le_bytes());
+    bpm.unpin_page(broken, true);
+
+    assert_eq!(
+        heap.verify(),
+        vec![(broken, PageError::FreeStartOutOfRange)]
+    );
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn rid_iter_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("rid_iter");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = HeapFile::new_expiring(bpm);
+    let later = now_epoch_secs() + 3600;
+    let mut deleted = Vec::new();
+    for i in 0..300u32 {
+        let expire_at = if i % 7 == 0 { 1 } else { later };
+        let tid = heap.insert_with_ttl(&i.to_le_bytes(), expire_at).unwrap();
+        if i % 5 == 0 {
+            deleted.push(tid);
+        }
+    }
+    for tid in deleted {
+        heap.delete_tuple(tid);
+    }
+
+    let rids: Vec<TupleId> = heap.rid_iter().collect();
+    let scanned: Vec<TupleId> = heap.scan().map(|(tid, _)| tid).collect();
+    assert_eq!(rids, scanned);
+    assert_eq!(
+        rids.len(),
+        (0..300).filter(|i| i % 7 != 0 && i % 5 != 0).count()
+    );
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn scan_stats_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("scan_stats");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = HeapFile::new(bpm);
+    // 8 tuples of 500 bytes fit on a page, so 40 tuples take 5 pages.
+    let tids: Vec<TupleId> = (0..40)
+        .map(|_| heap.insert_tuple(&[3; 500]).unwrap())
+        .collect();
+    assert_eq!(heap.pages.len(), 5);
+    for tid in tids.iter().step_by(3) {
+        assert!(heap.delete_tuple(*tid));
+    }
+
+    let mut scan = heap.scan();
+    assert_eq!(scan.by_ref().count(), 40 - 14);
+    assert_eq!(
+        scan.stats(),
+        ScanStats {
+            pages_visited: 5,
+            tombstones_skipped: 14,
+        }
+    );
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn rewrite_to_test() {
+    use crate::disk_manager::temp_db_path;
+
+    let src_path = temp_db_path("rewrite_src");
+    let dst_path = temp_db_path("rewrite_dst");
+    let dm = DiskManager::new(&src_path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = HeapFile::new(bpm);
+    let tids: Vec<TupleId> = (0..64u32)
+        .map(|i| heap.insert_tuple(&[i as u8; 400]).unwrap())
+        .collect();
+    // Leave every page mostly empty.
+    for (i, &tid) in tids.iter().enumerate() {
+        if i % 4 != 0 {
+            heap.delete_tuple(tid);
+        }
+    }
+    let source_pages = heap.pages.len();
+
+    let mut dst = DiskManager::new(&dst_path).unwrap();
+    let report = heap.rewrite_to(&mut dst).unwrap();
+    assert_eq!(report.remap.len(), 16);
+    assert!(report.pages.len() < source_pages);
+    assert_eq!(
+        report.bytes_saved,
+        ((source_pages - report.pages.len()) * PAGE_SIZE) as u64
+    );
+
+    for (&old, &new) in report.remap.iter() {
+        let mut page: Page = [0; PAGE_SIZE];
+        dst.read_page(new.page_id, &mut page).unwrap();
+        let sp = SlottedPage::from_buffer(&mut page).unwrap();
+        assert_eq!(sp.read(new.slot_id).unwrap(), heap.read_tuple(old).unwrap());
+    }
+    // The source still reads the same.
+    assert_eq!(heap.scan().count(), 16);
+    assert_eq!(heap.pages.len(), source_pages);
+
+    let _ = std::fs::remove_file(src_path);
+    let _ = std::fs::remove_file(dst_path);
+}
+
+#[test]
+fn estimate_capacity_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("estimate_capacity");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = HeapFile::new(bpm.clone());
+    heap.insert_tuple(&[1; 1000]).unwrap();
+
+    // Without a quota only the free space on the existing page counts.
+    let estimate = heap.estimate_remaining_capacity(100);
+    let mut exact = 0;
+    while heap.pages.len() == 1 {
+        heap.insert_tuple(&[2; 100]).unwrap();
+        exact += 1;
+    }
+    exact -= 1; // the last insert needed a new page
+    assert!(estimate.abs_diff(exact) <= 1);
+
+    // Under a quota, the pages still allocatable count as empty pages.
+    let without_quota = heap.estimate_remaining_capacity(100);
+    let dm = bpm.disk_manager.clone();
+    let until_quota = {
+        let mut dm = dm.lock().unwrap();
+        let next_page = dm.allocate_page().unwrap();
+        dm.deallocate_page(next_page);
+        dm.set_max_pages(next_page + 4);
+        dm.pages_until_quota().unwrap()
+    };
+    assert!(until_quota > 0);
+    let with_quota = heap.estimate_remaining_capacity(100);
+    assert_eq!(with_quota - without_quota, until_quota * (4090 / 104));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn insert_blocking_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("insert_blocking");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(1, dm);
+    let mut heap = HeapFile::new(bpm.clone());
+    let mut tids = Vec::new();
+    for i in 0..200u32 {
+        let tid = heap
+            .insert_tuple_blocking(&[i as u8; 100])
+            .unwrap()
+            .unwrap();
+        tids.push(tid);
+    }
+    assert!(heap.pages.len() > 1);
+    heap.release_working_page().unwrap();
+    for (i, &tid) in tids.iter().enumerate() {
+        assert_eq!(heap.read_tuple(tid).unwrap(), [i as u8; 100]);
+    }
+
+    // A pin the heap does not own still exhausts the pool.
+    let other = bpm.disk_manager.lock().unwrap().allocate_page().unwrap();
+    let _frame = bpm.fetch_page(other).unwrap();
+    assert!(matches!(
+        heap.insert_tuple_blocking(&[0; 100]),
+        Err(BufferError::PoolExhausted)
+    ));
+    bpm.unpin_page(other, false);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn tuple_ref_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("tuple_ref");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(2, dm);
+    let mut heap = HeapFile::new_versioned(bpm.clone());
+    let tid = heap.insert_tuple(b"borrowed bytes").unwrap();
+    assert_eq!(bpm.available_frames(), 2);
+
+    let tuple = heap.read_tuple_guard(tid).unwrap();
+    assert_eq!(
+        tuple.with(|bytes| bytes.to_vec()).unwrap(),
+        b"borrowed bytes"
+    );
+    assert_eq!(tuple.with(|bytes| bytes.len()), Some(14));
+    assert_eq!(bpm.available_frames(), 1);
+    // The page can be fetched elsewhere while the tuple is borrowed.
+    let other = bpm.clone();
+    std::thread::spawn(move || {
+        assert!(other.fetch_page(tid.page_id).is_some());
+        assert!(other.unpin_page(tid.page_id, false));
+    })
+    .join()
+    .unwrap();
+    drop(tuple);
+    assert_eq!(bpm.available_frames(), 2);
+
+    let missing = TupleId {
+        page_id: tid.page_id,
+        slot_id: SlotId(9),
+    };
+    assert!(heap.read_tuple_guard(missing).is_none());
+    assert_eq!(bpm.available_frames(), 2);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn relocate_tuple_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("relocate_tuple");
+    let dm = DiskManager::new(&path).unwrap();
+    let mut heap = HeapFile::new(BufferPoolManager::new(4, dm));
+    let tids: Vec<_> = (0..100u32)
+        .map(|i| heap.insert_tuple(&[i as u8; 100]).unwrap())
+        .collect();
+    let (first, last) = (heap.pages()[0], *heap.pages().last().unwrap());
+    assert_ne!(first, last);
+
+    // The last page still has room; the full first page does not.
+    let moved = heap.relocate_tuple(tids[0], last).unwrap();
+    assert_eq!(moved.page_id, last);
+    assert_eq!(heap.read_tuple(moved).unwrap(), [0u8; 100]);
+    assert_eq!(heap.read_tuple(tids[0]), None);
+    assert_eq!(heap.relocate_tuple(tids[99], first), None);
+    assert_eq!(heap.read_tuple(tids[99]).unwrap(), [99u8; 100]);
+    assert_eq!(heap.relocate_tuple(tids[0], last), None);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn export_import_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("export_import");
+    let bpm = BufferPoolManager::new(4, DiskManager::new(&path).unwrap());
+    let mut heap = HeapFile::new(bpm.clone());
+    let tids: Vec<_> = (0..200u32)
+        .map(|i| heap.insert_tuple(&vec![i as u8; i as usize % 50]).unwrap())
+        .collect();
+    heap.delete_tuple(tids[3]);
+
+    let mut dump = Vec::new();
+    assert_eq!(heap.export(&mut dump).unwrap(), 199);
+    let mut copy = HeapFile::new(bpm.clone());
+    let new_tids = copy.import(&dump[..]).unwrap();
+    assert_eq!(new_tids.len(), 199);
+
+    let mut original: Vec<Vec<u8>> = heap.scan().map(|(_, tuple)| tuple).collect();
+    let mut imported: Vec<Vec<u8>> = copy.scan().map(|(_, tuple)| tuple).collect();
+    original.sort();
+    imported.sort();
+    assert_eq!(original, imported);
+
+    // A stream cut off mid-tuple is an error.
+    let mut partial = HeapFile::new(bpm);
+    let err = partial.import(&dump[..dump.len() - 1]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn merge_pages_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("merge_pages");
+    let bpm = BufferPoolManager::new(4, DiskManager::new(&path).unwrap());
+    let mut heap = HeapFile::new(bpm.clone());
+    let tids: Vec<_> = (0..70u32)
+        .map(|i| heap.insert_tuple(&[i as u8; 100]).unwrap())
+        .collect();
+    let (a, b) = (heap.pages()[0], heap.pages()[1]);
+    // Both pages are full, so neither fits into the other.
+    assert_eq!(heap.merge_pages(a, b), None);
+
+    // Thin both pages out to a few tuples each.
+    for (i, &tid) in tids.iter().enumerate() {
+        if i % 10 != 0 {
+            heap.delete_tuple(tid);
+        }
+    }
+    let survivors_on_b: Vec<_> = tids
+        .iter()
+        .step_by(10)
+        .filter(|tid| tid.page_id == b)
+        .copied()
+        .collect();
+    assert!(!survivors_on_b.is_empty());
+
+    let remap = heap.merge_pages(a, b).unwrap();
+    assert_eq!(
+        remap.iter().map(|&(old, _)| old).collect::<Vec<_>>(),
+        survivors_on_b
+    );
+    for &(old, new) in &remap {
+        assert_eq!(new.page_id, a);
+        assert_eq!(heap.read_tuple(old), None);
+        let i = tids.iter().position(|&tid| tid == old).unwrap();
+        assert_eq!(heap.read_tuple(new).unwrap(), [i as u8; 100]);
+    }
+    assert!(!heap.pages().contains(&b));
+    assert_eq!(heap.scan().count(), 7);
+    let dm = bpm.disk_manager.lock().unwrap();
+    assert!(dm.directory().free_pages().contains(&b));
+    drop(dm);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn scan_readahead_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("scan_readahead");
+    let bpm = BufferPoolManager::new(3, DiskManager::new(&path).unwrap());
+    let mut heap = HeapFile::new(bpm.clone());
+    for i in 0..300u32 {
+        heap.insert_tuple(&[i as u8; 100]).unwrap();
+    }
+    let page_count = heap.pages().len();
+    assert!(page_count > 6);
+    let disk_reads = || bpm.disk_manager.lock().unwrap().stats().reads;
+
+    // Only the last pages are resident, so the first page of a plain scan
+    // costs a single read.
+    let before = disk_reads();
+    let mut scan = heap.scan();
+    scan.next().unwrap();
+    assert_eq!(disk_reads() - before, 1);
+    assert_eq!(scan.count(), 299);
+
+    // With a window of 2, pages 1 and 2 are read before page 0 is yielded.
+    let mut heap = HeapFile::open(bpm.clone(), heap.pages().to_vec());
+    for &page_id in heap.pages()[page_count - 3..].iter() {
+        bpm.fetch_page(page_id).unwrap();
+        bpm.unpin_page(page_id, false);
+    }
+    let before = disk_reads();
+    let mut scan = heap.scan_with_readahead(2);
+    scan.next().unwrap();
+    assert_eq!(disk_reads() - before, 3);
+    assert_eq!(scan.count(), 299);
+    // Every page was read exactly once.
+    assert_eq!(disk_reads() - before, page_count as u64);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn open_claims_pages_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("open_claims_pages");
+    let (pages, tid) = {
+        let bpm = BufferPoolManager::new(4, DiskManager::new(&path).unwrap());
+        let mut heap = HeapFile::new(bpm.clone());
+        let tid = heap.insert_tuple(b"first").unwrap();
+        let pages = heap.pages().to_vec();
+        drop(heap);
+
+        // Reopened in the same process, the pages move to the new heap.
+        let mut heap = HeapFile::open(bpm.clone(), pages);
+        assert!(heap.delete_tuple(tid));
+        assert!(heap.pages().is_empty());
+        let tid = heap.insert_tuple(b"second").unwrap();
+        (heap.pages().to_vec(), tid)
+    };
+
+    // After a restart the directory has no tags left; open adds them back.
+    let bpm = BufferPoolManager::new(4, DiskManager::new(&path).unwrap());
+    let mut heap = HeapFile::open(bpm.clone(), pages.clone());
+    assert!(heap.delete_tuple(tid));
+    assert!(heap.pages().is_empty());
+    assert!(bpm
+        .disk_manager
+        .lock()
+        .unwrap()
+        .directory()
+        .free_pages()
+        .contains(&pages[0]));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn scan_as_of_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("scan_as_of");
+    let dm = DiskManager::new(&path).unwrap();
+    let mut heap = HeapFile::new_versioned(BufferPoolManager::new(4, dm));
+    let a1 = heap.insert_tuple_at(b"a1", 10).unwrap();
+    let a2 = heap.update_tuple_at(a1, b"a2", 20).unwrap();
+    let a3 = heap.update_tuple_at(a2, b"a3", 30).unwrap();
+    let b1 = heap.insert_tuple_at(b"b1", 15).unwrap();
+    let c1 = heap.insert_tuple_at(b"c1", 40).unwrap();
+
+    let at_25: Vec<_> = heap.scan_as_of(25).collect();
+    assert_eq!(at_25, vec![(a2, b"a2".to_vec()), (b1, b"b1".to_vec())]);
+    let at_40: Vec<_> = heap.scan_as_of(40).collect();
+    assert_eq!(
+        at_40,
+        vec![
+            (a3, b"a3".to_vec()),
+            (b1, b"b1".to_vec()),
+            (c1, b"c1".to_vec())
+        ]
+    );
+    assert_eq!(heap.scan_as_of(5).count(), 0);
+    assert_eq!(heap.read_version_chain(a3).len(), 3);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn insert_tuple_unique_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    // Hashes only the parity of the key, so 1 and 3 collide without being equal.
+    #[derive(PartialEq, Eq)]
+    struct Parity(u8);
+    impl Hash for Parity {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            (self.0 % 2).hash(state);
+        }
+    }
+
+    let path = temp_db_path("insert_tuple_unique");
+    let dm = DiskManager::new(&path).unwrap();
+    let mut heap = HeapFile::new(BufferPoolManager::new(4, dm));
+    let existing = heap.insert_tuple(b"\x01 first").unwrap();
+    let key = |tuple: &[u8]| Parity(tuple[0]);
+
+    assert_eq!(
+        heap.insert_tuple_unique(b"\x01 again", key),
+        Err(UniqueInsertError::Duplicate { existing })
+    );
+    assert!(heap.insert_tuple_unique(b"\x03 other", key).is_ok());
+    assert_eq!(heap.scan().count(), 2);
+
+    // Once the holder is deleted, its key is free again.
+    heap.delete_tuple(existing);
+    let again = heap.insert_tuple_unique(b"\x01 again", key).unwrap();
+
+    // Keys written by plain inserts and updates are seen too.
+    let plain = heap.insert_tuple(b"\x05 plain").unwrap();
+    assert_eq!(
+        heap.insert_tuple_unique(b"\x05 unique", key),
+        Err(UniqueInsertError::Duplicate { existing: plain })
+    );
+    heap.update_tuple(again, b"\x07 updated").unwrap();
+    assert_eq!(
+        heap.insert_tuple_unique(b"\x07 unique", key),
+        Err(UniqueInsertError::Duplicate { existing: again })
+    );
+    assert_eq!(
+        heap.insert_tuple_unique(&[9; PAGE_SIZE], key),
+        Err(UniqueInsertError::NoSpace)
+    );
+
+    // Keys rewritten in place by for_each_mut are seen as well.
+    let mut heap = HeapFile::new(heap.buffer_pool_manager.clone());
+    let rewritten = heap.insert_tuple_unique(b"\x01 before", key).unwrap();
+    heap.for_each_mut(|_, tuple| {
+        tuple[0] = 2;
+        true
+    });
+    assert_eq!(
+        heap.insert_tuple_unique(b"\x02 after", key),
+        Err(UniqueInsertError::Duplicate {
+            existing: rewritten
+        })
+    );
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn oversized_insert_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("oversized_insert");
+    let bpm = BufferPoolManager::new(2, DiskManager::new(&path).unwrap());
+    let mut heap = HeapFile::new(bpm.clone());
+    let num_pages = || bpm.disk_manager.lock().unwrap().metadata().num_pages;
+    let before = num_pages();
+
+    // Too large for any page: nothing is allocated or left pinned.
+    assert_eq!(heap.insert_tuple(&[0; 5000]), None);
+    assert_eq!(heap.insert_tuple(&[0; MAX_TUPLE_SIZE + 1]), None);
+    assert_eq!(bpm.available_frames(), 2);
+    assert_eq!(num_pages(), before);
+    assert!(heap.pages().is_empty());
+
+    // The largest tuple still gets a page of its own.
+    let tid = heap.insert_tuple(&[1; MAX_TUPLE_SIZE]).unwrap();
+    assert_eq!(heap.read_tuple(tid).unwrap(), vec![1; MAX_TUPLE_SIZE]);
+    assert_eq!(bpm.available_frames(), 2);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn parallel_scan_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    let path = temp_db_path("parallel_scan");
+    let dm = DiskManager::new(&path).unwrap();
+    let mut heap = HeapFile::new(BufferPoolManager::new(8, dm));
+    for i in 0..2000u64 {
+        heap.insert_tuple(&[&i.to_le_bytes()[..], &[0u8; 40]].concat())
+            .unwrap();
+    }
+    assert!(heap.pages().len() > 4);
+    let value = |tuple: &[u8]| u64::from_le_bytes(tuple[..8].try_into().unwrap());
+    let serial: u64 = heap.scan().map(|(_, tuple)| value(&tuple)).sum();
+
+    let sum = AtomicU64::new(0);
+    let seen = AtomicUsize::new(0);
+    heap.parallel_scan(4, |_, tuple| {
+        sum.fetch_add(value(tuple), Ordering::Relaxed);
+        seen.fetch_add(1, Ordering::Relaxed);
+    });
+    assert_eq!(sum.into_inner(), serial);
+    assert_eq!(seen.into_inner(), 2000);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn seal_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("seal");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = HeapFile::new(bpm.clone());
+    let tids: Vec<_> = (0..200u32)
+        .map(|i| heap.insert_tuple(&[i as u8; 60]).unwrap())
+        .collect();
+    for tid in tids.iter().step_by(3) {
+        heap.delete_tuple(*tid);
+    }
+    let pages = heap.pages().to_vec();
+
+    let mut sealed = heap.seal();
+    assert_eq!(sealed.pages(), &pages[..]);
+    assert_eq!(sealed.len(), 200 - 67);
+    assert_eq!(sealed.scan().count(), sealed.len());
+    assert_eq!(sealed.read_tuple(tids[1]), Some(vec![1; 60]));
+    for &page_id in &pages {
+        let frame = bpm.fetch_page(page_id).unwrap();
+        let dead = SlottedPage::from_buffer_unchecked(&mut frame.lock().unwrap().data).dead_space();
+        assert_eq!(dead, 0);
+        bpm.unpin_page(page_id, false);
+    }
+
+    assert_eq!(sealed.insert_tuple(b"more"), Err(HeapSealed));
+    assert_eq!(sealed.update_tuple(tids[1], b"new"), Err(HeapSealed));
+    assert_eq!(sealed.delete_tuple(tids[1]), Err(HeapSealed));
+    assert_eq!(sealed.read_tuple(tids[1]), Some(vec![1; 60]));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn compaction_lost_write_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager, WriteDecision, COMPACTION_MAGIC};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // Once the intent record is written, every later write is lost, as if
+    // the process died right after the intent became durable.
+    let crashed = Arc::new(AtomicBool::new(false));
+    let interceptor = {
+        let crashed = crashed.clone();
+        Box::new(move |_: u64, page: &Page| {
+            if crashed.load(Ordering::SeqCst) {
+                return WriteDecision::Drop;
+            }
+            if &page[0..4] == COMPACTION_MAGIC {
+                crashed.store(true, Ordering::SeqCst);
+            }
+            WriteDecision::Allow
+        })
+    };
+    let path = temp_db_path("compaction_lost_write");
+    let dm = DiskManager::new(&path)
+        .unwrap()
+        .with_write_interceptor(interceptor);
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut hf = HeapFile::new(bpm.clone());
+    let tids: Vec<TupleId> = (0..20u8)
+        .map(|i| hf.insert_tuple(&[i; 50]).unwrap())
+        .collect();
+    let page_id = tids[0].page_id;
+    for tid in tids.iter().step_by(2) {
+        hf.delete_tuple(*tid);
+    }
+    bpm.flush_page(page_id).unwrap();
+    let read_disk = || {
+        let mut page: Page = [0; PAGE_SIZE];
+        bpm.disk_manager
+            .lock()
+            .unwrap()
+            .read_page(page_id, &mut page)
+            .unwrap();
+        page
+    };
+    let before = read_disk();
+
+    // The compacted page never reaches the disk.
+    assert!(hf.compact_page(page_id).unwrap());
+    assert!(crashed.load(Ordering::SeqCst));
+    assert_eq!(read_disk(), before);
+
+    crashed.store(false, Ordering::SeqCst);
+    assert!(hf.recover_compaction().unwrap());
+    let mut image = before;
+    SlottedPage::from_buffer_unchecked(&mut image).compact();
+    assert_eq!(read_disk(), image);
+    assert_eq!(hf.read_tuple(tids[1]).unwrap(), vec![1; 50]);
+    assert_eq!(hf.read_tuple(tids[0]), None);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn length_histogram_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("length_histogram");
+    let dm = DiskManager::new(&path).unwrap();
+    let mut heap = HeapFile::new(BufferPoolManager::new(4, dm));
+    for len in [1, 8, 8, 9, 16, 17, 100, 300, 300, 1000] {
+        heap.insert_tuple(&vec![0; len]).unwrap();
+    }
+    let gone = heap.insert_tuple(&[0; 5]).unwrap();
+    heap.delete_tuple(gone);
+
+    assert_eq!(heap.length_histogram(&[8, 16, 256]), vec![3, 2, 2, 3]);
+    assert_eq!(heap.length_histogram(&[]), vec![10]);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn scan_refs_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("scan_refs");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = HeapFile::new(bpm.clone());
+    let tids: Vec<_> = (1..=300usize)
+        .map(|len| heap.insert_tuple(&vec![len as u8; len]).unwrap())
+        .collect();
+    heap.delete_tuple(tids[9]);
+    let expected: usize = (1..=300).sum::<usize>() - 10;
+
+    let mut total = 0;
+    let mut count = 0;
+    let mut scan = heap.scan_refs();
+    while let Some((tid, tuple)) = scan.next_tuple() {
+        let len = tuple
+            .with(|bytes| {
+                assert_eq!(bytes[0] as usize, bytes.len() % 256);
+                bytes.len()
+            })
+            .unwrap();
+        assert_ne!(tid, tids[9]);
+        total += len;
+        count += 1;
+    }
+    assert_eq!((total, count), (expected, 299));
+    assert_eq!(bpm.available_frames(), 4);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn update_batch_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("update_batch");
+    let dm = DiskManager::new(&path).unwrap();
+    let mut heap = HeapFile::new(BufferPoolManager::new(4, dm));
+    let tids: Vec<_> = (0..3u8)
+        .map(|i| heap.insert_tuple(&[i; 20]).unwrap())
+        .collect();
+
+    // The third update is larger than a page, so the batch fails there.
+    let too_big = [9u8; PAGE_SIZE];
+    let result = heap.update_batch(&[
+        (tids[0], b"short"),
+        (tids[1], &[7u8; 500]),
+        (tids[2], &too_big),
+    ]);
+    assert_eq!(result, Err(BatchError::Failed { failed_at: 2 }));
+    for (i, &tid) in tids.iter().enumerate() {
+        assert_eq!(heap.read_tuple(tid).unwrap(), vec![i as u8; 20]);
+    }
+    // An image that no longer fits is reported, not silently dropped.
+    assert_eq!(heap.restore_record(tids[0], &too_big), Err(tids[0]));
+    assert_eq!(heap.read_tuple(tids[0]).unwrap(), vec![0; 20]);
+
+    let new_tids = heap
+        .update_batch(&[(tids[0], b"short"), (tids[2], b"done")])
+        .unwrap();
+    assert_eq!(new_tids, vec![tids[0], tids[2]]);
+    assert_eq!(heap.read_tuple(tids[0]).unwrap(), b"short");
+    assert_eq!(heap.read_tuple(tids[2]).unwrap(), b"done");
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn free_empty_pages_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("free_empty_pages");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = HeapFile::new(bpm.clone());
+    let tids: Vec<_> = (0..60u8)
+        .map(|i| heap.insert_tuple(&[i; 200]).unwrap())
+        .collect();
+    let last_page = tids[59].page_id;
+    let on_last: Vec<_> = tids.iter().filter(|t| t.page_id == last_page).collect();
+    let pages = heap.pages().len();
+
+    for tid in &on_last {
+        assert!(heap.delete_tuple(**tid));
+    }
+    assert_eq!(heap.pages().len(), pages - 1);
+    assert!(!heap.pages().contains(&last_page));
+    assert!(bpm
+        .disk_manager
+        .lock()
+        .unwrap()
+        .directory()
+        .free_pages()
+        .contains(&last_page));
+    // The dirty frame went with it, so nothing is written back later.
+    assert!(!bpm.flush_page(last_page).unwrap());
+    assert_eq!(heap.scan().count(), 60 - on_last.len());
+
+    // Opted out: the emptied page stays for reuse.
+    heap.set_free_empty_pages(false);
+    let first_page = tids[0].page_id;
+    for tid in tids.iter().filter(|t| t.page_id == first_page) {
+        heap.delete_tuple(*tid);
+    }
+    assert!(heap.pages().contains(&first_page));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn free_page_scan_position_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("free_page_scan_position");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = HeapFile::new(bpm);
+    let tids: Vec<_> = (0..3 * 19)
+        .map(|i| heap.insert_tuple(&[i as u8; 200]).unwrap())
+        .collect();
+    let on_page = |idx: usize| -> Vec<TupleId> {
+        let page_id = tids[idx * 19].page_id;
+        tids.iter()
+            .copied()
+            .filter(|t| t.page_id == page_id)
+            .collect()
+    };
+    let (first, second) = (on_page(0), on_page(1));
+    assert_eq!(heap.pages().len(), 3);
+
+    // Freeing an earlier page must not shift a saved position.
+    let mut scan = heap.scan();
+    scan.by_ref().take(first.len()).count();
+    let pos = scan.position();
+    drop(scan);
+    for tid in &first {
+        heap.delete_tuple(*tid);
+    }
+    assert_eq!(heap.pages().len(), 2);
+    let rest = heap.scan_from_position(pos).count();
+    assert_eq!(rest, tids.len() - first.len());
+
+    // If the position's own page is freed, the scan goes on after it.
+    let mut scan = heap.scan();
+    scan.by_ref().take(2).count();
+    let pos = scan.position();
+    drop(scan);
+    for tid in &second {
+        heap.delete_tuple(*tid);
+    }
+    let rest: Vec<_> = heap.scan_from_position(pos).map(|(tid, _)| tid).collect();
+    assert_eq!(rest, on_page(2));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn scan_rev_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("scan_rev");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = HeapFile::new(bpm);
+    let mut tids: Vec<_> = (0..200u32)
+        .map(|i| heap.insert_tuple(&i.to_le_bytes().repeat(25)).unwrap())
+        .collect();
+    assert!(heap.pages().len() > 1);
+    heap.delete_tuple(tids.remove(150));
+
+    let rows: Vec<_> = heap.scan_rev().collect();
+    tids.reverse();
+    assert_eq!(rows.iter().map(|(tid, _)| *tid).collect::<Vec<_>>(), tids);
+    assert_eq!(rows[0].1, 199u32.to_le_bytes().repeat(25));
+    assert_eq!(rows.last().unwrap().1, 0u32.to_le_bytes().repeat(25));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn update_relocation_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("update_relocation");
+    let dm = DiskManager::new(&path).unwrap();
+    let mut heap = HeapFile::new(BufferPoolManager::new(4, dm));
+    let small = heap.insert_tuple(b"small").unwrap();
+    let filler = heap.insert_tuple(&[1; 3000]).unwrap();
+    assert_eq!(small.page_id, filler.page_id);
+
+    // Fits in place: same TupleId.
+    assert_eq!(heap.update_tuple(small, b"SMALL"), Some(small));
+    assert_eq!(heap.read_tuple(small).unwrap(), b"SMALL");
+
+    // Too big for the page even after compaction: moves to a new page.
+    let grown = heap.update_tuple(small, &[2; 2000]).unwrap();
+    assert_ne!(grown.page_id, small.page_id);
+    assert_eq!(heap.read_tuple(grown).unwrap(), [2; 2000]);
+    assert_eq!(heap.read_tuple(small), None);
+    assert_eq!(heap.scan().count(), 2);
+    assert_eq!(heap.update_tuple(grown, &[3; PAGE_SIZE]), None);
+    assert_eq!(heap.read_tuple(grown).unwrap(), [2; 2000]);
+
+    // A batch undoes a move by putting the tuple back under its old TupleId.
+    let result = heap.update_batch(&[(filler, &[4; 3500]), (grown, &[5; PAGE_SIZE])]);
+    assert_eq!(result, Err(BatchError::Failed { failed_at: 1 }));
+    assert_eq!(heap.read_tuple(filler).unwrap(), [1; 3000]);
+    assert_eq!(heap.read_tuple(grown).unwrap(), [2; 2000]);
+    assert_eq!(heap.scan().count(), 2);
+
+    // Once the batch succeeds, the page the move emptied is freed.
+    let moved = heap.update_batch(&[(filler, &[4; 3500])]).unwrap();
+    assert_eq!(heap.read_tuple(moved[0]).unwrap(), [4; 3500]);
+    assert!(!heap.pages().contains(&filler.page_id));
+
+    let _ = std::fs::remove_file(path);
 }
@@ -0,0 +1,103 @@
+// Order-preserving key encodings: comparing the encoded bytes lexicographically,
+// as SortedHeapFile does, gives the numeric order of the values. Integers are
+// stored big-endian with the sign bit flipped so negatives sort first.
+
+pub fn encode_u64(value: u64) -> [u8; 8] {
+    value.to_be_bytes()
+}
+
+pub fn decode_u64(bytes: [u8; 8]) -> u64 {
+    u64::from_be_bytes(bytes)
+}
+
+pub fn encode_i32(value: i32) -> [u8; 4] {
+    ((value as u32) ^ (1 << 31)).to_be_bytes()
+}
+
+pub fn decode_i32(bytes: [u8; 4]) -> i32 {
+    (u32::from_be_bytes(bytes) ^ (1 << 31)) as i32
+}
+
+pub fn encode_i64(value: i64) -> [u8; 8] {
+    ((value as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+pub fn decode_i64(bytes: [u8; 8]) -> i64 {
+    (u64::from_be_bytes(bytes) ^ (1 << 63)) as i64
+}
+
+// Positive floats get their sign bit set; negative floats have all bits
+// flipped, which also reverses the order of their magnitudes. -0.0 sorts just
+// before 0.0, and NaNs sort beyond the infinities according to their sign.
+pub fn encode_f64(value: f64) -> [u8; 8] {
+    let bits = value.to_bits();
+    let ordered = if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits ^ (1 << 63)
+    };
+    ordered.to_be_bytes()
+}
+
+pub fn decode_f64(bytes: [u8; 8]) -> f64 {
+    let ordered = u64::from_be_bytes(bytes);
+    let bits = if ordered >> 63 == 1 {
+        ordered ^ (1 << 63)
+    } else {
+        !ordered
+    };
+    f64::from_bits(bits)
+}
+
+#[test]
+fn signed_key_order_test() {
+    let values = [
+        i64::MIN,
+        -70_000,
+        -256,
+        -1,
+        0,
+        1,
+        255,
+        256,
+        70_000,
+        i64::MAX,
+    ];
+    let mut encoded: Vec<[u8; 8]> = values.iter().rev().map(|&v| encode_i64(v)).collect();
+    encoded.sort();
+    let decoded: Vec<i64> = encoded.into_iter().map(decode_i64).collect();
+    assert_eq!(decoded, values);
+
+    let values = [i32::MIN, -5, -1, 0, 3, i32::MAX];
+    let mut encoded: Vec<[u8; 4]> = values.iter().rev().map(|&v| encode_i32(v)).collect();
+    encoded.sort();
+    let decoded: Vec<i32> = encoded.into_iter().map(decode_i32).collect();
+    assert_eq!(decoded, values);
+
+    assert!(encode_u64(255) < encode_u64(256));
+    assert_eq!(decode_u64(encode_u64(u64::MAX)), u64::MAX);
+}
+
+#[test]
+fn float_key_order_test() {
+    let values = [
+        f64::NEG_INFINITY,
+        -1e300,
+        -2.5,
+        -1e-300,
+        -0.0,
+        0.0,
+        1e-300,
+        2.5,
+        1e300,
+        f64::INFINITY,
+    ];
+    let mut encoded: Vec<[u8; 8]> = values.iter().rev().map(|&v| encode_f64(v)).collect();
+    encoded.sort();
+    let decoded: Vec<u64> = encoded
+        .into_iter()
+        .map(|b| decode_f64(b).to_bits())
+        .collect();
+    let expected: Vec<u64> = values.iter().map(|v| v.to_bits()).collect();
+    assert_eq!(decoded, expected);
+}
@@ -0,0 +1,225 @@
+use crate::buffer_manager::BufferPoolManager;
+use crate::disk_manager::OwnerTag;
+use crate::heap_file::{PageId, TupleId};
+use crate::slotted_page::SlottedPage;
+use std::collections::HashMap;
+
+// Bytes in front of every record holding the length of its key.
+const KEY_LEN_SIZE: usize = 2;
+
+// LogHeapFile::compact could not copy the record of `key`, e.g. because no
+// page could be allocated. The log is left as it was.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactError {
+    pub key: Vec<u8>,
+}
+
+// An append-only heap for write-heavy ingest. Every put appends a new record
+// to the tail page instead of updating in place, and an in-memory map points
+// each key at its latest record. compact rewrites the live records into fresh
+// pages and frees the old ones. Records are [key_len u16][key][value].
+pub struct LogHeapFile {
+    buffer_pool_manager: BufferPoolManager,
+    pages: Vec<PageId>, // Pages in append order, the last one is the tail
+    index: HashMap<Vec<u8>, TupleId>, // key -> latest record
+    owner: OwnerTag,
+}
+
+impl LogHeapFile {
+    pub fn new(buffer_pool_manager: BufferPoolManager) -> Self {
+        let owner = {
+            let mut dm = buffer_pool_manager.disk_manager.lock().unwrap();
+            dm.register_owner()
+        };
+        Self {
+            buffer_pool_manager,
+            pages: Vec::new(),
+            index: HashMap::new(),
+            owner,
+        }
+    }
+
+    // Store a new value for `key`, superseding any earlier one.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Option<TupleId> {
+        let mut record = Vec::with_capacity(KEY_LEN_SIZE + key.len() + value.len());
+        record.extend_from_slice(&u16::try_from(key.len()).ok()?.to_le_bytes());
+        record.extend_from_slice(key);
+        record.extend_from_slice(value);
+        let tid = self.append(&record)?;
+        self.index.insert(key.to_vec(), tid);
+        Some(tid)
+    }
+
+    // Read the latest value stored for `key`.
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let &tid = self.index.get(key)?;
+        let record = self.read_record(tid)?;
+        Some(record[KEY_LEN_SIZE + key.len()..].to_vec())
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    // Copy the latest record of every key into fresh pages and free all old
+    // pages, dropping the superseded versions. Returns how many pages were
+    // freed. If any record cannot be copied, the fresh pages are freed
+    // instead and the old pages and index stay in use.
+    pub fn compact(&mut self) -> Result<usize, CompactError> {
+        let old_pages = std::mem::take(&mut self.pages);
+        let mut keys: Vec<(Vec<u8>, TupleId)> = self
+            .index
+            .iter()
+            .map(|(key, &tid)| (key.clone(), tid))
+            .collect();
+        keys.sort_by_key(|&(_, tid)| (tid.page_id, tid.slot_id.0));
+        let mut index = HashMap::with_capacity(keys.len());
+        for (key, old_tid) in keys {
+            let Some(tid) = self
+                .read_record(old_tid)
+                .and_then(|record| self.append(&record))
+            else {
+                let new_pages = std::mem::replace(&mut self.pages, old_pages);
+                let mut dm = self.buffer_pool_manager.disk_manager.lock().unwrap();
+                for page_id in new_pages {
+                    dm.release_page(page_id, self.owner);
+                }
+                return Err(CompactError { key });
+            };
+            index.insert(key, tid);
+        }
+        self.index = index;
+        let mut dm = self.buffer_pool_manager.disk_manager.lock().unwrap();
+        Ok(old_pages
+            .into_iter()
+            .filter(|&page_id| dm.release_page(page_id, self.owner))
+            .count())
+    }
+
+    // Append a record to the tail page, starting a new tail when it is full.
+    fn append(&mut self, record: &[u8]) -> Option<TupleId> {
+        if let Some(&page_id) = self.pages.last() {
+            let frame = self.buffer_pool_manager.fetch_page(page_id)?;
+            let slot_id = {
+                let mut frame_lock = frame.lock().unwrap();
+                let slot_id = SlottedPage::from_buffer_unchecked(&mut frame_lock.data)
+                    .insert(record)
+                    .ok();
+                if slot_id.is_some() {
+                    frame_lock.is_dirty = true;
+                }
+                slot_id
+            };
+            let _ = self
+                .buffer_pool_manager
+                .unpin_page(page_id, slot_id.is_some());
+            if let Some(slot_id) = slot_id {
+                return Some(TupleId { page_id, slot_id });
+            }
+        }
+        let (page_id, frame) = {
+            let bpm = &self.buffer_pool_manager;
+            let page_id = bpm
+                .disk_manager
+                .lock()
+                .unwrap()
+                .allocate_page_for(self.owner)
+                .ok()?;
+            (page_id, bpm.fetch_page(page_id)?)
+        };
+        let slot_id = {
+            let mut frame_lock = frame.lock().unwrap();
+            frame_lock.is_dirty = true;
+            SlottedPage::init(&mut frame_lock.data).insert(record).ok()
+        };
+        let _ = self.buffer_pool_manager.unpin_page(page_id, true);
+        self.pages.push(page_id);
+        Some(TupleId {
+            page_id,
+            slot_id: slot_id?,
+        })
+    }
+
+    fn read_record(&self, tid: TupleId) -> Option<Vec<u8>> {
+        let frame = self.buffer_pool_manager.fetch_page(tid.page_id)?;
+        let record = {
+            let mut frame_lock = frame.lock().unwrap();
+            let sp = SlottedPage::from_buffer_unchecked(&mut frame_lock.data);
+            sp.read(tid.slot_id).map(|record| record.to_vec())
+        };
+        let _ = self.buffer_pool_manager.unpin_page(tid.page_id, false);
+        record
+    }
+}
+
+#[test]
+fn log_heap_compaction_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("log_heap");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut log = LogHeapFile::new(bpm.clone());
+
+    log.put(b"other", b"untouched").unwrap();
+    for i in 0..500u32 {
+        let value = [&i.to_le_bytes()[..], &[0; 96]].concat();
+        log.put(b"hot", &value).unwrap();
+    }
+    assert_eq!(&log.get(b"hot").unwrap()[..4], 499u32.to_le_bytes());
+    let before = log.page_count();
+    assert!(before > 10);
+
+    let freed = log.compact().unwrap();
+    assert_eq!(freed, before);
+    assert_eq!(log.page_count(), 1);
+    assert_eq!(&log.get(b"hot").unwrap()[..4], 499u32.to_le_bytes());
+    assert_eq!(log.get(b"other").unwrap(), b"untouched");
+    assert_eq!(log.get(b"missing"), None);
+    let free_pages = bpm
+        .disk_manager
+        .lock()
+        .unwrap()
+        .directory()
+        .free_pages()
+        .len();
+    assert_eq!(free_pages, before);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn log_heap_compaction_failure_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("log_heap_compaction_failure");
+    let mut dm = DiskManager::new(&path).unwrap();
+    dm.set_max_pages(4);
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut log = LogHeapFile::new(bpm.clone());
+
+    log.put(b"other", b"untouched").unwrap();
+    let mut last = 0u32;
+    while log
+        .put(b"hot", &[&last.to_le_bytes()[..], &[0; 96]].concat())
+        .is_some()
+    {
+        last += 1;
+    }
+    let before = log.page_count();
+
+    // Every page is taken, so there is nowhere to copy the records to.
+    assert!(log.compact().is_err());
+    assert_eq!(log.page_count(), before);
+    assert_eq!(&log.get(b"hot").unwrap()[..4], (last - 1).to_le_bytes());
+    assert_eq!(log.get(b"other").unwrap(), b"untouched");
+    assert!(bpm
+        .disk_manager
+        .lock()
+        .unwrap()
+        .directory()
+        .free_pages()
+        .is_empty());
+
+    let _ = std::fs::remove_file(path);
+}
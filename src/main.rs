@@ -1,7 +1,25 @@
+// main only demos a few calls; the rest of the storage API is covered by the
+// unit tests, so unused items are only reported in test builds.
+#[cfg_attr(not(test), allow(dead_code))]
 mod buffer_manager;
+#[cfg_attr(not(test), allow(dead_code))]
+mod crc32;
+#[cfg_attr(not(test), allow(dead_code))]
+mod database;
+#[cfg_attr(not(test), allow(dead_code))]
 mod disk_manager;
+#[cfg_attr(not(test), allow(dead_code))]
 mod heap_file;
+#[cfg_attr(not(test), allow(dead_code))]
+mod key;
+#[cfg_attr(not(test), allow(dead_code))]
+mod log_heap_file;
+#[cfg_attr(not(test), allow(dead_code))]
 mod slotted_page;
+#[cfg_attr(not(test), allow(dead_code))]
+mod sorted_heap_file;
+#[cfg_attr(not(test), allow(dead_code))]
+mod tuple_cache;
 use crate::buffer_manager::BufferPoolManager;
 use crate::disk_manager::{DiskManager, Page, PAGE_SIZE};
 use crate::heap_file::HeapFile;
@@ -10,25 +28,26 @@ use crate::slotted_page::{SlotId, SlottedPage};
 // The DiskManager is responsible for reading and writing pages to the database file.
 
 fn main() {
-    let mut disk_manager = DiskManager::new("test.db");
+    let mut disk_manager = DiskManager::new("test.db").unwrap();
     let mut page: Page = [2; PAGE_SIZE];
     let page2: Page = [1; PAGE_SIZE];
-    disk_manager.write_page(0, &page).unwrap();
-    disk_manager.write_page(1, &page2).unwrap();
-    disk_manager.read_page(0, &mut page).unwrap();
+    // Page 0 is the metadata header, so the demo pages start at 1.
+    disk_manager.write_page(1, &page).unwrap();
+    disk_manager.write_page(2, &page2).unwrap();
+    disk_manager.read_page(1, &mut page).unwrap();
     println!("Read page: {:?}", &page[..16]); // Print first 16 bytes for brevity
 
     // BufferPoolManager test
-    let mut buffer_pool_manager = BufferPoolManager::new(2, disk_manager);
-    let frame1 = buffer_pool_manager.fetch_page(0).unwrap();
+    let buffer_pool_manager = BufferPoolManager::new(2, disk_manager);
+    let frame1 = buffer_pool_manager.fetch_page(1).unwrap();
     {
         let frame1_lock = frame1.lock().unwrap();
-        println!("Fetched page 0: {:?}", &frame1_lock.data[..16]); // Print first 16 bytes for brevity
+        println!("Fetched page 1: {:?}", &frame1_lock.data[..16]); // Print first 16 bytes for brevity
     }
-    let frame2 = buffer_pool_manager.fetch_page(1).unwrap();
+    let frame2 = buffer_pool_manager.fetch_page(2).unwrap();
     {
         let frame2_lock = frame2.lock().unwrap();
-        println!("Fetched page 1: {:?}", &frame2_lock.data[..16]); // Print first 16 bytes for brevity
+        println!("Fetched page 2: {:?}", &frame2_lock.data[..16]); // Print first 16 bytes for brevity
     }
 
     let mut page: Page = [0u8; PAGE_SIZE];
@@ -49,7 +68,7 @@ fn main() {
     println!("Read 2: {:?}", std::str::from_utf8(read2).unwrap());
 
     let t3 = b"another tuple";
-    let id3: SlotId = sp.insert(t3).unwrap();
+    let _id3: SlotId = sp.insert(t3).unwrap();
 
     sp.delete(id2);
 
@@ -71,9 +90,10 @@ fn main() {
         );
     }
 
-    let dm = DiskManager::new("test.db");
+    // Release the file lock held by the first pool before reopening.
+    drop(buffer_pool_manager);
+    let dm = DiskManager::new("test.db").unwrap();
     let bpm = BufferPoolManager::new(8, dm);
-    let bpm = std::sync::Arc::new(std::sync::Mutex::new(bpm));
 
     let mut hf = HeapFile::new(bpm.clone());
 
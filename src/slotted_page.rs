@@ -1,10 +1,51 @@
+use crate::crc32::crc32;
 use crate::disk_manager::Page;
-use crate::disk_manager::PAGE_SIZE;
+use crate::disk_manager::{PAGE_SIZE, USABLE_PAGE_SIZE};
+use std::fmt::Write;
+use std::ops::Range;
 pub const INVALID_SLOT: u16 = 0xFFFF;
+/// Set in the length of a slot deleted with delete_retained. INVALID_SLOT
+/// has it set too, so a length with this bit clear is always a live tuple.
+pub const RETAINED_FLAG: u16 = 0x8000;
+/// Bytes of the delete stamp in front of a retained tuple.
+const RETAIN_STAMP_SIZE: usize = 8;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct SlotId(pub u16);
 
+/// Why a tuple could not be stored in or read from a page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlotError {
+    NoSpace,
+    TooManySlots,
+    NotFound,        // no live tuple in that slot
+    TupleCorrupt,    // tuple bytes do not match their stored CRC
+    FreeEndMismatch, // header free_end disagrees with the slot directory
+    OutOfBounds,     // slot entry points outside the tuple area
+}
+
+/// A broken invariant found by SlottedPage::check_invariants or HeapFile::verify.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageError {
+    Uninitialized, // all header fields are zero, e.g. a freshly allocated page
+    TooManySlots,
+    FreeEndMismatch { expected: u16, found: u16 },
+    FreeStartOutOfRange,
+    SlotOutOfBounds(SlotId),
+    SlotsOverlap(SlotId, SlotId),
+    Unreadable,           // the page could not be fetched
+    ShortRecord(SlotId),  // record is shorter than its heap's record prefix
+    DanglingLink(SlotId), // record links to a page outside its heap
+}
+
+/// What SlottedPage::repair had to fix.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    pub slots_truncated: u16,
+    pub free_end_fixed: bool,
+    pub dropped_slots: Vec<SlotId>,
+}
+
 /// SlottedPage: manages variable-length tuples in one page.
 pub struct SlottedPage<'a> {
     buf: &'a mut Page,
@@ -14,25 +55,87 @@ pub struct SlottedPage<'a> {
 /// [0..2): free_start (u16)
 /// [2..4): free_end (u16)
 /// [4..6): num_slots (u16)
+/// [6..7): free space bucket (u8), see free_space_bucket
 const HDR_FREE_START: usize = 0;
 const HDR_FREE_END: usize = 2;
 const HDR_NUM_SLOTS: usize = 4;
-const SLOT_ENTRY_SIZE: usize = 4; // offset(2) + len(2)
+const HDR_FREE_BUCKET: usize = 6;
+const HEADER_SIZE: usize = 7;
+pub const SLOT_ENTRY_SIZE: usize = 4; // offset(2) + len(2)
+/// Bytes of the CRC in front of tuples stored with insert_checked.
+pub const TUPLE_CRC_SIZE: usize = 4;
+/// Bytes of free space per step of the free space bucket.
+pub const FREE_SPACE_BUCKET_BYTES: usize = 16;
+/// Largest tuple an empty page can hold.
+pub const MAX_TUPLE_SIZE: usize = USABLE_PAGE_SIZE - HEADER_SIZE - SLOT_ENTRY_SIZE;
+/// Most slot entries that fit between the header and the page checksum.
+pub const MAX_SLOTS: usize = (USABLE_PAGE_SIZE - HEADER_SIZE) / SLOT_ENTRY_SIZE;
 
 impl<'a> SlottedPage<'a> {
     /// Initialize an empty page
     pub fn init(buf: &'a mut [u8; PAGE_SIZE]) -> Self {
-        let total: u16 = PAGE_SIZE as u16;
-        buf[HDR_FREE_START..HDR_FREE_START + 2].copy_from_slice(&6u16.to_le_bytes()); // store the place where free bytes start in bytes 0-1 (initially 6 (header size))
-        buf[HDR_FREE_END..HDR_FREE_END + 2].copy_from_slice(&total.to_le_bytes()); // store the total page size in bytes 2-3 (initially 4096)
+        let total: u16 = USABLE_PAGE_SIZE as u16;
+        buf[HDR_FREE_START..HDR_FREE_START + 2]
+            .copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes()); // store the place where free bytes start in bytes 0-1 (initially HEADER_SIZE, 7)
+        buf[HDR_FREE_END..HDR_FREE_END + 2].copy_from_slice(&total.to_le_bytes()); // store where free bytes end in bytes 2-3 (initially USABLE_PAGE_SIZE, 4084)
         buf[HDR_NUM_SLOTS..HDR_NUM_SLOTS + 2].copy_from_slice(&0u16.to_le_bytes()); // store number of slots (initially 0) in bytes 4-5
+        buf[HDR_FREE_BUCKET] = Self::bucket_for(USABLE_PAGE_SIZE - HEADER_SIZE);
         Self { buf }
     }
 
-    pub fn from_buffer(buf: &'a mut [u8; PAGE_SIZE]) -> Self {
+    /// Coarse measure of the contiguous free space, cached in the header so
+    /// a free-space map can be rebuilt from one byte per page. Kept up to
+    /// date by every change that moves free_start or free_end.
+    pub fn free_space_bucket(&self) -> u8 {
+        self.buf[HDR_FREE_BUCKET]
+    }
+
+    /// The bucket of `free_bytes` of contiguous free space: its size in units
+    /// of FREE_SPACE_BUCKET_BYTES, rounded down.
+    pub fn bucket_for(free_bytes: usize) -> u8 {
+        (free_bytes / FREE_SPACE_BUCKET_BYTES).min(u8::MAX as usize) as u8
+    }
+
+    fn refresh_free_space_bucket(&mut self) {
+        self.buf[HDR_FREE_BUCKET] = Self::bucket_for(self.largest_contiguous_free());
+    }
+
+    /// Wrap an existing page after checking that its header is plausible.
+    /// Use from_buffer_unchecked for pages already known to be initialized.
+    pub fn from_buffer(buf: &'a mut [u8; PAGE_SIZE]) -> Result<Self, PageError> {
+        let sp = Self { buf };
+        sp.check_header()?;
+        Ok(sp)
+    }
+
+    pub fn from_buffer_unchecked(buf: &'a mut [u8; PAGE_SIZE]) -> Self {
         Self { buf }
     }
 
+    // Cheap sanity check of the three header fields only; check_invariants
+    // also walks the slot directory.
+    fn check_header(&self) -> Result<(), PageError> {
+        let (free_start, free_end) = (self.free_start() as usize, self.free_end() as usize);
+        let num_slots = self.num_slots() as usize;
+        if free_start == 0 && free_end == 0 && num_slots == 0 {
+            return Err(PageError::Uninitialized);
+        }
+        if num_slots > MAX_SLOTS {
+            return Err(PageError::TooManySlots);
+        }
+        let expected_free_end = USABLE_PAGE_SIZE - num_slots * SLOT_ENTRY_SIZE;
+        if free_end > expected_free_end {
+            return Err(PageError::FreeEndMismatch {
+                expected: expected_free_end as u16,
+                found: free_end as u16,
+            });
+        }
+        if free_start < HEADER_SIZE || free_start > free_end {
+            return Err(PageError::FreeStartOutOfRange);
+        }
+        Ok(())
+    }
+
     fn free_start(&self) -> u16 {
         // Read starting place size from bytes 0-1
         u16::from_le_bytes(
@@ -57,9 +160,11 @@ impl<'a> SlottedPage<'a> {
     // these functions are to modify the header fields with new integer values (u16), makes life easier not to deal with byte slices directly
     fn set_free_start(&mut self, val: u16) {
         self.buf[HDR_FREE_START..HDR_FREE_START + 2].copy_from_slice(&val.to_le_bytes());
+        self.refresh_free_space_bucket();
     }
     fn set_free_end(&mut self, val: u16) {
         self.buf[HDR_FREE_END..HDR_FREE_END + 2].copy_from_slice(&val.to_le_bytes());
+        self.refresh_free_space_bucket();
     }
     fn set_num_slots(&mut self, val: u16) {
         self.buf[HDR_NUM_SLOTS..HDR_NUM_SLOTS + 2].copy_from_slice(&val.to_le_bytes());
@@ -69,9 +174,9 @@ impl<'a> SlottedPage<'a> {
     // First two bytes: offset (u16)
     // Next two bytes: length (u16)
     // This metadata is stored at the end of the page and grows backwards
-    // Slot 0 -> 4092-4095, Slot 1 -> 4088-4091, etc.
+    // Slot 0 -> 4080-4083, Slot 1 -> 4076-4079, etc., right below the page LSN
     fn slot_offset(&self, slot_id: u16) -> usize {
-        PAGE_SIZE - ((slot_id as usize + 1) * SLOT_ENTRY_SIZE)
+        USABLE_PAGE_SIZE - ((slot_id as usize + 1) * SLOT_ENTRY_SIZE)
     }
 
     // Read Slot, finds metadata for the given slot_id
@@ -95,43 +200,153 @@ impl<'a> SlottedPage<'a> {
     }
 
     /// Insert a tuple (variable length)
-    pub fn insert(&mut self, tuple: &[u8]) -> Option<SlotId> {
+    pub fn insert(&mut self, tuple: &[u8]) -> Result<SlotId, SlotError> {
+        let slot = self.allocate(tuple.len())?;
+        let (offset, _) = self.read_slot(slot.0);
+        self.buf[offset as usize..offset as usize + tuple.len()].copy_from_slice(tuple);
+        Ok(slot)
+    }
+
+    /// Insert a tuple prefixed with a CRC of its bytes. Read it back with
+    /// read_checked, which catches the tuple being changed in memory or on
+    /// disk behind the page's back.
+    pub fn insert_checked(&mut self, tuple: &[u8]) -> Result<SlotId, SlotError> {
+        let slot = self.allocate(TUPLE_CRC_SIZE + tuple.len())?;
+        let record = self.read_mut(slot).unwrap();
+        record[..TUPLE_CRC_SIZE].copy_from_slice(&crc32(tuple).to_le_bytes());
+        record[TUPLE_CRC_SIZE..].copy_from_slice(tuple);
+        Ok(slot)
+    }
+
+    /// Read a tuple stored with insert_checked, verifying its CRC.
+    pub fn read_checked(&self, slot: SlotId) -> Result<&[u8], SlotError> {
+        let record = &self.buf[self.live_range(slot)?];
+        if record.len() < TUPLE_CRC_SIZE {
+            return Err(SlotError::TupleCorrupt);
+        }
+        let (stored, tuple) = record.split_at(TUPLE_CRC_SIZE);
+        if u32::from_le_bytes(stored.try_into().unwrap()) != crc32(tuple) {
+            return Err(SlotError::TupleCorrupt);
+        }
+        Ok(tuple)
+    }
+
+    /// Claim a live slot with room for `len` bytes before the bytes are known.
+    /// The space reads as zeros until written with fill.
+    pub fn reserve(&mut self, len: usize) -> Option<SlotId> {
+        let slot = self.allocate(len).ok()?;
+        let (offset, _) = self.read_slot(slot.0);
+        self.buf[offset as usize..offset as usize + len].fill(0);
+        Some(slot)
+    }
+
+    /// Write the bytes of a reserved slot. They must be exactly as long as
+    /// the reservation.
+    pub fn fill(&mut self, slot: SlotId, bytes: &[u8]) -> bool {
+        match self.read_mut(slot) {
+            Some(space) if space.len() == bytes.len() => {
+                space.copy_from_slice(bytes);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Take `len` bytes from the free space plus a slot entry pointing at them.
+    // The entry of a deleted tuple is reused if there is one; only otherwise
+    // does the directory grow.
+    fn allocate(&mut self, len: usize) -> Result<SlotId, SlotError> {
+        self.check_free_end()?;
         let num_slots = self.num_slots();
+        if let Some(slot_id) = (0..num_slots).find(|&id| self.read_slot(id).1 == INVALID_SLOT) {
+            let free_start = self.free_start();
+            if free_start as usize + len > self.free_end() as usize {
+                return Err(SlotError::NoSpace);
+            }
+            self.set_free_start(free_start + len as u16);
+            self.write_slot(slot_id, free_start, len as u16);
+            return Ok(SlotId(slot_id));
+        }
+        if num_slots as usize >= MAX_SLOTS {
+            return Err(SlotError::TooManySlots);
+        }
         let free_start = self.free_start();
         let free_end = self.free_end();
-        let need_space = tuple.len() as u16 + SLOT_ENTRY_SIZE as u16;
-
-        if free_start + need_space > free_end {
-            return None; // no space
+        if free_start as usize + len + SLOT_ENTRY_SIZE > free_end as usize {
+            return Err(SlotError::NoSpace);
         }
 
-        // Copy tuple into free space
-        let offset: u16 = free_start;
-        self.buf[offset as usize..offset as usize + tuple.len()].copy_from_slice(tuple);
-
         // Update header
-        self.set_free_start(offset + tuple.len() as u16);
+        let offset: u16 = free_start;
+        self.set_free_start(offset + len as u16);
         self.set_num_slots(num_slots + 1);
         self.set_free_end(free_end - SLOT_ENTRY_SIZE as u16);
 
         // Write slot entry
-        self.write_slot(num_slots, offset, tuple.len() as u16);
-        Some(SlotId(num_slots))
+        self.write_slot(num_slots, offset, len as u16);
+        Ok(SlotId(num_slots))
+    }
+
+    // free_end must sit right below the slot directory, or a write sized by
+    // it can run into the directory. Writes are refused if it does not.
+    fn check_free_end(&self) -> Result<(), SlotError> {
+        let directory_start = USABLE_PAGE_SIZE - self.num_slots() as usize * SLOT_ENTRY_SIZE;
+        if self.free_end() as usize == directory_start {
+            return Ok(());
+        }
+        Err(SlotError::FreeEndMismatch)
     }
 
     /// Read a tuple
     pub fn read(&self, slot: SlotId) -> Option<&[u8]> {
+        Some(&self.buf[self.tuple_range(slot)?])
+    }
+
+    /// Where a live tuple's bytes lie within the page.
+    pub fn tuple_range(&self, slot: SlotId) -> Option<Range<usize>> {
+        self.live_range(slot).ok()
+    }
+
+    // Byte range of a live tuple. An entry reaching into the header, the free
+    // space or the slot directory is refused rather than sliced, since it can
+    // only come from a corrupted directory.
+    fn live_range(&self, slot: SlotId) -> Result<Range<usize>, SlotError> {
         if slot.0 >= self.num_slots() {
-            return None;
+            return Err(SlotError::NotFound);
         }
         let (offset, len) = self.read_slot(slot.0);
-        if len == INVALID_SLOT {
+        if len & RETAINED_FLAG != 0 {
+            return Err(SlotError::NotFound);
+        }
+        let range = offset as usize..offset as usize + len as usize;
+        if range.start < HEADER_SIZE || range.end > self.free_start() as usize {
+            return Err(SlotError::OutOfBounds);
+        }
+        Ok(range)
+    }
+
+    /// Mutable access to a tuple's bytes; the length cannot change
+    pub fn read_mut(&mut self, slot: SlotId) -> Option<&mut [u8]> {
+        let range = self.live_range(slot).ok()?;
+        Some(&mut self.buf[range])
+    }
+
+    /// The raw (offset, len) directory entry of a slot, for tools that check
+    /// the physical layout. len is INVALID_SLOT for a tombstone and has
+    /// RETAINED_FLAG set for a retained delete. None if the slot does not exist.
+    pub fn slot_entry(&self, slot: SlotId) -> Option<(u16, u16)> {
+        if slot.0 >= self.num_slots() {
             return None;
         }
-        Some(&self.buf[offset as usize..offset as usize + len as usize])
+        Some(self.read_slot(slot.0))
     }
 
     // Tuple Iterator
+    /// Number of slot entries, live or deleted.
+    pub fn slot_count(&self) -> u16 {
+        self.num_slots()
+    }
+
     pub fn iter(&self) -> SlottedPageIterator<'_> {
         SlottedPageIterator {
             sp: self,
@@ -144,7 +359,7 @@ impl<'a> SlottedPage<'a> {
         let num_slots = self.num_slots();
         let mut tuples: Vec<(u16, u16, u16)> = Vec::new(); // (slot_id, offset, len)
 
-        // Collect valid tuples
+        // Collect valid tuples, retained ones included
         for slot_id in 0..num_slots {
             let (offset, len) = self.read_slot(slot_id);
             if len != INVALID_SLOT {
@@ -156,8 +371,9 @@ impl<'a> SlottedPage<'a> {
         tuples.sort_by_key(|&(_, offset, _)| offset);
 
         // Rebuild the page with keeping slot ids the same
-        let mut new_free_start: u16 = 6; // header size
-        for (i, &(slot_id, old_offset, len)) in tuples.iter().enumerate() {
+        let mut new_free_start: u16 = HEADER_SIZE as u16;
+        for &(slot_id, old_offset, slot_len) in tuples.iter() {
+            let len = slot_len & !RETAINED_FLAG;
             // Move tuple to new location
             let slice: Vec<u8> =
                 self.buf[old_offset as usize..old_offset as usize + len as usize].to_vec();
@@ -165,36 +381,225 @@ impl<'a> SlottedPage<'a> {
             self.buf[new_free_start as usize..new_free_start as usize + len as usize]
                 .copy_from_slice(&slice);
             // Update slot entry
-            self.write_slot(slot_id as u16, new_free_start, len);
+            self.write_slot(slot_id, new_free_start, slot_len);
             new_free_start += len;
         }
 
-        // Update header
+        // Update header. Tombstoned slots keep their directory entries, so
+        // free_end does not move.
         self.set_free_start(new_free_start);
-        self.set_free_end(
-            PAGE_SIZE as u16 - (num_slots - tuples.len() as u16) * SLOT_ENTRY_SIZE as u16,
-        );
+        self.set_free_end(USABLE_PAGE_SIZE as u16 - num_slots * SLOT_ENTRY_SIZE as u16);
+    }
+
+    /// Compact, then zero the free region so the bytes of deleted tuples do
+    /// not linger in the page.
+    pub fn compact_secure(&mut self) {
+        self.compact();
+        let (free_start, free_end) = (self.free_start() as usize, self.free_end() as usize);
+        self.buf[free_start..free_end].fill(0);
+    }
+
+    /// Rewrite the page into a canonical form: the live tuples packed in slot
+    /// order, renumbered from 0 with no tombstones, and every other byte
+    /// zeroed. Pages with the same live tuples in the same order end up
+    /// byte-identical up to the page trailer, which is left alone. Slot ids
+    /// change and retained tuples are dropped.
+    pub fn normalize(&mut self) {
+        let tuples: Vec<Vec<u8>> = self.iter().map(|(_, tuple)| tuple.to_vec()).collect();
+        self.buf[..USABLE_PAGE_SIZE].fill(0);
+        self.set_num_slots(0);
+        self.set_free_start(HEADER_SIZE as u16);
+        self.set_free_end(USABLE_PAGE_SIZE as u16);
+        for tuple in &tuples {
+            self.insert(tuple)
+                .expect("live tuples fit once holes are gone");
+        }
+    }
+
+    /// Check that the header and the slot directory agree with each other
+    pub fn check_invariants(&self) -> Result<(), PageError> {
+        let num_slots = self.num_slots() as usize;
+        if num_slots > MAX_SLOTS {
+            return Err(PageError::TooManySlots);
+        }
+        let expected_free_end = (USABLE_PAGE_SIZE - num_slots * SLOT_ENTRY_SIZE) as u16;
+        if self.free_end() != expected_free_end {
+            return Err(PageError::FreeEndMismatch {
+                expected: expected_free_end,
+                found: self.free_end(),
+            });
+        }
+        let free_start = self.free_start();
+        if (free_start as usize) < HEADER_SIZE || free_start > expected_free_end {
+            return Err(PageError::FreeStartOutOfRange);
+        }
+        // Every live tuple must lie in the data area, without overlapping another.
+        let mut ranges: Vec<(u16, u16, SlotId)> = Vec::new(); // (start, end, slot)
+        for slot_id in 0..num_slots as u16 {
+            let (offset, len) = self.read_slot(slot_id);
+            if len == INVALID_SLOT {
+                continue;
+            }
+            let len = len & !RETAINED_FLAG;
+            if (offset as usize) < HEADER_SIZE
+                || offset as usize + len as usize > free_start as usize
+            {
+                return Err(PageError::SlotOutOfBounds(SlotId(slot_id)));
+            }
+            ranges.push((offset, offset + len, SlotId(slot_id)));
+        }
+        ranges.sort_by_key(|&(start, _, _)| start);
+        for pair in ranges.windows(2) {
+            if pair[1].0 < pair[0].1 {
+                return Err(PageError::SlotsOverlap(pair[0].2, pair[1].2));
+            }
+        }
+        Ok(())
+    }
+
+    /// Heal a page whose header disagrees with its slot directory: recompute
+    /// free_end from num_slots, drop slots pointing outside the data area and
+    /// compact. Meant for migrating pages written before compact kept free_end
+    /// in sync.
+    pub fn repair(&mut self) -> RepairReport {
+        let mut report = RepairReport::default();
+        if self.num_slots() as usize > MAX_SLOTS {
+            report.slots_truncated = self.num_slots() - MAX_SLOTS as u16;
+            self.set_num_slots(MAX_SLOTS as u16);
+        }
+        let num_slots = self.num_slots();
+        let free_end = USABLE_PAGE_SIZE as u16 - num_slots * SLOT_ENTRY_SIZE as u16;
+        if self.free_end() != free_end {
+            report.free_end_fixed = true;
+            self.set_free_end(free_end);
+        }
+        for slot_id in 0..num_slots {
+            let (offset, len) = self.read_slot(slot_id);
+            if len == INVALID_SLOT {
+                continue;
+            }
+            let len = len & !RETAINED_FLAG;
+            if (offset as usize) < HEADER_SIZE || offset as usize + len as usize > free_end as usize
+            {
+                self.write_slot(slot_id, offset, INVALID_SLOT);
+                report.dropped_slots.push(SlotId(slot_id));
+            }
+        }
+        self.compact();
+        report
+    }
+
+    /// Undo an insert that a crash cut short: drop trailing slot entries that
+    /// do not point at a tuple below free_start, e.g. because the header was
+    /// bumped but the entry never written, and move free_end back in line
+    /// with the directory. Returns whether anything changed.
+    pub fn sync_directory(&mut self) -> bool {
+        let mut num_slots = self.num_slots().min(MAX_SLOTS as u16);
+        while num_slots > 0 {
+            let (offset, len) = self.read_slot(num_slots - 1);
+            let end = offset as usize + (len & !RETAINED_FLAG) as usize;
+            let consistent = len == INVALID_SLOT
+                || (offset as usize >= HEADER_SIZE && end <= self.free_start() as usize);
+            if consistent {
+                break;
+            }
+            num_slots -= 1;
+        }
+        let free_end = (USABLE_PAGE_SIZE - num_slots as usize * SLOT_ENTRY_SIZE) as u16;
+        let changed = num_slots != self.num_slots() || free_end != self.free_end();
+        self.set_num_slots(num_slots);
+        self.set_free_end(free_end);
+        changed
+    }
+
+    /// Shrink the directory past trailing tombstones, e.g. after a vacuum
+    /// deleted the newest tuples. Stops at the last live or retained slot and
+    /// returns how many entries were dropped.
+    pub fn truncate_trailing_tombstones(&mut self) -> u16 {
+        let old = self.num_slots();
+        let mut num_slots = old;
+        while num_slots > 0 && self.read_slot(num_slots - 1).1 == INVALID_SLOT {
+            num_slots -= 1;
+        }
+        self.set_num_slots(num_slots);
+        self.set_free_end((USABLE_PAGE_SIZE - num_slots as usize * SLOT_ENTRY_SIZE) as u16);
+        old - num_slots
+    }
+
+    /// Human readable dump of the header and every slot entry, with a short
+    /// preview of each live tuple (as text if printable, hex otherwise).
+    pub fn dump(&self) -> String {
+        const PREVIEW_LEN: usize = 16;
+        let mut out = String::new();
+        writeln!(
+            out,
+            "free_start={} free_end={} num_slots={}",
+            self.free_start(),
+            self.free_end(),
+            self.num_slots()
+        )
+        .unwrap();
+        for slot_id in 0..self.num_slots() {
+            let (offset, len) = self.read_slot(slot_id);
+            if len == INVALID_SLOT {
+                writeln!(out, "slot {}: offset={} dead", slot_id, offset).unwrap();
+                continue;
+            }
+            if let Some(stamp) = self.retained_stamp(slot_id) {
+                let len = len & !RETAINED_FLAG;
+                writeln!(
+                    out,
+                    "slot {}: offset={} len={} retained stamp={}",
+                    slot_id, offset, len, stamp
+                )
+                .unwrap();
+                continue;
+            }
+            let start = (offset as usize).min(PAGE_SIZE);
+            let end = (offset as usize + len as usize).min(PAGE_SIZE);
+            let bytes = &self.buf[start..end.min(start + PREVIEW_LEN)];
+            let preview = match std::str::from_utf8(bytes) {
+                Ok(text) if !text.chars().any(|c| c.is_control()) => format!("{:?}", text),
+                _ => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+            };
+            let ellipsis = if end - start > PREVIEW_LEN { "..." } else { "" };
+            writeln!(
+                out,
+                "slot {}: offset={} len={} live {}{}",
+                slot_id, offset, len, preview, ellipsis
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    /// Bytes compaction would reclaim: everything below free_start that is
+    /// not part of a live or retained tuple, i.e. the bytes of deleted tuples
+    /// and the slack left by in-place updates that shrank a tuple.
+    pub fn dead_space(&self) -> usize {
+        let used: usize = (0..self.num_slots())
+            .map(|slot_id| self.read_slot(slot_id).1)
+            .filter(|&len| len != INVALID_SLOT)
+            .map(|len| (len & !RETAINED_FLAG) as usize)
+            .sum();
+        (self.free_start() as usize).saturating_sub(HEADER_SIZE + used)
     }
 
     pub fn largest_contiguous_free(&self) -> usize {
         let free_start = self.free_start() as usize;
         let free_end = self.free_end() as usize;
-        if free_end >= free_start {
-            free_end - free_start
-        } else {
-            0
-        }
+        free_end.saturating_sub(free_start)
     }
 
     // Update
     // If new tuple size is less than or equal to old size, do in-place update
     // If new tuple size is greater, call delete + insert
     pub fn update(&mut self, slot: SlotId, new_tuple: &[u8]) -> bool {
-        if slot.0 >= self.num_slots() {
+        if slot.0 >= self.num_slots() || self.check_free_end().is_err() {
             return false;
         }
         let (offset, len) = self.read_slot(slot.0);
-        if len == INVALID_SLOT {
+        if len & RETAINED_FLAG != 0 {
             return false;
         }
         if new_tuple.len() as u16 <= len {
@@ -217,6 +622,12 @@ impl<'a> SlottedPage<'a> {
         let new_off = self.free_start();
         let new_len = new_tuple.len() as u16;
         let dst = new_off as usize;
+        // Never write past free_end or into the slot directory, even if the
+        // header claims more room than there is.
+        let directory_start = self.slot_offset(self.num_slots() - 1);
+        if dst + new_tuple.len() > (self.free_end() as usize).min(directory_start) {
+            return false;
+        }
         self.buf[dst..dst + new_tuple.len()].copy_from_slice(new_tuple);
         self.set_free_start(new_off + new_len);
 
@@ -224,7 +635,7 @@ impl<'a> SlottedPage<'a> {
         self.write_slot(slot.0, new_off, new_len);
 
         // Old region [off..off+len] becomes a hole; compact() will reclaim later.
-        return true;
+        true
     }
 
     // Delete a tuple
@@ -235,7 +646,7 @@ impl<'a> SlottedPage<'a> {
         }
         // get slot metadata
         let (offset, len) = self.read_slot(slot.0);
-        if len == INVALID_SLOT {
+        if len & RETAINED_FLAG != 0 {
             // Already deleted
             return false;
         }
@@ -243,6 +654,115 @@ impl<'a> SlottedPage<'a> {
         self.write_slot(slot.0, offset, INVALID_SLOT);
         true
     }
+
+    /// Exchange the directory entries of two live slots, so each slot id now
+    /// names the other's tuple. The tuple bytes do not move.
+    pub fn swap_slots(&mut self, a: SlotId, b: SlotId) -> bool {
+        let (Some(_), Some(_)) = (self.tuple_range(a), self.tuple_range(b)) else {
+            return false;
+        };
+        let (a_offset, a_len) = self.read_slot(a.0);
+        let (b_offset, b_len) = self.read_slot(b.0);
+        self.write_slot(a.0, b_offset, b_len);
+        self.write_slot(b.0, a_offset, a_len);
+        true
+    }
+
+    /// Delete a tuple but keep its bytes, tagged with `stamp`, so that
+    /// undelete can bring it back. Reads skip it like any deleted tuple. The
+    /// bytes are only reclaimed by a gc with a horizon past `stamp`. Returns
+    /// false if there is no live tuple or no room for the stamp.
+    pub fn delete_retained(&mut self, slot: SlotId, stamp: u64) -> bool {
+        let Some(range) = self.tuple_range(slot) else {
+            return false;
+        };
+        let len = RETAIN_STAMP_SIZE + range.len();
+        if self.largest_contiguous_free() < len {
+            self.compact();
+            if self.largest_contiguous_free() < len {
+                return false;
+            }
+        }
+        if self.check_free_end().is_err() {
+            return false;
+        }
+        // compact may have moved the tuple.
+        let tuple = self.read(slot).unwrap().to_vec();
+        let offset = self.free_start() as usize;
+        self.buf[offset..offset + RETAIN_STAMP_SIZE].copy_from_slice(&stamp.to_le_bytes());
+        self.buf[offset + RETAIN_STAMP_SIZE..offset + len].copy_from_slice(&tuple);
+        self.set_free_start((offset + len) as u16);
+        self.write_slot(slot.0, offset as u16, len as u16 | RETAINED_FLAG);
+        true
+    }
+
+    /// Revive a tuple deleted with delete_retained.
+    pub fn undelete(&mut self, slot: SlotId) -> bool {
+        if self.retained_stamp(slot.0).is_none() {
+            return false;
+        }
+        let (offset, len) = self.read_slot(slot.0);
+        let len = (len & !RETAINED_FLAG) - RETAIN_STAMP_SIZE as u16;
+        // The stamp is left behind as a hole for compact.
+        self.write_slot(slot.0, offset + RETAIN_STAMP_SIZE as u16, len);
+        true
+    }
+
+    /// Insert a tuple into one particular deleted slot, e.g. to put back a
+    /// tuple under the SlotId it had. Compacts if that makes room. Fails if
+    /// the slot is not a tombstone or the tuple does not fit.
+    pub fn insert_at(&mut self, slot: SlotId, tuple: &[u8]) -> bool {
+        if slot.0 >= self.num_slots() || self.read_slot(slot.0).1 != INVALID_SLOT {
+            return false;
+        }
+        if self.largest_contiguous_free() < tuple.len() {
+            self.compact();
+            if self.largest_contiguous_free() < tuple.len() {
+                return false;
+            }
+        }
+        let offset = self.free_start();
+        self.buf[offset as usize..offset as usize + tuple.len()].copy_from_slice(tuple);
+        self.set_free_start(offset + tuple.len() as u16);
+        self.write_slot(slot.0, offset, tuple.len() as u16);
+        true
+    }
+
+    /// Drop every retained tuple stamped before `horizon` for good and compact
+    /// the page. Returns how many were dropped.
+    pub fn gc(&mut self, horizon: u64) -> u16 {
+        let mut dropped = 0;
+        for slot_id in 0..self.num_slots() {
+            if self
+                .retained_stamp(slot_id)
+                .is_some_and(|stamp| stamp < horizon)
+            {
+                let (offset, _) = self.read_slot(slot_id);
+                self.write_slot(slot_id, offset, INVALID_SLOT);
+                dropped += 1;
+            }
+        }
+        self.compact();
+        dropped
+    }
+
+    // Delete stamp of a slot deleted with delete_retained. None if the entry
+    // points too close to the end of the page to hold a stamp.
+    fn retained_stamp(&self, slot_id: u16) -> Option<u64> {
+        if slot_id >= self.num_slots() {
+            return None;
+        }
+        let (offset, len) = self.read_slot(slot_id);
+        if len == INVALID_SLOT || len & RETAINED_FLAG == 0 {
+            return None;
+        }
+        let offset = offset as usize;
+        if offset + RETAIN_STAMP_SIZE > USABLE_PAGE_SIZE {
+            return None;
+        }
+        let stamp = &self.buf[offset..offset + RETAIN_STAMP_SIZE];
+        Some(u64::from_le_bytes(stamp.try_into().unwrap()))
+    }
 }
 
 pub struct SlottedPageIterator<'a> {
@@ -257,14 +777,709 @@ impl<'a> Iterator for SlottedPageIterator<'a> {
         while self.current_slot < self.sp.num_slots() {
             let slot_id = self.current_slot;
             self.current_slot += 1;
-            let (offset, len) = self.sp.read_slot(slot_id);
-            if len != INVALID_SLOT {
-                let data = &self.sp.buf[offset as usize..offset as usize + len as usize];
+            if let Some(data) = self.sp.read(SlotId(slot_id)) {
                 return Some((SlotId(slot_id), data));
-            } else {
-                continue;
             }
         }
         None
     }
 }
+
+#[test]
+fn max_slots_test() {
+    let mut page: Page = [0; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut page);
+    for i in 0..MAX_SLOTS {
+        assert_eq!(sp.insert(b""), Ok(SlotId(i as u16)));
+    }
+    assert_eq!(sp.insert(b""), Err(SlotError::TooManySlots));
+    // Running out of bytes is reported differently.
+    let mut page: Page = [0; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut page);
+    assert_eq!(sp.insert(&[0; PAGE_SIZE]), Err(SlotError::NoSpace));
+}
+
+#[test]
+fn compact_keeps_invariants_test() {
+    let mut page: Page = [0; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut page);
+    let ids: Vec<SlotId> = (0..5u8).map(|i| sp.insert(&[i; 10]).unwrap()).collect();
+    sp.delete(ids[1]);
+    sp.delete(ids[3]);
+    sp.compact();
+    assert_eq!(sp.check_invariants(), Ok(()));
+    assert_eq!(sp.read(ids[4]).unwrap(), &[4; 10]);
+}
+
+#[test]
+fn repair_test() {
+    let mut page: Page = [0; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut page);
+    let ids: Vec<SlotId> = (0..4u8).map(|i| sp.insert(&[i; 10]).unwrap()).collect();
+    sp.delete(ids[1]);
+    // What the old compact left behind: free_end only counting live slots.
+    sp.set_free_end(USABLE_PAGE_SIZE as u16 - 3 * SLOT_ENTRY_SIZE as u16);
+    // And a slot pointing into the directory.
+    sp.write_slot(ids[2].0, USABLE_PAGE_SIZE as u16 - 8, 6);
+    assert_eq!(
+        sp.check_invariants(),
+        Err(PageError::FreeEndMismatch {
+            expected: USABLE_PAGE_SIZE as u16 - 16,
+            found: USABLE_PAGE_SIZE as u16 - 12,
+        })
+    );
+
+    let report = sp.repair();
+    assert!(report.free_end_fixed);
+    assert_eq!(report.dropped_slots, vec![ids[2]]);
+    assert_eq!(sp.check_invariants(), Ok(()));
+    assert_eq!(sp.read(ids[0]).unwrap(), &[0; 10]);
+    assert_eq!(sp.read(ids[2]), None);
+    assert_eq!(sp.read(ids[3]).unwrap(), &[3; 10]);
+    assert_eq!(sp.repair(), RepairReport::default());
+}
+
+#[test]
+fn dump_test() {
+    let mut page: Page = [0; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut page);
+    sp.insert(b"hello world").unwrap();
+    sp.insert(&[0x00, 0xff]).unwrap();
+    let dead = sp.insert(b"gone").unwrap();
+    sp.delete(dead);
+
+    let dump = sp.dump();
+    assert!(dump.contains("free_start=24 free_end=4072 num_slots=3"));
+    assert!(dump.contains("slot 0: offset=7 len=11 live \"hello world\""));
+    assert!(dump.contains("slot 1: offset=18 len=2 live 00ff"));
+    assert!(dump.contains("slot 2: offset=20 dead"));
+}
+
+/// FixedSlottedPage: manages tuples that all have the same length in one page.
+/// The tuple length is stored once in the header and the slot directory is just
+/// a liveness bitmap, so a slot's offset is computed instead of stored.
+pub struct FixedSlottedPage<'a> {
+    buf: &'a mut Page,
+}
+
+/// Header layout
+/// [0..2): tuple_len (u16)
+/// [2..4): num_slots (u16), slots handed out so far
+/// followed by the liveness bitmap (one bit per slot) and then the tuple data
+const FIXED_HDR_TUPLE_LEN: usize = 0;
+const FIXED_HDR_NUM_SLOTS: usize = 2;
+const FIXED_HDR_SIZE: usize = 4;
+
+impl<'a> FixedSlottedPage<'a> {
+    /// Initialize an empty page holding tuples of exactly `tuple_len` bytes
+    pub fn init(buf: &'a mut Page, tuple_len: usize) -> Self {
+        assert!(tuple_len > 0 && tuple_len < USABLE_PAGE_SIZE - FIXED_HDR_SIZE);
+        buf.fill(0);
+        buf[FIXED_HDR_TUPLE_LEN..FIXED_HDR_TUPLE_LEN + 2]
+            .copy_from_slice(&(tuple_len as u16).to_le_bytes());
+        buf[FIXED_HDR_NUM_SLOTS..FIXED_HDR_NUM_SLOTS + 2].copy_from_slice(&0u16.to_le_bytes());
+        Self { buf }
+    }
+
+    #[allow(dead_code)]
+    pub fn from_buffer(buf: &'a mut Page) -> Self {
+        Self { buf }
+    }
+
+    pub fn tuple_len(&self) -> usize {
+        u16::from_le_bytes(
+            self.buf[FIXED_HDR_TUPLE_LEN..FIXED_HDR_TUPLE_LEN + 2]
+                .try_into()
+                .unwrap(),
+        ) as usize
+    }
+
+    fn num_slots(&self) -> u16 {
+        u16::from_le_bytes(
+            self.buf[FIXED_HDR_NUM_SLOTS..FIXED_HDR_NUM_SLOTS + 2]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    fn set_num_slots(&mut self, val: u16) {
+        self.buf[FIXED_HDR_NUM_SLOTS..FIXED_HDR_NUM_SLOTS + 2].copy_from_slice(&val.to_le_bytes());
+    }
+
+    /// Number of tuples the page can hold: every slot costs tuple_len bytes
+    /// plus one bit in the bitmap.
+    pub fn capacity(&self) -> usize {
+        ((USABLE_PAGE_SIZE - FIXED_HDR_SIZE) * 8) / (self.tuple_len() * 8 + 1)
+    }
+
+    fn data_start(&self) -> usize {
+        FIXED_HDR_SIZE + self.capacity().div_ceil(8)
+    }
+
+    fn tuple_offset(&self, slot_id: u16) -> usize {
+        self.data_start() + slot_id as usize * self.tuple_len()
+    }
+
+    fn is_live(&self, slot_id: u16) -> bool {
+        let byte = self.buf[FIXED_HDR_SIZE + slot_id as usize / 8];
+        byte & (1 << (slot_id % 8)) != 0
+    }
+
+    fn set_live(&mut self, slot_id: u16, live: bool) {
+        let idx = FIXED_HDR_SIZE + slot_id as usize / 8;
+        if live {
+            self.buf[idx] |= 1 << (slot_id % 8);
+        } else {
+            self.buf[idx] &= !(1 << (slot_id % 8));
+        }
+    }
+
+    /// Bytes still available for new tuples
+    pub fn free_space(&self) -> usize {
+        (self.capacity() - self.num_slots() as usize) * self.tuple_len()
+    }
+
+    /// Insert a tuple, which must be exactly tuple_len bytes long
+    pub fn insert(&mut self, tuple: &[u8]) -> Option<SlotId> {
+        if tuple.len() != self.tuple_len() {
+            return None; // wrong width
+        }
+        let slot_id = self.num_slots();
+        if slot_id as usize >= self.capacity() {
+            return None; // no space
+        }
+        let offset = self.tuple_offset(slot_id);
+        self.buf[offset..offset + tuple.len()].copy_from_slice(tuple);
+        self.set_live(slot_id, true);
+        self.set_num_slots(slot_id + 1);
+        Some(SlotId(slot_id))
+    }
+
+    /// Read a tuple
+    pub fn read(&self, slot: SlotId) -> Option<&[u8]> {
+        if slot.0 >= self.num_slots() || !self.is_live(slot.0) {
+            return None;
+        }
+        let offset = self.tuple_offset(slot.0);
+        Some(&self.buf[offset..offset + self.tuple_len()])
+    }
+
+    // Delete a tuple
+    pub fn delete(&mut self, slot: SlotId) -> bool {
+        if slot.0 >= self.num_slots() || !self.is_live(slot.0) {
+            return false;
+        }
+        self.set_live(slot.0, false);
+        true
+    }
+
+    // Tuple Iterator
+    pub fn iter(&self) -> impl Iterator<Item = (SlotId, &[u8])> + '_ {
+        (0..self.num_slots()).filter_map(move |slot_id| {
+            self.read(SlotId(slot_id))
+                .map(|tuple| (SlotId(slot_id), tuple))
+        })
+    }
+}
+
+#[test]
+fn fixed_slotted_page_test() {
+    let row = [7u8; 16];
+    let mut fixed_buf: Page = [0; PAGE_SIZE];
+    let mut var_buf: Page = [0; PAGE_SIZE];
+    let mut fixed = FixedSlottedPage::init(&mut fixed_buf, row.len());
+    let mut var = SlottedPage::init(&mut var_buf);
+
+    let mut rows = 0;
+    while var.insert(&row).is_ok() {
+        assert_eq!(fixed.insert(&row), Some(SlotId(rows)));
+        rows += 1;
+    }
+    // Same data, but the fixed layout has no per-slot directory entries.
+    assert!(fixed.free_space() > var.largest_contiguous_free());
+    assert!(fixed.capacity() > rows as usize);
+
+    assert_eq!(fixed.insert(b"too short"), None);
+    assert_eq!(fixed.read(SlotId(3)), Some(&row[..]));
+    assert!(fixed.delete(SlotId(3)));
+    assert_eq!(fixed.read(SlotId(3)), None);
+    assert_eq!(fixed.iter().count(), rows as usize - 1);
+}
+
+/// PackedPage: rows of one fixed width packed back to back for scan-heavy
+/// micro-batches. There is no directory and no liveness bitmap, so rows can
+/// only be appended; row i lives at a computed offset.
+pub struct PackedPage<'a> {
+    buf: &'a mut Page,
+}
+
+/// Header layout
+/// [0..2): row_width (u16)
+/// [2..4): row_count (u16)
+/// followed by the rows
+const PACKED_HDR_ROW_WIDTH: usize = 0;
+const PACKED_HDR_ROW_COUNT: usize = 2;
+const PACKED_HDR_SIZE: usize = 4;
+
+impl<'a> PackedPage<'a> {
+    /// Initialize an empty page holding rows of exactly `row_width` bytes
+    pub fn init(buf: &'a mut Page, row_width: usize) -> Self {
+        assert!(row_width > 0 && row_width <= USABLE_PAGE_SIZE - PACKED_HDR_SIZE);
+        buf[PACKED_HDR_ROW_WIDTH..PACKED_HDR_ROW_WIDTH + 2]
+            .copy_from_slice(&(row_width as u16).to_le_bytes());
+        buf[PACKED_HDR_ROW_COUNT..PACKED_HDR_ROW_COUNT + 2].copy_from_slice(&0u16.to_le_bytes());
+        Self { buf }
+    }
+
+    #[allow(dead_code)]
+    pub fn from_buffer(buf: &'a mut Page) -> Self {
+        Self { buf }
+    }
+
+    pub fn row_width(&self) -> usize {
+        u16::from_le_bytes(
+            self.buf[PACKED_HDR_ROW_WIDTH..PACKED_HDR_ROW_WIDTH + 2]
+                .try_into()
+                .unwrap(),
+        ) as usize
+    }
+
+    pub fn len(&self) -> usize {
+        u16::from_le_bytes(
+            self.buf[PACKED_HDR_ROW_COUNT..PACKED_HDR_ROW_COUNT + 2]
+                .try_into()
+                .unwrap(),
+        ) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of rows the page can hold
+    pub fn capacity(&self) -> usize {
+        (USABLE_PAGE_SIZE - PACKED_HDR_SIZE) / self.row_width()
+    }
+
+    /// Append a row, which must be exactly row_width bytes long. Returns its
+    /// index, or None if the width is wrong or the page is full.
+    pub fn push(&mut self, row: &[u8]) -> Option<usize> {
+        let idx = self.len();
+        if row.len() != self.row_width() || idx >= self.capacity() {
+            return None;
+        }
+        let offset = PACKED_HDR_SIZE + idx * row.len();
+        self.buf[offset..offset + row.len()].copy_from_slice(row);
+        self.buf[PACKED_HDR_ROW_COUNT..PACKED_HDR_ROW_COUNT + 2]
+            .copy_from_slice(&(idx as u16 + 1).to_le_bytes());
+        Some(idx)
+    }
+
+    /// Read row `i`
+    pub fn get(&self, i: usize) -> Option<&[u8]> {
+        if i >= self.len() {
+            return None;
+        }
+        let width = self.row_width();
+        let offset = PACKED_HDR_SIZE + i * width;
+        Some(&self.buf[offset..offset + width])
+    }
+
+    /// Rows in the order they were pushed
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.buf[PACKED_HDR_SIZE..PACKED_HDR_SIZE + self.len() * self.row_width()]
+            .chunks_exact(self.row_width())
+    }
+}
+
+#[test]
+fn packed_page_test() {
+    let mut buf: Page = [0; PAGE_SIZE];
+    let mut packed = PackedPage::init(&mut buf, 2);
+    let mut rows = 0u16;
+    while packed.push(&rows.to_le_bytes()).is_some() {
+        rows += 1;
+    }
+    // Every byte past the header holds row data.
+    assert_eq!(rows as usize, packed.capacity());
+    assert_eq!(rows as usize, (USABLE_PAGE_SIZE - PACKED_HDR_SIZE) / 2);
+    assert_eq!(packed.len(), rows as usize);
+
+    for i in [0, 1, 777, rows as usize - 1] {
+        assert_eq!(packed.get(i), Some(&(i as u16).to_le_bytes()[..]));
+    }
+    assert_eq!(packed.get(rows as usize), None);
+    assert!(packed
+        .iter()
+        .enumerate()
+        .all(|(i, row)| row == (i as u16).to_le_bytes()));
+
+    let mut buf: Page = [0; PAGE_SIZE];
+    let mut packed = PackedPage::init(&mut buf, 8);
+    assert!(packed.is_empty());
+    assert_eq!(packed.push(b"short"), None);
+    assert_eq!(packed.push(b"8 bytes!"), Some(0));
+}
+
+#[test]
+fn from_buffer_checked_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    assert_eq!(
+        SlottedPage::from_buffer(&mut buf).err(),
+        Some(PageError::Uninitialized)
+    );
+
+    SlottedPage::init(&mut buf).insert(b"abc").unwrap();
+    let sp = SlottedPage::from_buffer(&mut buf).unwrap();
+    assert_eq!(sp.read(SlotId(0)), Some(&b"abc"[..]));
+
+    // free_start pointing into the header is rejected too.
+    buf[HDR_FREE_START..HDR_FREE_START + 2].copy_from_slice(&2u16.to_le_bytes());
+    assert_eq!(
+        SlottedPage::from_buffer(&mut buf).err(),
+        Some(PageError::FreeStartOutOfRange)
+    );
+}
+
+#[test]
+fn reserve_fill_test() {
+    let mut buf = [0xaa; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    sp.insert(b"before").unwrap();
+    let slot = sp.reserve(5).unwrap();
+    assert_eq!(sp.read(slot), Some(&[0u8; 5][..]));
+    // Later inserts must not land in the reserved space.
+    let after = sp.insert(b"after").unwrap();
+
+    assert!(!sp.fill(slot, b"too long"));
+    assert!(sp.fill(slot, b"hello"));
+    assert_eq!(sp.read(slot), Some(&b"hello"[..]));
+    assert_eq!(sp.read(after), Some(&b"after"[..]));
+    assert!(sp.check_invariants().is_ok());
+
+    assert_eq!(sp.reserve(PAGE_SIZE), None);
+}
+
+#[test]
+fn tuple_crc_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let slot = sp.insert_checked(b"checked tuple").unwrap();
+    assert_eq!(sp.read_checked(slot), Ok(&b"checked tuple"[..]));
+    assert_eq!(sp.read_checked(SlotId(5)), Err(SlotError::NotFound));
+
+    // Flip a bit inside the tuple, as a stray write into the frame would.
+    let (offset, _) = sp.read_slot(slot.0);
+    sp.buf[offset as usize + TUPLE_CRC_SIZE + 3] ^= 0x10;
+    assert_eq!(sp.read_checked(slot), Err(SlotError::TupleCorrupt));
+}
+
+#[test]
+fn update_relocate_bounds_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let slot = sp.insert(&[1; 8]).unwrap();
+    let filler = sp.largest_contiguous_free() - SLOT_ENTRY_SIZE - 16;
+    sp.insert(&vec![2; filler]).unwrap();
+
+    // A stale free_end claims the slot directory is free space, so the
+    // relocated tuple would run into it.
+    sp.set_free_end(PAGE_SIZE as u16);
+    let before = sp.buf[sp.slot_offset(1)..].to_vec();
+    assert!(!sp.update(slot, &[3; 32]));
+    assert_eq!(&sp.buf[sp.slot_offset(1)..], &before[..]);
+    assert_eq!(sp.read(slot), Some(&[1; 8][..]));
+}
+
+#[test]
+fn free_end_guard_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let slot = sp.insert(b"abc").unwrap();
+    sp.set_free_end(PAGE_SIZE as u16);
+    assert_eq!(sp.insert(b"def"), Err(SlotError::FreeEndMismatch));
+    assert!(!sp.update(slot, b"a longer tuple"));
+    assert_eq!(sp.read(slot), Some(&b"abc"[..]));
+}
+
+#[test]
+fn retained_delete_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let kept = sp.insert(b"kept").unwrap();
+    let slot = sp.insert(b"retained tuple").unwrap();
+    let free_before = sp.largest_contiguous_free();
+
+    assert!(sp.delete_retained(slot, 5));
+    assert_eq!(sp.read(slot), None);
+    assert_eq!(sp.iter().count(), 1);
+    assert!(!sp.delete(slot));
+    // Compaction keeps retained bytes around.
+    sp.compact();
+    assert!(sp.check_invariants().is_ok());
+    assert!(sp.undelete(slot));
+    assert_eq!(sp.read(slot), Some(&b"retained tuple"[..]));
+    assert!(!sp.undelete(slot));
+
+    assert!(sp.delete_retained(slot, 7));
+    assert_eq!(sp.gc(7), 0); // not yet past the horizon
+    assert_eq!(sp.gc(8), 1);
+    assert_eq!(sp.read(slot), None);
+    assert!(!sp.undelete(slot));
+    assert_eq!(sp.read(kept), Some(&b"kept"[..]));
+    assert_eq!(
+        sp.largest_contiguous_free(),
+        free_before + b"retained tuple".len()
+    );
+
+    // A corrupt entry pointing past the usable page is not read as a stamp.
+    assert!(sp.delete_retained(kept, 9));
+    let (_, len) = sp.read_slot(kept.0);
+    sp.write_slot(kept.0, USABLE_PAGE_SIZE as u16 - 4, len);
+    assert_eq!(sp.retained_stamp(kept.0), None);
+    assert!(!sp.undelete(kept));
+}
+
+#[test]
+fn slot_entry_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let first = sp.insert(b"first").unwrap();
+    let second = sp.insert(b"second tuple").unwrap();
+    assert_eq!(sp.slot_entry(first), Some((HEADER_SIZE as u16, 5)));
+    assert_eq!(sp.slot_entry(second), Some((HEADER_SIZE as u16 + 5, 12)));
+    assert_eq!(sp.slot_entry(SlotId(2)), None);
+
+    sp.delete(first);
+    assert_eq!(
+        sp.slot_entry(first),
+        Some((HEADER_SIZE as u16, INVALID_SLOT))
+    );
+}
+
+#[test]
+fn read_bounds_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let good = sp.insert(b"in bounds").unwrap();
+    let bad = sp.insert_checked(b"soon out of bounds").unwrap();
+
+    // Point the entry at the slot directory.
+    let directory = sp.slot_offset(1) as u16;
+    sp.write_slot(bad.0, directory, 8);
+    assert_eq!(sp.read(bad), None);
+    assert_eq!(sp.read_checked(bad), Err(SlotError::OutOfBounds));
+    assert!(sp.read_mut(bad).is_none());
+    // And into the header.
+    sp.write_slot(bad.0, 2, 4);
+    assert_eq!(sp.read(bad), None);
+    assert_eq!(sp.iter().count(), 1);
+    assert_eq!(sp.read(good), Some(&b"in bounds"[..]));
+}
+
+#[test]
+fn swap_slots_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let a = sp.insert(b"alpha").unwrap();
+    let b = sp.insert(b"beta").unwrap();
+    let c = sp.insert(b"gamma").unwrap();
+
+    assert!(sp.swap_slots(a, c));
+    let order: Vec<&[u8]> = sp.iter().map(|(_, tuple)| tuple).collect();
+    assert_eq!(order, [&b"gamma"[..], b"beta", b"alpha"]);
+    assert!(sp.check_invariants().is_ok());
+
+    sp.delete(b);
+    assert!(!sp.swap_slots(a, b));
+    assert!(!sp.swap_slots(a, SlotId(3)));
+    assert_eq!(sp.read(a), Some(&b"gamma"[..]));
+}
+
+#[test]
+fn free_space_bucket_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let mut last = sp.free_space_bucket();
+    assert_eq!(last, 254);
+    let mut slots = Vec::new();
+    while let Ok(slot) = sp.insert(&[7u8; 300]) {
+        slots.push(slot);
+        let bucket = sp.free_space_bucket();
+        assert!(bucket < last);
+        assert_eq!(
+            bucket,
+            SlottedPage::bucket_for(sp.largest_contiguous_free())
+        );
+        last = bucket;
+    }
+
+    for slot in slots.iter().step_by(2) {
+        sp.delete(*slot);
+    }
+    sp.compact();
+    assert!(sp.free_space_bucket() > last);
+    assert_eq!(
+        sp.free_space_bucket(),
+        SlottedPage::bucket_for(sp.largest_contiguous_free())
+    );
+}
+
+#[test]
+fn compact_secure_test() {
+    let secret = b"SECRET-PATTERN-42";
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let keep = sp.insert(b"public").unwrap();
+    let gone = sp.insert(secret).unwrap();
+    sp.delete(gone);
+
+    sp.compact_secure();
+    assert_eq!(sp.read(keep), Some(&b"public"[..]));
+    assert!(sp.check_invariants().is_ok());
+    assert!(!buf.windows(secret.len()).any(|w| w == secret));
+}
+
+#[test]
+fn dead_space_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let a = sp.insert(&[1; 100]).unwrap();
+    let b = sp.insert(&[2; 40]).unwrap();
+    let c = sp.insert(&[3; 60]).unwrap();
+    let d = sp.insert(&[4; 30]).unwrap();
+    assert_eq!(sp.dead_space(), 0);
+
+    sp.delete(b);
+    sp.delete(d);
+    assert!(sp.update(a, &[5; 75]));
+    assert!(sp.delete_retained(c, 1));
+    assert_eq!(sp.dead_space(), 40 + 30 + 25 + 60);
+
+    let before = sp.largest_contiguous_free();
+    sp.compact();
+    assert_eq!(sp.largest_contiguous_free() - before, 40 + 30 + 25 + 60);
+    assert_eq!(sp.dead_space(), 0);
+}
+
+#[test]
+fn sync_directory_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let first = sp.insert(b"first").unwrap();
+    let second = sp.insert(b"second").unwrap();
+    sp.delete(second);
+    assert!(!sp.sync_directory());
+
+    // Crash in the middle of an insert: the header is bumped, the entry is not.
+    let free_start = sp.free_start();
+    sp.set_free_start(free_start + 10);
+    sp.set_num_slots(3);
+    sp.set_free_end(sp.free_end() - SLOT_ENTRY_SIZE as u16);
+    assert!(sp.check_invariants().is_err());
+
+    assert!(sp.sync_directory());
+    assert_eq!(sp.slot_count(), 2);
+    assert!(sp.check_invariants().is_ok());
+    assert_eq!(sp.read(first), Some(&b"first"[..]));
+    assert!(!sp.sync_directory());
+    let third = sp.insert(b"third").unwrap();
+    assert_eq!(third, second);
+    assert_eq!(sp.read(third), Some(&b"third"[..]));
+}
+
+#[test]
+fn reuse_deleted_slot_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    for tuple in [&b"zero"[..], b"one", b"two"] {
+        sp.insert(tuple).unwrap();
+    }
+    assert!(sp.delete(SlotId(1)));
+    let free_end = sp.free_end();
+
+    assert_eq!(sp.insert(b"one again").unwrap(), SlotId(1));
+    assert_eq!(sp.slot_count(), 3);
+    assert_eq!(sp.free_end(), free_end);
+    assert_eq!(sp.read(SlotId(1)), Some(&b"one again"[..]));
+    assert!(sp.update(SlotId(1), b"1"));
+    let live: Vec<_> = sp.iter().map(|(slot, _)| slot).collect();
+    assert_eq!(live, [SlotId(0), SlotId(1), SlotId(2)]);
+
+    // No tombstone left, so the directory grows again.
+    assert_eq!(sp.insert(b"three").unwrap(), SlotId(3));
+    assert!(sp.check_invariants().is_ok());
+}
+
+#[test]
+fn insert_at_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let first = sp.insert(b"first").unwrap();
+    let second = sp.insert(&[2; 1000]).unwrap();
+    sp.insert(&[3; 2500]).unwrap();
+    assert!(!sp.insert_at(second, b"live slots are refused"));
+
+    sp.delete(second);
+    sp.delete(first);
+    // Only fits once the deleted bytes are compacted away.
+    assert!(sp.insert_at(second, &[4; 1200]));
+    assert_eq!(sp.read(second), Some(&[4; 1200][..]));
+    assert_eq!(sp.read(first), None);
+    assert_eq!(sp.slot_count(), 3);
+    assert!(sp.check_invariants().is_ok());
+    assert!(!sp.insert_at(first, &[5; 1000]));
+    assert!(!sp.insert_at(SlotId(9), b"no such slot"));
+}
+
+#[test]
+fn truncate_trailing_tombstones_test() {
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut buf);
+    let slots: Vec<_> = (0..5u8).map(|i| sp.insert(&[i; 10]).unwrap()).collect();
+    sp.delete(slots[1]);
+    sp.delete(slots[3]);
+    sp.delete(slots[4]);
+
+    assert_eq!(sp.truncate_trailing_tombstones(), 2);
+    assert_eq!(sp.slot_count(), 3);
+    assert_eq!(
+        sp.free_end() as usize,
+        USABLE_PAGE_SIZE - 3 * SLOT_ENTRY_SIZE
+    );
+    assert!(sp.check_invariants().is_ok());
+    assert_eq!(sp.read(slots[2]), Some(&[2; 10][..]));
+    assert_eq!(sp.read(slots[1]), None);
+    assert_eq!(sp.truncate_trailing_tombstones(), 0);
+    assert_eq!(sp.insert(b"next").unwrap(), slots[1]);
+}
+
+#[test]
+fn normalize_test() {
+    let mut a_buf = [0u8; PAGE_SIZE];
+    let mut a = SlottedPage::init(&mut a_buf);
+    a.insert(b"one").unwrap();
+    a.insert(b"two").unwrap();
+    a.insert(b"three").unwrap();
+
+    // Same tuples, built through deletes, updates and a compaction.
+    let mut b_buf = [0xAAu8; PAGE_SIZE];
+    let mut b = SlottedPage::init(&mut b_buf);
+    let junk = b.insert(b"junk").unwrap();
+    let one = b.insert(b"uno").unwrap();
+    b.insert(b"two").unwrap();
+    b.insert(b"3").unwrap();
+    b.delete(junk);
+    assert!(b.update(one, b"one"));
+    b.compact();
+    assert!(b.update(SlotId(3), b"three"));
+
+    a.normalize();
+    b.normalize();
+    let tuples: Vec<_> = b.iter().collect();
+    assert_eq!(tuples[0], (SlotId(0), &b"one"[..]));
+    assert!(b.check_invariants().is_ok());
+    assert_eq!(a_buf[..USABLE_PAGE_SIZE], b_buf[..USABLE_PAGE_SIZE]);
+    // The trailer, e.g. the page LSN, is kept.
+    assert!(b_buf[USABLE_PAGE_SIZE..].iter().all(|&byte| byte == 0xAA));
+}
@@ -0,0 +1,288 @@
+use crate::buffer_manager::BufferPoolManager;
+use crate::disk_manager::{OwnerTag, Page, PAGE_SIZE};
+use crate::heap_file::PageId;
+use crate::slotted_page::SlottedPage;
+use std::ops::Range;
+
+// Bytes in front of every record holding the length of its key.
+const KEY_LEN_SIZE: usize = 2;
+
+// A heap whose pages partition the key space: each page holds the keys from
+// its own min key up to the next page's. A sparse in-memory index of the
+// per-page min keys routes inserts and lets range scans skip pages outside
+// the range. A full page is split in two by key. Splits move records, so
+// records are addressed by key rather than by TupleId. Keys may repeat, and a
+// run of equal keys may be split across pages; the index remembers where.
+// Records are [key_len u16][key][data]. Keys compare bytewise.
+pub struct SortedHeapFile {
+    buffer_pool_manager: BufferPoolManager,
+    index: Vec<IndexEntry>, // Sorted by min key
+    owner: OwnerTag,
+}
+
+struct IndexEntry {
+    min: Vec<u8>,
+    page_id: PageId,
+    spills: bool, // The page's largest key is also the next page's min key
+}
+
+impl SortedHeapFile {
+    pub fn new(buffer_pool_manager: BufferPoolManager) -> Self {
+        let owner = {
+            let mut dm = buffer_pool_manager.disk_manager.lock().unwrap();
+            dm.register_owner()
+        };
+        Self {
+            buffer_pool_manager,
+            index: Vec::new(),
+            owner,
+        }
+    }
+
+    // Insert a record into the page whose key range contains `key`, splitting
+    // that page if it is full. Returns false if the record could not be stored.
+    pub fn insert_sorted(&mut self, key: &[u8], data: &[u8]) -> bool {
+        let Ok(key_len) = u16::try_from(key.len()) else {
+            return false;
+        };
+        let mut record = Vec::with_capacity(KEY_LEN_SIZE + key.len() + data.len());
+        record.extend_from_slice(&key_len.to_le_bytes());
+        record.extend_from_slice(key);
+        record.extend_from_slice(data);
+
+        if self.index.is_empty() {
+            let Some(page_id) = page_image(&[record]).and_then(|image| self.new_page(image)) else {
+                return false;
+            };
+            self.index.push(IndexEntry {
+                min: key.to_vec(),
+                page_id,
+                spills: false,
+            });
+            return true;
+        }
+        let idx = self
+            .index
+            .partition_point(|entry| entry.min.as_slice() <= key)
+            .saturating_sub(1);
+        let page_id = self.index[idx].page_id;
+        let inserted = self
+            .with_page(page_id, true, |page| {
+                SlottedPage::from_buffer_unchecked(page)
+                    .insert(&record)
+                    .is_ok()
+            })
+            .unwrap_or(false);
+        let inserted = inserted || self.split_insert(idx, record);
+        if inserted && key < self.index[idx].min.as_slice() {
+            self.index[idx].min = key.to_vec();
+        }
+        inserted
+    }
+
+    // All records with lo <= key <= hi as (key, data), in key order. Only the
+    // pages whose key range overlaps [lo, hi] are read.
+    pub fn range_scan(&self, lo: &[u8], hi: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut out = Vec::new();
+        for idx in self.overlapping_pages(lo, hi) {
+            let records = self
+                .with_page(self.index[idx].page_id, false, page_records)
+                .unwrap_or_default();
+            out.extend(
+                records
+                    .iter()
+                    .map(|record| split_record(record))
+                    .filter(|(key, _)| lo <= *key && *key <= hi)
+                    .map(|(key, data)| (key.to_vec(), data.to_vec())),
+            );
+        }
+        out.sort();
+        out
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.index.len()
+    }
+
+    // Index positions of the pages whose key range overlaps [lo, hi].
+    fn overlapping_pages(&self, lo: &[u8], hi: &[u8]) -> Range<usize> {
+        let mut start = self
+            .index
+            .partition_point(|entry| entry.min.as_slice() <= lo)
+            .saturating_sub(1);
+        // A run of `lo` may start on earlier pages.
+        while start > 0 && self.index[start].min == lo && self.index[start - 1].spills {
+            start -= 1;
+        }
+        let end = self
+            .index
+            .partition_point(|entry| entry.min.as_slice() <= hi);
+        start..end.max(start)
+    }
+
+    // Split the full page at index position `idx` in two by key, with
+    // `record` added, and put the upper half on a new page.
+    fn split_insert(&mut self, idx: usize, record: Vec<u8>) -> bool {
+        let page_id = self.index[idx].page_id;
+        let Some(mut records) = self.with_page(page_id, false, page_records) else {
+            return false;
+        };
+        records.push(record);
+        records.sort_by(|a, b| split_record(a).0.cmp(split_record(b).0));
+        // Split by bytes so each half gets about half the page.
+        let total: usize = records.iter().map(|r| r.len()).sum();
+        let mut mid = 0;
+        let mut lower_bytes = 0;
+        while mid < records.len() - 1 && lower_bytes + records[mid].len() <= total / 2 {
+            lower_bytes += records[mid].len();
+            mid += 1;
+        }
+        let mid = mid.max(1);
+        let (lower, upper) = records.split_at(mid);
+        // Build both images first so a failed split leaves the page untouched.
+        let (Some(lower_image), Some(upper_image)) = (page_image(lower), page_image(upper)) else {
+            return false;
+        };
+        let Some(new_page_id) = self.new_page(upper_image) else {
+            return false;
+        };
+        self.with_page(page_id, true, |page| *page = lower_image);
+        let upper_min = split_record(&upper[0]).0.to_vec();
+        let spills = std::mem::replace(
+            &mut self.index[idx].spills,
+            split_record(&lower[lower.len() - 1]).0 == upper_min,
+        );
+        self.index.insert(
+            idx + 1,
+            IndexEntry {
+                min: upper_min,
+                page_id: new_page_id,
+                spills,
+            },
+        );
+        true
+    }
+
+    // Allocate a page and fill it with `image`.
+    fn new_page(&mut self, image: Page) -> Option<PageId> {
+        let page_id = {
+            let mut dm = self.buffer_pool_manager.disk_manager.lock().unwrap();
+            dm.allocate_page_for(self.owner).ok()?
+        };
+        self.with_page(page_id, true, |page| *page = image)?;
+        Some(page_id)
+    }
+
+    // Run `f` on a page in the buffer pool, marking it dirty if `dirty` is set.
+    fn with_page<R>(
+        &self,
+        page_id: PageId,
+        dirty: bool,
+        f: impl FnOnce(&mut Page) -> R,
+    ) -> Option<R> {
+        let frame = self.buffer_pool_manager.fetch_page(page_id)?;
+        let result = {
+            let mut frame_lock = frame.lock().unwrap();
+            frame_lock.is_dirty |= dirty;
+            f(&mut frame_lock.data)
+        };
+        let _ = self.buffer_pool_manager.unpin_page(page_id, dirty);
+        Some(result)
+    }
+}
+
+fn page_records(page: &mut Page) -> Vec<Vec<u8>> {
+    let sp = SlottedPage::from_buffer_unchecked(page);
+    sp.iter().map(|(_, record)| record.to_vec()).collect()
+}
+
+// A fresh slotted page holding `records`, or None if they do not fit.
+fn page_image(records: &[Vec<u8>]) -> Option<Page> {
+    let mut image: Page = [0; PAGE_SIZE];
+    let mut sp = SlottedPage::init(&mut image);
+    for record in records {
+        sp.insert(record).ok()?;
+    }
+    Some(image)
+}
+
+fn split_record(record: &[u8]) -> (&[u8], &[u8]) {
+    let key_len = u16::from_le_bytes(record[..KEY_LEN_SIZE].try_into().unwrap()) as usize;
+    record[KEY_LEN_SIZE..].split_at(key_len)
+}
+
+#[test]
+fn sorted_range_scan_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("sorted_heap");
+    let dm = DiskManager::new(&path).unwrap();
+    let bpm = BufferPoolManager::new(4, dm);
+    let mut heap = SortedHeapFile::new(bpm.clone());
+
+    // Insert 0..600 in a scrambled order (37 is coprime with 600).
+    for i in 0..600u32 {
+        let key = (i * 37 % 600).to_be_bytes();
+        assert!(heap.insert_sorted(&key, &[key[3]; 60]));
+    }
+    assert!(heap.page_count() > 5);
+
+    // Only the pages actually holding keys in the range should be read.
+    let (lo, hi) = (200u32.to_be_bytes(), 260u32.to_be_bytes());
+    let expected_pages = heap
+        .index
+        .iter()
+        .filter(|entry| {
+            let records = heap.with_page(entry.page_id, false, page_records).unwrap();
+            records.iter().any(|record| {
+                let key = split_record(record).0;
+                lo.as_slice() <= key && key <= hi.as_slice()
+            })
+        })
+        .count();
+    assert!(expected_pages >= 2 && expected_pages < heap.page_count());
+    let before = bpm.stats();
+    let rows = heap.range_scan(&lo, &hi);
+    let after = bpm.stats();
+    let fetches = (after.hits + after.misses) - (before.hits + before.misses);
+    assert_eq!(fetches as usize, expected_pages);
+
+    let keys: Vec<u32> = rows
+        .iter()
+        .map(|(key, _)| u32::from_be_bytes(key[..].try_into().unwrap()))
+        .collect();
+    assert_eq!(keys, (200..=260).collect::<Vec<u32>>());
+    assert!(rows.iter().all(|(key, data)| data == &[key[3]; 60]));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn sorted_duplicate_keys_test() {
+    use crate::disk_manager::{temp_db_path, DiskManager};
+
+    let path = temp_db_path("sorted_duplicates");
+    let dm = DiskManager::new(&path).unwrap();
+    let mut heap = SortedHeapFile::new(BufferPoolManager::new(4, dm));
+    for i in 0..40u8 {
+        assert!(heap.insert_sorted(&[i], &[i; 60]));
+    }
+    // Enough copies of one key to fill several pages.
+    for _ in 0..3 * 60 {
+        assert!(heap.insert_sorted(&[20], &[0; 60]));
+    }
+    assert!(heap.page_count() >= 3);
+
+    assert_eq!(heap.range_scan(&[20], &[20]).len(), 1 + 3 * 60);
+    let keys: Vec<u8> = heap
+        .range_scan(&[19], &[21])
+        .iter()
+        .map(|(key, _)| key[0])
+        .collect();
+    let mut expected = vec![19];
+    expected.resize(1 + 1 + 3 * 60, 20);
+    expected.push(21);
+    assert_eq!(keys, expected);
+
+    let _ = std::fs::remove_file(path);
+}
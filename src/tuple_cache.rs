@@ -0,0 +1,80 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::heap_file::TupleId;
+
+// A small LRU cache of tuple bytes keyed by TupleId, bounded by entry count.
+// Every access gets a new tick; the entry with the oldest tick is evicted.
+pub struct TupleCache {
+    capacity: usize,
+    entries: HashMap<TupleId, (Vec<u8>, u64)>, // tid -> (bytes, last used tick)
+    by_tick: BTreeMap<u64, TupleId>,
+    tick: u64,
+}
+
+impl TupleCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            by_tick: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn get(&mut self, tid: TupleId) -> Option<&[u8]> {
+        self.tick += 1;
+        let (bytes, last_used) = self.entries.get_mut(&tid)?;
+        self.by_tick.remove(last_used);
+        *last_used = self.tick;
+        self.by_tick.insert(self.tick, tid);
+        Some(bytes)
+    }
+
+    pub fn insert(&mut self, tid: TupleId, bytes: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.invalidate(tid);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.by_tick.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.entries.insert(tid, (bytes, self.tick));
+        self.by_tick.insert(self.tick, tid);
+    }
+
+    pub fn invalidate(&mut self, tid: TupleId) {
+        if let Some((_, last_used)) = self.entries.remove(&tid) {
+            self.by_tick.remove(&last_used);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[test]
+fn tuple_cache_lru_test() {
+    use crate::slotted_page::SlotId;
+
+    let tid = |slot| TupleId {
+        page_id: 1,
+        slot_id: SlotId(slot),
+    };
+    let mut cache = TupleCache::new(2);
+    cache.insert(tid(0), b"a".to_vec());
+    cache.insert(tid(1), b"b".to_vec());
+    // Touch 0 so 1 becomes the least recently used.
+    assert_eq!(cache.get(tid(0)), Some(&b"a"[..]));
+    cache.insert(tid(2), b"c".to_vec());
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.get(tid(1)), None);
+    assert_eq!(cache.get(tid(2)), Some(&b"c"[..]));
+
+    cache.invalidate(tid(0));
+    assert_eq!(cache.get(tid(0)), None);
+    assert_eq!(cache.len(), 1);
+}